@@ -1,46 +1,60 @@
 use anyhow::Result;
-use common::{
-    ManageRequest, ManageResponse, app_dir, load_state, recv_json, save_state, send_json,
-};
-use std::process::Command;
+use common::{Device, ManageRequest, ManageResponse, recv_json, send_json};
 use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
 use tracing::info;
 
-pub async fn start(app: &str) -> Result<()> {
-    manage(app, "start").await
+use super::devices::Target;
+
+pub async fn start(target: Target, app: &str) -> Result<()> {
+    manage(target, app, "start").await
+}
+
+pub async fn stop(target: Target, app: &str) -> Result<()> {
+    manage(target, app, "stop").await
 }
 
-pub async fn stop(app: &str) -> Result<()> {
-    manage(app, "stop").await
+pub async fn restart(target: Target, app: &str) -> Result<()> {
+    manage(target, app, "restart").await
 }
 
-pub async fn restart(app: &str) -> Result<()> {
-    manage(app, "restart").await
+async fn manage(target: Target, app: &str, action: &str) -> Result<()> {
+    let (mut socket, token) = target.connect().await?;
+    let resp = exchange(&mut socket, token, app, action).await?;
+
+    if resp.success {
+        info!("SUCCESS: {}", resp.message);
+    } else {
+        tracing::error!("ERROR: {}", resp.message);
+    }
+
+    Ok(())
 }
 
-async fn manage(app: &str, action: &str) -> Result<()> {
-    // TODO: In this moment it's have only on localhost.
-    let tcp = TcpStream::connect("127.0.0.1:7530").await?;
-    let mut socket = crate::tls::connect(tcp, "127.0.0.1").await?;
+// rollouts already hold the saved device and its token
+pub async fn request(device: &Device, app: &str, action: &str) -> Result<ManageResponse> {
+    let tcp = TcpStream::connect(format!("{}:{}", device.host, device.port)).await?;
+    let mut socket = crate::tls::connect(tcp, &device.host).await?;
+    exchange(&mut socket, device.token.clone(), app, action).await
+}
 
+async fn exchange(
+    socket: &mut TlsStream<TcpStream>,
+    token: Option<String>,
+    app: &str,
+    action: &str,
+) -> Result<ManageResponse> {
     let app_normalize = app.replace("/", "_");
 
     let req = ManageRequest {
         msg_type: "manage".into(),
         app: app_normalize.to_string(),
         action: action.to_string(),
+        daemon_token: token,
     };
 
-    send_json(&mut socket, &req).await?;
-    let resp: ManageResponse = recv_json(&mut socket).await?;
-
-    if resp.success {
-        info!("SUCCESS: {}", resp.message);
-    } else {
-        tracing::error!("ERROR: {}", resp.message);
-    }
-
-    Ok(())
+    send_json(socket, &req).await?;
+    recv_json(socket).await
 }
 
 // pub fn rollback(app: &str) -> Result<()> {
@@ -71,6 +85,6 @@ async fn manage(app: &str, action: &str) -> Result<()> {
 //     Ok(())
 // }
 
-pub async fn rollback(app: &str) -> Result<()> {
-    manage(app, "rollback").await
+pub async fn rollback(target: Target, app: &str) -> Result<()> {
+    manage(target, app, "rollback").await
 }
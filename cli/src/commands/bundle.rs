@@ -0,0 +1,202 @@
+use anyhow::Result;
+use clap::Args;
+use common::bundle::{self, BundleManifest};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+#[derive(Args)]
+pub struct BundleArgs {
+    /// Forge repository (user/repo) or local directory
+    pub source: String,
+    /// Output file (default: <app>.flare)
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Prebuilt tarball or binary to ship instead of running [build]
+    #[arg(long)]
+    pub artifact: Option<PathBuf>,
+    /// Leave the source tree out (artifact-only bundle)
+    #[arg(long)]
+    pub no_source: bool,
+    #[arg(long)]
+    pub github: bool,
+    #[arg(long, default_value = "http://localhost:8080")]
+    pub forge: String,
+    #[arg(long = "ref")]
+    pub git_ref: Option<String>,
+    /// App directory inside a monorepo
+    #[arg(long)]
+    pub path: Option<String>,
+    /// [profile.<name>] overlay to apply on import
+    #[arg(long)]
+    pub profile: Option<String>,
+}
+
+pub async fn run(args: BundleArgs) -> Result<()> {
+    let fetched = if Path::new(&args.source).is_dir() {
+        None
+    } else {
+        Some(fetch(&args).await?)
+    };
+
+    let result = write_bundle(&args, fetched.as_ref().map(|(_, root)| root.as_path()));
+
+    if let Some((tmp, _)) = fetched {
+        let _ = std::fs::remove_dir_all(tmp);
+    }
+    result
+}
+
+fn write_bundle(args: &BundleArgs, fetched: Option<&Path>) -> Result<()> {
+    let dir = fetched.unwrap_or(Path::new(&args.source));
+
+    let app_root = dir.join(args.path.as_deref().unwrap_or(""));
+    let config_text = std::fs::read_to_string(app_root.join("flare.toml"))
+        .map_err(|e| anyhow::anyhow!("Can't read flare.toml in {:?}: {}", app_root, e))?;
+    let config: common::AppConfig = toml::from_str(&config_text)?;
+
+    let source = if args.no_source {
+        None
+    } else {
+        Some(common::manifest::scan(dir)?)
+    };
+
+    let artifact = match &args.artifact {
+        Some(path) => {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .ok_or_else(|| anyhow::anyhow!("Invalid artifact path {:?}", path))?;
+            Some(common::manifest::file_entry(path, name)?)
+        }
+        None => None,
+    };
+
+    if source.is_none() && artifact.is_none() {
+        anyhow::bail!("Nothing to bundle: pass --artifact or drop --no-source");
+    }
+
+    let key = bundle::signing_key()?;
+    let public_key = bundle::public_key_hex(&key);
+
+    // monorepo apps are named after the repository, like `flare deploy --path`
+    let app = match (&args.path, fetched) {
+        (None, _) => config.app.name.clone(),
+        (Some(_), Some(_)) => args.source.clone(),
+        (Some(_), None) => crate::commands::deploy::app_name(dir)?,
+    };
+
+    let manifest = BundleManifest {
+        app,
+        created: chrono::Utc::now().to_rfc3339(),
+        config_hash: common::sha256_hex(config_text.as_bytes()),
+        source,
+        artifact,
+        public_key: public_key.clone(),
+        path: args.path.clone(),
+        profile: args.profile.clone(),
+    };
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    let sig = bundle::sign(&key, &json);
+
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(format!("{}.{}", config.app.name, bundle::BUNDLE_EXT)));
+
+    let file = std::fs::File::create(&output)?;
+    let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    append_bytes(&mut tar, "bundle.json", &json)?;
+    append_bytes(&mut tar, "bundle.sig", sig.as_bytes())?;
+    append_bytes(&mut tar, "flare.toml", config_text.as_bytes())?;
+
+    if let Some(source) = &manifest.source {
+        for f in &source.files {
+            tar.append_path_with_name(dir.join(&f.path), Path::new("source").join(&f.path))?;
+        }
+    }
+
+    if let (Some(a), Some(path)) = (&manifest.artifact, &args.artifact) {
+        tar.append_path_with_name(path, Path::new("artifact").join(&a.path))?;
+    }
+
+    tar.into_inner()?.finish()?;
+
+    let files = manifest.source.as_ref().map_or(0, |s| s.files.len());
+    println!("Bundle: {:?} ({} source files)", output, files);
+    println!("Signed with key {}", public_key);
+    println!("Trust it on a device with: flared trust {}", public_key);
+
+    Ok(())
+}
+
+fn append_bytes<W: std::io::Write>(
+    tar: &mut tar::Builder<W>,
+    name: &str,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+// downloads the repo like the daemon would; returns (temp dir, source root)
+async fn fetch(args: &BundleArgs) -> Result<(PathBuf, PathBuf)> {
+    let auth = crate::commands::auth::load().unwrap_or_default();
+    let git_ref = args.git_ref.as_deref().unwrap_or("main");
+
+    let url = if args.github {
+        format!(
+            "https://api.github.com/repos/{}/tarball/{}",
+            args.source, git_ref
+        )
+    } else {
+        let forge = if args.forge != "http://localhost:8080" {
+            args.forge.clone()
+        } else {
+            auth.forge.clone().unwrap_or(args.forge.clone())
+        };
+        format!("{}/git/{}/archive?ref={}", forge, args.source, git_ref)
+    };
+
+    info!("Downloading {}", url);
+
+    let mut r = reqwest::Client::new()
+        .get(&url)
+        .header("User-Agent", "Flare");
+    if let Some(pass) = &auth.password {
+        if args.github {
+            r = r.bearer_auth(pass);
+        } else if let Some(user) = &auth.user {
+            r = r.basic_auth(user, Some(pass));
+        }
+    }
+
+    let resp = r.send().await?;
+    if !resp.status().is_success() {
+        anyhow::bail!("HTTP {}", resp.status());
+    }
+    let data = resp.bytes().await?;
+
+    let tmp = std::env::temp_dir().join(format!("flare-bundle-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&tmp);
+    std::fs::create_dir_all(&tmp)?;
+    // same checks as the daemon, the tarball is no more trusted here
+    let limits = common::extract::Limits::from_env()?;
+    common::extract::unpack(flate2::read::GzDecoder::new(&data[..]), &tmp, &limits)?;
+
+    // forge tarballs wrap everything in a single top-level directory
+    let entries: Vec<_> = std::fs::read_dir(&tmp)?.filter_map(|e| e.ok()).collect();
+    let root = match entries.as_slice() {
+        [only] if only.path().is_dir() => only.path(),
+        _ => tmp.clone(),
+    };
+
+    Ok((tmp, root))
+}
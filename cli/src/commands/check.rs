@@ -0,0 +1,56 @@
+use anyhow::Result;
+use common::RepoConfig;
+use std::path::{Path, PathBuf};
+
+pub fn run(dir: &Path) -> Result<()> {
+    let mut errors = 0;
+    let mut warnings = 0;
+
+    for file in targets(dir)? {
+        let problems = common::check::check_file(&file)?;
+        if problems.is_empty() {
+            println!("{}: ok", file.display());
+        }
+        for p in &problems {
+            if p.is_error() {
+                errors += 1;
+            } else {
+                warnings += 1;
+            }
+            println!("{}:{}", file.display(), p);
+        }
+    }
+
+    if errors > 0 {
+        anyhow::bail!("{} error(s), {} warning(s)", errors, warnings);
+    }
+    if warnings > 0 {
+        println!("{} warning(s)", warnings);
+    }
+    Ok(())
+}
+
+// a monorepo root is checked through the apps it lists
+fn targets(dir: &Path) -> Result<Vec<PathBuf>> {
+    let root = dir.join("flare.toml");
+    let text = std::fs::read_to_string(&root)
+        .map_err(|e| anyhow::anyhow!("Can't read {:?}: {}", root, e))?;
+
+    // unparseable files go straight to check_file for a located error
+    let value: toml::Value = match toml::from_str(&text) {
+        Ok(v) => v,
+        Err(_) => return Ok(vec![root]),
+    };
+    let repo: RepoConfig = value.clone().try_into().unwrap_or_default();
+
+    let mut files = Vec::new();
+    if repo.apps.is_empty() || value.get("app").is_some() {
+        files.push(root);
+    }
+    files.extend(
+        repo.apps
+            .iter()
+            .map(|a| dir.join(&a.path).join("flare.toml")),
+    );
+    Ok(files)
+}
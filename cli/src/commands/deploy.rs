@@ -1,34 +1,66 @@
 use anyhow::Result;
-use common::{DeployRequest, DeployResponse, recv_json, send_json};
+use clap::Args;
+use common::{
+    DeployProgress, DeployRequest, DeployResponse, Device, SyncNeeded, recv_json, send_json,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tracing::{error, info};
 
-pub async fn run(
-    host: String,
-    port: u16,
-    repo: String,
-    github: bool,
-    forge: String,
-    token: Option<String>,
-    user: Option<String>,
-) -> Result<()> {
+#[derive(Args, Clone)]
+pub struct DeployArgs {
+    /// Forge repository (user/repo) or a local directory to upload
+    pub repo: String,
+    #[arg(long)]
+    pub github: bool,
+    #[arg(long, default_value = "http://localhost:8080")]
+    pub forge: String,
+    #[arg(long)]
+    pub token: Option<String>,
+    #[arg(long)]
+    pub user: Option<String>,
+    /// Branch, tag or commit to deploy (default: main)
+    #[arg(long = "ref")]
+    pub git_ref: Option<String>,
+    /// Download speed limit on the device, e.g. 512K (bytes per second)
+    #[arg(long)]
+    pub max_bandwidth: Option<String>,
+    /// Continue the last failed build from the failing step
+    #[arg(long)]
+    pub resume: bool,
+    /// Prebuilt tarball or binary to upload instead of running [build]
+    #[arg(long)]
+    pub artifact: Option<PathBuf>,
+    /// App directory inside a monorepo (default: every [[apps]] entry)
+    #[arg(long)]
+    pub path: Option<String>,
+    /// Merge [profile.<name>] from flare.toml over the base config
+    #[arg(long)]
+    pub profile: Option<String>,
+}
+
+pub async fn run(host: String, port: u16, token: Option<String>, args: DeployArgs) -> Result<()> {
     // load saved auth if not provided
     let auth = crate::commands::auth::load()?;
 
-    let final_user = user
+    let final_user = args
+        .user
         .or(auth.user)
         .or_else(|| std::env::var("FLARE_USER").ok());
 
-    let final_token = token
+    let final_token = args
+        .token
         .or(auth.password)
         .or_else(|| std::env::var("FLARE_PASS").ok());
 
-    let final_forge = if github {
+    let final_forge = if args.github {
         "github".into()
-    } else if forge != "http://localhost:8080" {
-        forge
+    } else if args.forge != "http://localhost:8080" {
+        args.forge
     } else {
-        auth.forge.unwrap_or(forge)
+        auth.forge.unwrap_or(args.forge)
     };
 
     let tcp = TcpStream::connect(format!("{}:{}", host, port)).await?;
@@ -36,39 +68,38 @@ pub async fn run(
 
     info!("Connected to {}:{}", host, port);
 
-    tracing::info!("Sending auth_user: {:?}", final_user);
-    tracing::info!("Sending auth_password: {:?}", final_token);
-
     let req = DeployRequest {
         msg_type: "deploy".into(),
-        repo,
+        repo: args.repo,
         forge: final_forge,
         auth_user: final_user,
         auth_password: final_token,
-        daemon_token: None,
+        daemon_token: token,
+        git_ref: args.git_ref,
+        max_bandwidth: args.max_bandwidth,
+        manifest: None,
+        resume: args.resume,
+        artifact: None,
+        path: args.path,
+        profile: args.profile,
+        tags: Vec::new(),
+        progress: true,
     };
 
-    send_json(&mut socket, &req).await?;
-    let resp: DeployResponse = recv_json(&mut socket).await?;
-
-    if resp.success {
-        info!("SUCCESS: {}", resp.message);
-    } else {
-        error!("ERROR: {}", resp.message);
-    }
-
+    let resp = exchange(&mut socket, req, args.artifact).await?;
+    report(&resp);
     Ok(())
 }
 
-pub async fn run_to_device(
-    device_id: &str,
-    repo: String,
-    github: bool,
-    forge: String,
-    token: Option<String>,
-    user: Option<String>,
-) -> Result<()> {
+pub async fn run_to_device(device_id: &str, args: DeployArgs) -> Result<()> {
     let device = common::get_device(device_id)?;
+    let resp = to_device(&device, args).await?;
+    report(&resp);
+    Ok(())
+}
+
+// one saved device; the caller reports the outcome
+pub async fn to_device(device: &Device, args: DeployArgs) -> Result<DeployResponse> {
     let auth = crate::commands::auth::load().unwrap_or_default();
 
     let tcp = TcpStream::connect(format!("{}:{}", device.host, device.port)).await?;
@@ -76,21 +107,166 @@ pub async fn run_to_device(
 
     let req = DeployRequest {
         msg_type: "deploy".into(),
-        repo,
-        forge: if github { "github".into() } else { forge },
-        auth_user: user.or(auth.user),
-        auth_password: token.or(auth.password),
+        repo: args.repo,
+        forge: if args.github {
+            "github".into()
+        } else {
+            args.forge
+        },
+        auth_user: args.user.or(auth.user),
+        auth_password: args.token.or(auth.password),
         daemon_token: device.token.clone(),
+        git_ref: args.git_ref,
+        // per-deploy flag wins over the device default
+        max_bandwidth: args.max_bandwidth.or(device.max_bandwidth.clone()),
+        manifest: None,
+        resume: args.resume,
+        artifact: None,
+        path: args.path,
+        profile: args.profile,
+        // `arch` and `arch=arm64` for arch = "arm64", like --tag selects devices
+        tags: device
+            .tags
+            .iter()
+            .flat_map(|(k, v)| [k.clone(), format!("{}={}", k, v)])
+            .collect(),
+        progress: true,
     };
 
-    send_json(&mut socket, &req).await?;
-    let resp: DeployResponse = recv_json(&mut socket).await?;
+    exchange(&mut socket, req, args.artifact).await
+}
+
+async fn exchange<S>(
+    socket: &mut S,
+    mut req: DeployRequest,
+    artifact: Option<PathBuf>,
+) -> Result<DeployResponse>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    // a local directory is uploaded instead of fetched from a forge
+    let local = Path::new(&req.repo)
+        .is_dir()
+        .then(|| PathBuf::from(&req.repo));
+
+    if let Some(dir) = &local {
+        req.repo = app_name(dir)?;
+    }
+
+    // a resumed build gets the fixed sources too, over its half-built tree
+    if let Some(dir) = &local {
+        let manifest = common::manifest::scan(dir)?;
+        info!(
+            "Local release: {} files, {} bytes",
+            manifest.files.len(),
+            manifest.total_size()
+        );
+        req.manifest = Some(manifest);
+    }
+
+    // blob key -> local file, for whatever the daemon asks us to upload
+    let mut files: HashMap<String, (PathBuf, u64)> = HashMap::new();
+
+    if let (Some(dir), Some(manifest)) = (&local, &req.manifest) {
+        for f in &manifest.files {
+            files.insert(f.blob_key(), (dir.join(&f.path), f.size));
+        }
+    }
+
+    if let Some(path) = &artifact {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow::anyhow!("Invalid artifact path {:?}", path))?;
+        let entry = common::manifest::file_entry(path, name)?;
+        info!("Artifact: {} ({} bytes)", entry.path, entry.size);
+        files.insert(entry.blob_key(), (path.clone(), entry.size));
+        req.artifact = Some(entry);
+    }
+
+    send_json(socket, &req).await?;
+    let mut reply: serde_json::Value = recv_json(socket).await?;
+
+    if reply.get("missing").is_some() {
+        let need: SyncNeeded = serde_json::from_value(reply)?;
+        upload(socket, &files, &need.missing).await?;
+        reply = recv_json(socket).await?;
+    }
+
+    // canary and blue-green deploys report while they run
+    while reply.get("progress").is_some() {
+        let p: DeployProgress = serde_json::from_value(reply)?;
+        info!("{}", p.progress);
+        reply = recv_json(socket).await?;
+    }
+
+    Ok(serde_json::from_value(reply)?)
+}
+
+pub fn report(resp: &DeployResponse) {
+    print_steps(&resp.steps);
 
     if resp.success {
         info!("SUCCESS: {}", resp.message);
     } else {
         error!("ERROR: {}", resp.message);
     }
+}
+
+pub fn app_name(dir: &Path) -> Result<String> {
+    if let Ok(config) = common::load_app_config(dir) {
+        return Ok(config.app.name);
+    }
+
+    let abs = std::fs::canonicalize(dir)?;
+    abs.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow::anyhow!("Can't name app from {:?}", dir))
+}
+
+async fn upload<S>(
+    socket: &mut S,
+    files: &HashMap<String, (PathBuf, u64)>,
+    missing: &[String],
+) -> Result<()>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let bytes: u64 = missing
+        .iter()
+        .filter_map(|k| files.get(k))
+        .map(|(_, size)| size)
+        .sum();
+    info!(
+        "Uploading {} of {} files ({} bytes)",
+        missing.len(),
+        files.len(),
+        bytes
+    );
+
+    for key in missing {
+        let (path, _) = files
+            .get(key)
+            .ok_or_else(|| anyhow::anyhow!("Daemon asked for unknown blob {}", key))?;
+        let data = std::fs::read(path)?;
+        common::send_msg(socket, &data).await?;
+    }
 
     Ok(())
 }
+
+fn print_steps(steps: &[common::StepReport]) {
+    for step in steps {
+        println!(
+            "  {:8} {:24} {:>8.1}s",
+            step.status,
+            step.name,
+            step.duration_ms as f64 / 1000.0
+        );
+        if matches!(step.status.as_str(), "failed" | "timeout" | "detected") {
+            for line in step.log.lines() {
+                println!("    | {}", line);
+            }
+        }
+    }
+}
@@ -1,5 +1,31 @@
 use anyhow::Result;
-use common::load_config;
+use common::{DeviceInfo, InfoRequest, load_config, recv_json, send_json};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+// a saved device (--device) or --host/--port without a token
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub device: Option<String>,
+    /// Sent with --host, a saved device uses its own
+    pub token: Option<String>,
+}
+
+impl Target {
+    pub async fn connect(self) -> Result<(TlsStream<TcpStream>, Option<String>)> {
+        let (host, port, token) = match &self.device {
+            Some(id) => {
+                let device = common::get_device(id)?;
+                (device.host, device.port, device.token)
+            }
+            None => (self.host, self.port, self.token),
+        };
+
+        let tcp = TcpStream::connect(format!("{}:{}", host, port)).await?;
+        Ok((crate::tls::connect(tcp, &host).await?, token))
+    }
+}
 
 pub fn list() -> Result<()> {
     let config = load_config()?;
@@ -12,7 +38,16 @@ pub fn list() -> Result<()> {
 
     for d in &config.devices {
         let name = d.name.as_deref().unwrap_or("unnamed");
-        println!("[{}] {:16} {}:{}", d.id, name, d.host, d.port);
+        let mut labels: Vec<String> = d.groups.iter().map(|g| format!("@{}", g)).collect();
+        labels.extend(d.tags.iter().map(|(k, v)| format!("{}={}", k, v)));
+        let line = format!(
+            "[{}] {:16} {:22} {}",
+            d.id,
+            name,
+            format!("{}:{}", d.host, d.port),
+            labels.join(" ")
+        );
+        println!("{}", line.trim_end());
     }
 
     Ok(())
@@ -43,3 +78,26 @@ pub fn remove(id: &str) -> Result<()> {
 
     Ok(())
 }
+
+pub async fn info(id: &str) -> Result<()> {
+    let device = common::get_device(id)?;
+
+    let tcp = TcpStream::connect(format!("{}:{}", device.host, device.port)).await?;
+    let mut socket = crate::tls::connect(tcp, &device.host).await?;
+
+    let req = InfoRequest {
+        msg_type: "info".into(),
+    };
+    send_json(&mut socket, &req).await?;
+    let info: DeviceInfo = recv_json(&mut socket).await?;
+
+    println!(
+        "Device:  {}",
+        device.name.as_deref().unwrap_or(&device.host)
+    );
+    println!("Arch:    {}", info.arch);
+    println!("OS:      {}", info.os);
+    println!("Version: {}", info.version);
+
+    Ok(())
+}
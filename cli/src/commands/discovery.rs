@@ -148,6 +148,9 @@ pub async fn sync(range: &str) -> Result<()> {
             host: device.host.clone(),
             port: device.port,
             token: Some(token), // plain token
+            max_bandwidth: None,
+            groups: Vec::new(),
+            tags: Default::default(),
         };
 
         config.devices.push(new_device);
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use common::{EnvRequest, EnvResponse, recv_json, send_json};
+use std::collections::HashMap;
+
+use super::devices::Target;
+
+// Device-side variables for an app. They live next to the app on the
+// device, survive deploys and win over [env] and env_file.
+
+pub async fn set(target: Target, app: &str, pairs: &[String]) -> Result<()> {
+    let mut vars = HashMap::new();
+    for pair in pairs {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected KEY=value, got {:?}", pair))?;
+        vars.insert(key.to_string(), value.to_string());
+    }
+    let resp = send(target, app, "set", vars, Vec::new()).await?;
+    println!("✓ {}", resp.message);
+    Ok(())
+}
+
+pub async fn unset(target: Target, app: &str, keys: &[String]) -> Result<()> {
+    let resp = send(target, app, "unset", HashMap::new(), keys.to_vec()).await?;
+    println!("✓ {}", resp.message);
+    Ok(())
+}
+
+pub async fn list(target: Target, app: &str) -> Result<()> {
+    let resp = send(target, app, "list", HashMap::new(), Vec::new()).await?;
+    if resp.vars.is_empty() {
+        println!("No variables set for {}", app);
+        return Ok(());
+    }
+
+    let mut vars: Vec<_> = resp.vars.into_iter().collect();
+    vars.sort();
+    for (key, value) in vars {
+        println!("{}={}", key, value);
+    }
+    Ok(())
+}
+
+async fn send(
+    target: Target,
+    app: &str,
+    action: &str,
+    vars: HashMap<String, String>,
+    keys: Vec<String>,
+) -> Result<EnvResponse> {
+    let (mut socket, token) = target.connect().await?;
+
+    let req = EnvRequest {
+        msg_type: "env".into(),
+        app: app.replace('/', "_"),
+        action: action.into(),
+        daemon_token: token,
+        vars,
+        keys,
+    };
+    send_json(&mut socket, &req).await?;
+
+    let resp: EnvResponse = recv_json(&mut socket).await?;
+    if !resp.success {
+        anyhow::bail!("{}", resp.message);
+    }
+    Ok(resp)
+}
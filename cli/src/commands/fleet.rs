@@ -0,0 +1,185 @@
+use anyhow::Result;
+use common::Device;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{Instrument, error, info};
+
+use super::deploy::DeployArgs;
+use super::rolling::{Rollout, RolloutArgs};
+
+// Deploys to several saved devices at once: `--device all`, a list
+// (`--device pi1,pi2`), and/or the devices in any --group that carry every
+// --tag (`key` or `key=value`). At most --parallel deploys run at a time,
+// their output is prefixed with the device, and a table of outcomes ends it.
+// With a rolling strategy the devices go in batches instead, see rolling.rs.
+
+const DEFAULT_PARALLEL: usize = 4;
+
+pub struct Selector {
+    pub device: Option<String>,
+    pub groups: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+pub struct Outcome {
+    pub device: Device,
+    pub status: &'static str, // "ok", "failed"; rollouts add more
+    pub message: String,
+    pub duration: Duration,
+    pub apps: Vec<String>, // deployed on the device
+}
+
+impl Outcome {
+    pub fn ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+impl Selector {
+    // anything but a single device by id or name
+    pub fn is_fleet(&self) -> bool {
+        !self.groups.is_empty()
+            || !self.tags.is_empty()
+            || self
+                .device
+                .as_deref()
+                .is_some_and(|d| d == "all" || d.contains(','))
+    }
+
+    pub fn select(&self) -> Result<Vec<Device>> {
+        let devices = match self.device.as_deref() {
+            None | Some("all") => common::load_config()?.devices,
+            Some(list) => list
+                .split(',')
+                .map(|d| common::get_device(d.trim()))
+                .collect::<Result<_>>()?,
+        };
+
+        Ok(devices
+            .into_iter()
+            .filter(|d| self.groups.is_empty() || self.groups.iter().any(|g| d.groups.contains(g)))
+            .filter(|d| self.tags.iter().all(|t| has_tag(d, t)))
+            .collect())
+    }
+}
+
+fn has_tag(device: &Device, tag: &str) -> bool {
+    match tag.split_once('=') {
+        Some((key, value)) => device.tags.get(key).is_some_and(|v| v == value),
+        None => device.tags.contains_key(tag),
+    }
+}
+
+pub fn label(device: &Device) -> String {
+    device.name.clone().unwrap_or_else(|| device.host.clone())
+}
+
+pub async fn run(
+    selector: Selector,
+    args: DeployArgs,
+    parallel: Option<usize>,
+    rollout: RolloutArgs,
+) -> Result<()> {
+    let devices = selector.select()?;
+    if devices.is_empty() {
+        anyhow::bail!("No saved device matches");
+    }
+    if let Some(rollout) = Rollout::resolve(&rollout, &args)? {
+        return super::rolling::run(devices, args, rollout).await;
+    }
+
+    // a rolling [strategy] in the repo would go unnoticed, so don't guess
+    if parallel.is_none() && !super::rolling::is_local(&args) {
+        anyhow::bail!(
+            "{} is a forge repo, its [strategy] isn't read before deploying: \
+             pass --batch <n|%> for a rolling deploy or --parallel <n> to deploy without batches",
+            args.repo
+        );
+    }
+    let parallel = parallel.unwrap_or(DEFAULT_PARALLEL).max(1);
+    info!(
+        "Deploying to {} devices, {} at a time",
+        devices.len(),
+        parallel
+    );
+
+    let outcomes = deploy(devices, &args, parallel).await;
+    print_summary(&outcomes);
+
+    let failed = outcomes.iter().filter(|o| !o.ok()).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} devices failed", failed, outcomes.len());
+    }
+    Ok(())
+}
+
+// outcomes in the order of `devices`
+pub async fn deploy(devices: Vec<Device>, args: &DeployArgs, parallel: usize) -> Vec<Outcome> {
+    let slots = Arc::new(Semaphore::new(parallel));
+    let mut tasks = JoinSet::new();
+
+    for (i, device) in devices.into_iter().enumerate() {
+        let slots = slots.clone();
+        let args = args.clone();
+        let span = tracing::info_span!("deploy", device = %label(&device));
+
+        tasks.spawn(
+            async move {
+                let _slot = slots.acquire_owned().await;
+                (i, one(device, args).await)
+            }
+            .instrument(span),
+        );
+    }
+
+    let mut outcomes: Vec<(usize, Outcome)> = tasks.join_all().await;
+    outcomes.sort_by_key(|(i, _)| *i);
+    outcomes.into_iter().map(|(_, o)| o).collect()
+}
+
+async fn one(device: Device, args: DeployArgs) -> Outcome {
+    let started = Instant::now();
+    let (success, message, apps) = match super::deploy::to_device(&device, args).await {
+        Ok(resp) => {
+            for step in resp.steps.iter().filter(|s| s.status == "failed") {
+                let why = step.log.lines().next().unwrap_or_default();
+                error!("{} failed: {}", step.name, why);
+            }
+            (resp.success, resp.message, resp.apps)
+        }
+        Err(e) => (false, e.to_string(), Vec::new()),
+    };
+
+    if success {
+        info!("SUCCESS: {}", message);
+    } else {
+        error!("ERROR: {}", message);
+    }
+    Outcome {
+        device,
+        status: if success { "ok" } else { "failed" },
+        message,
+        duration: started.elapsed(),
+        apps,
+    }
+}
+
+pub fn print_summary(outcomes: &[Outcome]) {
+    println!();
+    println!(
+        "{:16} {:22} {:11} {:>8}  MESSAGE",
+        "DEVICE", "HOST", "RESULT", "TIME"
+    );
+    for o in outcomes {
+        println!(
+            "{:16} {:22} {:11} {:>7.1}s  {}",
+            label(&o.device),
+            format!("{}:{}", o.device.host, o.device.port),
+            o.status,
+            o.duration.as_secs_f64(),
+            o.message
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::Result;
+use common::detect::{self, Detection};
+use common::*;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub fn run(dir: &Path, interactive: bool, force: bool) -> Result<()> {
+    let path = dir.join("flare.toml");
+    if path.exists() && !force {
+        anyhow::bail!(
+            "{} already exists (use --force to overwrite)",
+            path.display()
+        );
+    }
+
+    let (stack, mut config) = match detect::detect(dir) {
+        Some(Detection { stack, config }) => {
+            println!("Detected {}", stack);
+            (Some(stack), config)
+        }
+        None => {
+            println!("Couldn't detect the project type, writing a minimal flare.toml");
+            let name = std::fs::canonicalize(dir)?
+                .file_name()
+                .map(|n| n.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "app".into());
+            (None, detect::empty_config(&name, "0.1.0"))
+        }
+    };
+
+    if interactive {
+        confirm(&mut config)?;
+    }
+
+    let mut text = match stack {
+        Some(stack) => format!("# Generated by flare init ({})\n\n", stack),
+        None => "# Generated by flare init, see CONFIG.md for [build] and [run]\n\n".into(),
+    };
+    text.push_str(&toml::to_string_pretty(&config)?);
+    std::fs::write(&path, text)?;
+
+    println!("✓ Wrote {}", path.display());
+    println!("  Check it with: flare check {}", dir.display());
+    Ok(())
+}
+
+// walks through the detected values; enter keeps one, "-" drops it
+fn confirm(config: &mut AppConfig) -> Result<()> {
+    println!("Press enter to keep a value, \"-\" to leave it out");
+    println!("---");
+
+    config.app.name = ask("App name", Some(&config.app.name))?.unwrap_or_default();
+    if config.app.name.is_empty() {
+        anyhow::bail!("App name is required");
+    }
+    config.app.version = ask("Version", Some(&config.app.version))?.unwrap_or_default();
+
+    let build = config.build.as_ref().and_then(|b| b.command.clone());
+    match ask("Build command", build.as_deref())? {
+        Some(command) => {
+            config
+                .build
+                .get_or_insert_with(BuildSection::default)
+                .command = Some(command);
+        }
+        None => config.build = None,
+    }
+
+    let run = config.run.as_ref().map(|r| r.command.clone());
+    match ask("Run command", run.as_deref())? {
+        Some(command) => {
+            let port = config
+                .run
+                .as_ref()
+                .and_then(|r| r.port)
+                .map(|p| p.to_string());
+            let port = match ask("Port", port.as_deref())? {
+                Some(p) => Some(
+                    p.parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid port: {}", p))?,
+                ),
+                None => None,
+            };
+            config.run = Some(RunSection {
+                command,
+                port,
+                ..Default::default()
+            });
+        }
+        None => config.run = None,
+    }
+
+    if config.run.is_some() {
+        // no URL keeps the detected probe, which only checks the port
+        let url = config.health.as_ref().and_then(|h| h.url.clone());
+        if let Some(url) = ask("Health check URL", url.as_deref())? {
+            config.health = Some(HealthSection {
+                url: Some(url),
+                ..Default::default()
+            });
+        }
+    } else {
+        config.health = None;
+    }
+
+    if config.run.is_none() || config.web.is_some() {
+        let domain = config.web.as_ref().map(|w| w.domain.clone());
+        match ask("Domain for the static site", domain.as_deref())? {
+            Some(domain) => {
+                let root = config.web.as_ref().and_then(|w| w.root.clone());
+                let root = ask("Site root", root.as_deref().or(Some(".")))?;
+                config.web = Some(WebSection { domain, root });
+            }
+            None => config.web = None,
+        }
+    }
+
+    println!("---");
+    Ok(())
+}
+
+fn ask(label: &str, default: Option<&str>) -> Result<Option<String>> {
+    match default {
+        Some(d) => print!("{} [{}]: ", label, d),
+        None => print!("{}: ", label),
+    }
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(match line.trim() {
+        "" => default.map(String::from),
+        "-" => None,
+        s => Some(s.to_string()),
+    })
+}
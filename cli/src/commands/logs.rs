@@ -0,0 +1,41 @@
+use anyhow::Result;
+use common::{LogsChunk, LogsRequest, recv_json, send_json};
+
+use super::devices::Target;
+
+pub struct LogsArgs {
+    pub follow: bool,
+    pub since: Option<String>,
+    pub release: Option<String>,
+    pub grep: Option<String>,
+    pub lines: Option<usize>,
+}
+
+pub async fn run(target: Target, app: &str, args: LogsArgs) -> Result<()> {
+    let (mut socket, token) = target.connect().await?;
+
+    let req = LogsRequest {
+        msg_type: "logs".into(),
+        app: app.replace('/', "_"),
+        daemon_token: token,
+        follow: args.follow,
+        since: args.since,
+        release: args.release,
+        grep: args.grep,
+        lines: args.lines,
+    };
+    send_json(&mut socket, &req).await?;
+
+    loop {
+        let chunk: LogsChunk = recv_json(&mut socket).await?;
+        if let Some(e) = chunk.error {
+            anyhow::bail!("{}", e);
+        }
+        for line in &chunk.lines {
+            println!("{}", line);
+        }
+        if chunk.done {
+            return Ok(());
+        }
+    }
+}
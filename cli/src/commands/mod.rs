@@ -1,5 +1,13 @@
 pub mod apps;
 pub mod auth;
+pub mod bundle;
+pub mod check;
 pub mod deploy;
 pub mod devices;
 pub mod discovery;
+pub mod env;
+pub mod fleet;
+pub mod init;
+pub mod logs;
+pub mod rolling;
+pub mod schema;
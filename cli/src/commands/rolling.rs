@@ -0,0 +1,270 @@
+use anyhow::Result;
+use clap::Args;
+use common::{AppState, Device, Share, StrategySection, StrategyType};
+use std::path::Path;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{Instrument, error, info};
+
+use super::deploy::DeployArgs;
+use super::fleet::{self, Outcome};
+
+// Fleet deploys with [strategy] type = "rolling" (read from the flare.toml
+// of a local directory, a forge repo needs --batch) or --batch: batch_size devices (default 25%) are
+// updated at once. Every app a device deployed then has to keep running
+// without failing a probe for wait_time (default 30s) before the next batch
+// starts. Once more than max_failures devices (default 0) failed, the rest
+// are skipped and, with rollback = true, the devices already updated go back
+// to their previous release.
+
+const DEFAULT_BATCH: Share = Share::Percent(25);
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+const POLL: Duration = Duration::from_secs(2);
+
+#[derive(Args, Clone)]
+pub struct RolloutArgs {
+    /// Rolling deploy: devices per batch, a number or a percentage like 25%.
+    /// Needed for a forge repo, whose [strategy] is only read on the device
+    #[arg(long)]
+    pub batch: Option<String>,
+    /// Rolling deploy: failed devices tolerated, a number or a percentage
+    #[arg(long)]
+    pub max_failures: Option<String>,
+    /// Rolling deploy: roll back updated devices when the rollout stops
+    #[arg(long)]
+    pub rollback: bool,
+}
+
+pub struct Rollout {
+    batch: Share,
+    max_failures: Share,
+    wait: Duration,
+    rollback: bool,
+}
+
+impl Rollout {
+    // flags win over flare.toml; None when neither asks for a rollout
+    pub fn resolve(flags: &RolloutArgs, args: &DeployArgs) -> Result<Option<Rollout>> {
+        let strategy = local_strategy(args).filter(|s| s.r#type == StrategyType::Rolling);
+        if strategy.is_none() && flags.batch.is_none() {
+            return Ok(None);
+        }
+        let strategy = strategy.as_ref();
+
+        let share = |flag: &Option<String>, config: Option<&String>| {
+            flag.as_ref()
+                .or(config)
+                .map(|s| Share::parse(s))
+                .transpose()
+        };
+        let batch = share(&flags.batch, strategy.and_then(|s| s.batch_size.as_ref()))?;
+        let max_failures = share(
+            &flags.max_failures,
+            strategy.and_then(|s| s.max_failures.as_ref()),
+        )?;
+        let wait = strategy
+            .and_then(|s| s.wait_time.as_deref())
+            .map(common::parse_duration)
+            .transpose()?;
+
+        Ok(Some(Rollout {
+            batch: batch.unwrap_or(DEFAULT_BATCH),
+            max_failures: max_failures.unwrap_or(Share::Count(0)),
+            wait: wait.unwrap_or(DEFAULT_WAIT),
+            rollback: flags.rollback || strategy.and_then(|s| s.rollback).unwrap_or(false),
+        }))
+    }
+}
+
+// a directory uploaded from here rather than a forge repo, which is only
+// read on the device
+pub fn is_local(args: &DeployArgs) -> bool {
+    Path::new(&args.repo).is_dir()
+}
+
+fn local_strategy(args: &DeployArgs) -> Option<StrategySection> {
+    if !is_local(args) {
+        return None;
+    }
+    let dir = Path::new(&args.repo);
+    // an app in a monorepo has its own flare.toml
+    let dir = match &args.path {
+        Some(p) => dir.join(p),
+        None => dir.to_path_buf(),
+    };
+    common::load_app_config(&dir).ok()?.strategy
+}
+
+pub async fn run(devices: Vec<Device>, args: DeployArgs, rollout: Rollout) -> Result<()> {
+    let total = devices.len();
+    let size = rollout.batch.of(total).max(1);
+    let allowed = rollout.max_failures.of(total);
+    let batches: Vec<Vec<Device>> = devices.chunks(size).map(|c| c.to_vec()).collect();
+    info!(
+        "Rolling deploy to {} devices: {} batches of up to {}, {} failures allowed",
+        total,
+        batches.len(),
+        size,
+        allowed
+    );
+
+    let mut outcomes: Vec<Outcome> = Vec::new();
+    let mut failed = 0;
+    let mut stopped = false;
+
+    for (i, batch) in batches.into_iter().enumerate() {
+        if stopped {
+            outcomes.extend(batch.into_iter().map(skipped));
+            continue;
+        }
+
+        let names: Vec<String> = batch.iter().map(fleet::label).collect();
+        info!("Batch {}: {}", i + 1, names.join(", "));
+        let mut done = fleet::deploy(batch, &args, size).await;
+        watch(&mut done, rollout.wait).await;
+
+        failed += done.iter().filter(|o| !o.ok()).count();
+        outcomes.extend(done);
+        if failed > allowed {
+            error!(
+                "{} devices failed, {} allowed: stopping the rollout",
+                failed, allowed
+            );
+            stopped = true;
+        }
+    }
+
+    if stopped && rollout.rollback {
+        roll_back(&mut outcomes).await;
+    }
+    fleet::print_summary(&outcomes);
+
+    if stopped {
+        anyhow::bail!(
+            "Rollout stopped after {} of {} devices failed",
+            failed,
+            total
+        );
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} devices failed", failed, total);
+    }
+    Ok(())
+}
+
+fn skipped(device: Device) -> Outcome {
+    Outcome {
+        device,
+        status: "skipped",
+        message: "Rollout stopped".into(),
+        duration: Duration::ZERO,
+        apps: Vec::new(),
+    }
+}
+
+// devices that deployed have to stay healthy for `wait`, all at the same time
+async fn watch(outcomes: &mut [Outcome], wait: Duration) {
+    if wait.is_zero() {
+        return;
+    }
+    let mut tasks = JoinSet::new();
+    for (i, o) in outcomes.iter().enumerate().filter(|(_, o)| o.ok()) {
+        let device = o.device.clone();
+        let apps = o.apps.clone();
+        let span = tracing::info_span!("deploy", device = %fleet::label(&device));
+        tasks.spawn(
+            async move {
+                info!("Watching for {:?}", wait);
+                let result = healthy(&device, &apps, wait).await;
+                match &result {
+                    Ok(()) => info!("Healthy for {:?}", wait),
+                    Err(why) => error!("UNHEALTHY: {}", why),
+                }
+                (i, result)
+            }
+            .instrument(span),
+        );
+    }
+
+    for (i, result) in tasks.join_all().await {
+        if let Err(why) = result {
+            let o = &mut outcomes[i];
+            o.status = "unhealthy";
+            o.message = why;
+        }
+    }
+}
+
+// the state of each app every POLL until `wait` is over
+async fn healthy(device: &Device, apps: &[String], wait: Duration) -> Result<(), String> {
+    let deadline = Instant::now() + wait;
+    loop {
+        for app in apps {
+            let resp = super::apps::request(device, app, "status")
+                .await
+                .map_err(|e| format!("{}: {}", app, e))?;
+            if !resp.success {
+                return Err(format!("{}: {}", app, resp.message));
+            }
+            if let Some(why) = resp.state.as_ref().and_then(unhealthy) {
+                return Err(format!("{} {}", app, why));
+            }
+        }
+        if Instant::now() >= deadline {
+            return Ok(());
+        }
+        tokio::time::sleep(POLL).await;
+    }
+}
+
+fn unhealthy(state: &AppState) -> Option<String> {
+    if state.status != "running" {
+        return Some(match state.exit_code {
+            Some(code) => format!("is {} (exit code {})", state.status, code),
+            None => format!("is {}", state.status),
+        });
+    }
+    let health = state.health.as_ref()?;
+    let why = health.message.as_deref().unwrap_or("failing");
+    if health.live == Some(false) {
+        return Some(format!("fails its liveness probe: {}", why));
+    }
+    if health.ready == Some(false) {
+        return Some(format!("fails its readiness probe: {}", why));
+    }
+    None
+}
+
+// back to the release before this deploy on every device that got it
+async fn roll_back(outcomes: &mut [Outcome]) {
+    for o in outcomes
+        .iter_mut()
+        .filter(|o| matches!(o.status, "ok" | "unhealthy"))
+    {
+        let span = tracing::info_span!("deploy", device = %fleet::label(&o.device));
+        let failures = roll_back_device(&o.device, &o.apps).instrument(span).await;
+
+        if failures.is_empty() {
+            o.status = "rolled back";
+        } else {
+            o.status = "failed";
+            o.message = format!("Rollback failed: {}", failures.join("; "));
+        }
+    }
+}
+
+async fn roll_back_device(device: &Device, apps: &[String]) -> Vec<String> {
+    let mut failures = Vec::new();
+    for app in apps {
+        match super::apps::request(device, app, "rollback").await {
+            Ok(resp) if resp.success => info!("{}: {}", app, resp.message),
+            Ok(resp) => failures.push(format!("{}: {}", app, resp.message)),
+            Err(e) => failures.push(format!("{}: {}", app, e)),
+        }
+    }
+    if !failures.is_empty() {
+        error!("Rollback failed: {}", failures.join("; "));
+    }
+    failures
+}
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use serde_json::{Value, json};
+use std::io::{ErrorKind, Write};
+
+// generated from common::AppConfig, so it can't drift from what the daemon
+// accepts; schemars misses serde aliases and monorepo roots, patched in below
+pub fn run() -> Result<()> {
+    let mut schema = serde_json::to_value(schemars::schema_for!(common::AppConfig))?;
+    patch(&mut schema);
+
+    // `flare schema | head` closes the pipe early
+    let mut out = std::io::stdout().lock();
+    let written = serde_json::to_writer_pretty(&mut out, &schema)
+        .map_err(std::io::Error::from)
+        .and_then(|_| writeln!(out));
+    match written {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        other => Ok(other?),
+    }
+}
+
+fn patch(schema: &mut Value) {
+    // type = "blue-green" is accepted as well
+    let variants = schema
+        .pointer_mut("/definitions/StrategyType/oneOf")
+        .and_then(|v| v.as_array_mut());
+    for variant in variants.into_iter().flatten() {
+        if let Some(values) = variant.get_mut("enum").and_then(|v| v.as_array_mut())
+            && values.contains(&json!("bluegreen"))
+        {
+            values.push(json!("blue-green"));
+        }
+    }
+
+    // a monorepo root may only list [[apps]]
+    if let Some(root) = schema.as_object_mut() {
+        root.remove("required");
+        root.insert(
+            "anyOf".into(),
+            json!([{ "required": ["app"] }, { "required": ["apps"] }]),
+        );
+    }
+}
@@ -16,6 +16,10 @@ struct Cli {
 
     #[arg(long, default_value_t = 7530, global = true)]
     port: u16,
+
+    /// Daemon token for --host (saved devices bring their own), or FLARE_DAEMON_TOKEN
+    #[arg(long, global = true)]
+    daemon_token: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -25,29 +29,91 @@ enum Cmd {
         action: AuthAction,
     },
     Deploy {
-        repo: String,
+        /// Saved device by id or name, a comma-separated list, or "all"
         #[arg(long)]
         device: Option<String>,
+        /// Saved devices in this group (repeatable)
+        #[arg(long = "group")]
+        groups: Vec<String>,
+        /// Saved devices with this tag, key or key=value (repeatable, all must match)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Devices deployed to at the same time (default 4). A forge repo's
+        /// [strategy] isn't read here, so its fleet deploys need --parallel or --batch
+        #[arg(long)]
+        parallel: Option<usize>,
+        #[command(flatten)]
+        rollout: commands::rolling::RolloutArgs,
+        #[command(flatten)]
+        args: commands::deploy::DeployArgs,
+    },
+    /// Write a flare.toml for the project in a directory
+    Init {
+        #[arg(default_value = ".")]
+        dir: std::path::PathBuf,
+        /// Confirm each detected value
+        #[arg(short, long)]
+        interactive: bool,
+        /// Overwrite an existing flare.toml
         #[arg(long)]
-        github: bool,
-        #[arg(long, default_value = "http://localhost:8080")]
-        forge: String,
+        force: bool,
+    },
+    /// Validate flare.toml (every [[apps]] entry in a monorepo)
+    Check {
+        #[arg(default_value = ".")]
+        dir: std::path::PathBuf,
+    },
+    /// Print the JSON Schema of flare.toml (for taplo / Even Better TOML)
+    Schema,
+    /// Pack an app into a signed bundle for offline devices
+    Bundle(commands::bundle::BundleArgs),
+    /// Variables kept on the device for an app, applied over [env]
+    Env {
+        #[arg(long, global = true)]
+        device: Option<String>,
+        #[command(subcommand)]
+        action: EnvAction,
+    },
+    /// Output of an app, its builds and hooks (active release by default)
+    Logs {
+        app: String,
+        #[arg(long)]
+        device: Option<String>,
+        /// Keep streaming new lines
+        #[arg(short, long)]
+        follow: bool,
+        /// Only lines newer than this, e.g. 10m, 2h or an RFC 3339 time
         #[arg(long)]
-        token: Option<String>,
+        since: Option<String>,
+        /// Release id (versions/<id>) instead of the active one
         #[arg(long)]
-        user: Option<String>,
+        release: Option<String>,
+        /// Only lines containing this text
+        #[arg(long)]
+        grep: Option<String>,
+        /// Only the last N lines
+        #[arg(short = 'n', long)]
+        lines: Option<usize>,
     },
     Start {
         app: String,
+        #[arg(long)]
+        device: Option<String>,
     },
     Stop {
         app: String,
+        #[arg(long)]
+        device: Option<String>,
     },
     Restart {
         app: String,
+        #[arg(long)]
+        device: Option<String>,
     },
     Rollback {
         app: String,
+        #[arg(long)]
+        device: Option<String>,
     },
     Discover,
     Sync {
@@ -61,7 +127,32 @@ enum Cmd {
 
 #[derive(Subcommand)]
 enum DeviceAction {
-    Rm { id: String },
+    Rm {
+        id: String,
+    },
+    /// Show architecture and version reported by the daemon
+    Info {
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum EnvAction {
+    /// Set KEY=value pairs and restart the app if it is running
+    Set {
+        app: String,
+        #[arg(required = true)]
+        vars: Vec<String>,
+    },
+    /// Remove variables and restart the app if it is running
+    Unset {
+        app: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    List {
+        app: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -88,6 +179,10 @@ async fn main() {
 async fn run(cli: Cli) -> Result<()> {
     use commands::*;
 
+    let daemon_token = cli
+        .daemon_token
+        .or_else(|| std::env::var("FLARE_DAEMON_TOKEN").ok());
+
     match cli.cmd {
         Cmd::Auth { action } => match action {
             AuthAction::Login => auth::login(),
@@ -95,30 +190,115 @@ async fn run(cli: Cli) -> Result<()> {
             AuthAction::Status => auth::status(),
         },
         Cmd::Deploy {
-            repo,
             device,
-            github,
-            forge,
-            token,
-            user,
+            groups,
+            tags,
+            parallel,
+            rollout,
+            args,
         } => {
-            if let Some(dev) = device {
+            let selector = fleet::Selector {
+                device,
+                groups,
+                tags,
+            };
+            if selector.is_fleet() {
+                fleet::run(selector, args, parallel, rollout).await
+            } else if let Some(dev) = selector.device {
                 // deploy to saved device
-                deploy::run_to_device(&dev, repo, github, forge, token, user).await
+                deploy::run_to_device(&dev, args).await
             } else {
                 // deploy to host from CLI args
-                deploy::run(cli.host, cli.port, repo, github, forge, token, user).await
+                deploy::run(cli.host, cli.port, daemon_token, args).await
+            }
+        }
+        Cmd::Init {
+            dir,
+            interactive,
+            force,
+        } => init::run(&dir, interactive, force),
+        Cmd::Check { dir } => check::run(&dir),
+        Cmd::Schema => schema::run(),
+        Cmd::Bundle(args) => bundle::run(args).await,
+        Cmd::Env { device, action } => {
+            let target = devices::Target {
+                host: cli.host,
+                port: cli.port,
+                device,
+                token: daemon_token,
+            };
+            match action {
+                EnvAction::Set { app, vars } => env::set(target, &app, &vars).await,
+                EnvAction::Unset { app, keys } => env::unset(target, &app, &keys).await,
+                EnvAction::List { app } => env::list(target, &app).await,
             }
         }
-        Cmd::Start { app } => apps::start(&app).await,
-        Cmd::Stop { app } => apps::stop(&app).await,
-        Cmd::Restart { app } => apps::restart(&app).await,
-        Cmd::Rollback { app } => apps::rollback(&app).await,
+        Cmd::Logs {
+            app,
+            device,
+            follow,
+            since,
+            release,
+            grep,
+            lines,
+        } => {
+            let target = devices::Target {
+                host: cli.host,
+                port: cli.port,
+                device,
+                token: daemon_token,
+            };
+            let args = logs::LogsArgs {
+                follow,
+                since,
+                release,
+                grep,
+                lines,
+            };
+            logs::run(target, &app, args).await
+        }
+        Cmd::Start { app, device } => {
+            let target = devices::Target {
+                host: cli.host,
+                port: cli.port,
+                device,
+                token: daemon_token,
+            };
+            apps::start(target, &app).await
+        }
+        Cmd::Stop { app, device } => {
+            let target = devices::Target {
+                host: cli.host,
+                port: cli.port,
+                device,
+                token: daemon_token,
+            };
+            apps::stop(target, &app).await
+        }
+        Cmd::Restart { app, device } => {
+            let target = devices::Target {
+                host: cli.host,
+                port: cli.port,
+                device,
+                token: daemon_token,
+            };
+            apps::restart(target, &app).await
+        }
+        Cmd::Rollback { app, device } => {
+            let target = devices::Target {
+                host: cli.host,
+                port: cli.port,
+                device,
+                token: daemon_token,
+            };
+            apps::rollback(target, &app).await
+        }
         Cmd::Discover => discovery::discover().await,
         Cmd::Sync { range } => discovery::sync(&range).await,
         Cmd::Devices { action } => match action {
             None => devices::list(),
             Some(DeviceAction::Rm { id }) => devices::remove(&id),
+            Some(DeviceAction::Info { id }) => devices::info(&id).await,
         },
     }
 }
@@ -0,0 +1,177 @@
+use crate::{FileEntry, Manifest};
+use anyhow::Result;
+pub use ed25519_dalek::SigningKey;
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+// Offline bundle layout (tar.gz):
+//   bundle.json   BundleManifest
+//   bundle.sig    hex ed25519 signature of bundle.json
+//   flare.toml    app config (from <path>/ for monorepo apps)
+//   source/...    release tree (optional)
+//   artifact/<n>  prebuilt artifact (optional)
+
+pub const BUNDLE_EXT: &str = "flare";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub app: String,
+    pub created: String,
+    pub config_hash: String,
+    pub source: Option<Manifest>,
+    pub artifact: Option<FileEntry>,
+    pub public_key: String,
+    #[serde(default)]
+    pub path: Option<String>, // app directory inside a monorepo
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+fn key_path() -> std::path::PathBuf {
+    crate::flare_dir().join("bundle.key")
+}
+
+pub fn trusted_keys_path() -> std::path::PathBuf {
+    crate::flare_dir().join("trusted_keys")
+}
+
+// created on first use, kept next to the other CLI credentials
+pub fn signing_key() -> Result<SigningKey> {
+    let path = key_path();
+    if path.exists() {
+        let bytes = hex::decode(std::fs::read_to_string(&path)?.trim())?;
+        let seed: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Corrupt key in {:?}", path))?;
+        return Ok(SigningKey::from_bytes(&seed));
+    }
+
+    let mut seed = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut seed);
+
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, hex::encode(seed))?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+pub fn public_key_hex(key: &SigningKey) -> String {
+    hex::encode(key.verifying_key().to_bytes())
+}
+
+pub fn sign(key: &SigningKey, data: &[u8]) -> String {
+    hex::encode(key.sign(data).to_bytes())
+}
+
+pub fn load_trusted_keys() -> Vec<String> {
+    read_keys(&trusted_keys_path())
+}
+
+fn read_keys(path: &std::path::Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect()
+}
+
+pub fn trust_key(key_hex: &str) -> Result<()> {
+    add_key(&trusted_keys_path(), key_hex)
+}
+
+fn add_key(path: &std::path::Path, key_hex: &str) -> Result<()> {
+    parse_public_key(key_hex)?;
+
+    let mut keys = read_keys(path);
+    if !keys.iter().any(|k| k == key_hex) {
+        keys.push(key_hex.to_string());
+    }
+
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(path, keys.join("\n") + "\n")?;
+    Ok(())
+}
+
+fn parse_public_key(key_hex: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(key_hex)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Public key must be 32 bytes"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+// returns the trusted key that produced the signature
+pub fn verify(data: &[u8], sig_hex: &str, trusted: &[String]) -> Result<String> {
+    let bytes: [u8; 64] = hex::decode(sig_hex.trim())?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Signature must be 64 bytes"))?;
+    let sig = Signature::from_bytes(&bytes);
+
+    for key_hex in trusted {
+        if let Ok(key) = parse_public_key(key_hex)
+            && key.verify(data, &sig).is_ok()
+        {
+            return Ok(key_hex.clone());
+        }
+    }
+
+    anyhow::bail!("Bundle signature is not from a trusted key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn verifies_a_signed_bundle() {
+        let signer = key(1);
+        let json = br#"{"app":"demo"}"#;
+        let sig = sign(&signer, json);
+        let trusted = vec![public_key_hex(&key(2)), public_key_hex(&signer)];
+        assert_eq!(
+            verify(json, &sig, &trusted).unwrap(),
+            public_key_hex(&signer)
+        );
+    }
+
+    #[test]
+    fn rejects_untrusted_keys_and_tampering() {
+        let signer = key(1);
+        let json = br#"{"app":"demo"}"#.to_vec();
+        let sig = sign(&signer, &json);
+
+        let others = vec![public_key_hex(&key(2))];
+        assert!(verify(&json, &sig, &others).is_err());
+        assert!(verify(&json, &sig, &[]).is_err());
+
+        let trusted = vec![public_key_hex(&signer)];
+        let mut flipped = json.clone();
+        flipped[3] ^= 1;
+        assert!(verify(&flipped, &sig, &trusted).is_err());
+        assert!(verify(&json, "not hex", &trusted).is_err());
+        assert!(verify(&json, &sig[..64], &trusted).is_err());
+    }
+
+    #[test]
+    fn trusts_each_key_once() {
+        let dir = std::env::temp_dir().join(format!("flare-keys-{}", std::process::id()));
+        let path = dir.join("trusted_keys");
+        let hex = public_key_hex(&key(1));
+
+        add_key(&path, &hex).unwrap();
+        add_key(&path, &hex).unwrap();
+        assert_eq!(read_keys(&path), vec![hex]);
+        assert!(add_key(&path, "abcd").is_err());
+        assert!(add_key(&path, "zz").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
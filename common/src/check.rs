@@ -0,0 +1,782 @@
+use crate::{AppConfig, DatabaseType, Probe, ProbeType, StrategyType, interpolate};
+use anyhow::Result;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+// Validates flare.toml beyond what serde catches: files it refers to, values
+// that only make sense together, overlays and `${...}` names. Shared by
+// `flare check` and the daemon, which refuses to deploy on errors.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Problem {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.line, self.column, level, self.message
+        )
+    }
+}
+
+pub fn check_file(path: &Path) -> Result<Vec<Problem>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Can't read {:?}: {}", path, e))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(check(&text, dir))
+}
+
+// errors fail, warnings are returned for the caller to log
+pub fn validate(dir: &Path) -> Result<Vec<Problem>> {
+    // a repo without flare.toml deploys with what was detected for it
+    let file = if dir.join("flare.toml").exists() {
+        "flare.toml"
+    } else {
+        crate::detect::DETECTED
+    };
+    let (errors, warnings): (Vec<_>, Vec<_>) = check_file(&dir.join(file))?
+        .into_iter()
+        .partition(|p| p.is_error());
+
+    if !errors.is_empty() {
+        let lines: Vec<String> = errors.iter().map(|p| format!("{}:{}", file, p)).collect();
+        anyhow::bail!("Invalid {}:\n  {}", file, lines.join("\n  "));
+    }
+    Ok(warnings)
+}
+
+pub fn check(text: &str, dir: &Path) -> Vec<Problem> {
+    let mut c = Checker {
+        text,
+        doc: ImDocument::parse(text).ok(),
+        problems: Vec::new(),
+    };
+
+    match toml::from_str::<AppConfig>(text) {
+        Ok(config) => {
+            c.semantic(&config, dir);
+            if let Ok(raw) = toml::from_str::<toml::Value>(text) {
+                c.overlays(&raw);
+                c.variables(&raw);
+            }
+        }
+        Err(e) => c.push_at(Severity::Error, e.span(), e.message().to_string()),
+    }
+
+    c.problems
+        .sort_by_key(|p| (p.line, p.column, p.severity != Severity::Error));
+    c.problems
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    doc: Option<ImDocument<&'a str>>,
+    problems: Vec<Problem>,
+}
+
+impl Checker<'_> {
+    fn error(&mut self, path: &[&str], message: impl Into<String>) {
+        let span = self.locate(path);
+        self.push_at(Severity::Error, span, message.into());
+    }
+
+    fn warning(&mut self, path: &[&str], message: impl Into<String>) {
+        let span = self.locate(path);
+        self.push_at(Severity::Warning, span, message.into());
+    }
+
+    fn push_at(&mut self, severity: Severity, span: Option<Range<usize>>, message: String) {
+        let (line, column) = span.map_or((1, 1), |s| position(self.text, s.start));
+        self.problems.push(Problem {
+            severity,
+            line,
+            column,
+            message,
+        });
+    }
+
+    // span of the deepest part of `path` present in the document
+    fn locate(&self, path: &[&str]) -> Option<Range<usize>> {
+        let doc = self.doc.as_ref()?;
+        let mut table: &dyn TableLike = doc.as_table();
+        let mut span = None;
+        let mut i = 0;
+
+        while i < path.len() {
+            let (key, item) = match table.get_key_value(path[i]) {
+                Some(kv) => kv,
+                None => break,
+            };
+            span = key.span().or(span);
+            let index = path.get(i + 1).and_then(|s| s.parse::<usize>().ok());
+
+            match item {
+                Item::Table(t) => table = t,
+                Item::Value(Value::InlineTable(t)) => table = t,
+                Item::ArrayOfTables(a) => match index.and_then(|n| a.get(n)) {
+                    Some(t) => {
+                        span = t.span().or(span);
+                        table = t;
+                        i += 1;
+                    }
+                    None => break,
+                },
+                Item::Value(Value::Array(a)) => {
+                    span = index.and_then(|n| a.get(n)).and_then(|v| v.span()).or(span);
+                    break;
+                }
+                Item::Value(v) => {
+                    span = v.span().or(span);
+                    break;
+                }
+                Item::None => break,
+            }
+            i += 1;
+        }
+        span
+    }
+
+    fn semantic(&mut self, config: &AppConfig, dir: &Path) {
+        if config.app.name.trim().is_empty() {
+            self.error(&["app", "name"], "app name is empty");
+        }
+
+        if let Some(build) = &config.build {
+            if build.command.is_some() && build.steps.is_some() {
+                self.error(&["build"], "[build] has both command and steps, pick one");
+            }
+
+            let mut names = Vec::new();
+            for (i, step) in build.steps.iter().flatten().enumerate() {
+                let idx = i.to_string();
+                if step.name.trim().is_empty() {
+                    self.error(&["build", "steps", &idx, "name"], "step name is empty");
+                } else if names.contains(&&step.name) {
+                    self.error(
+                        &["build", "steps", &idx, "name"],
+                        format!("duplicate step name '{}'", step.name),
+                    );
+                }
+                names.push(&step.name);
+
+                if let Some(w) = &step.workdir {
+                    if !crate::is_contained(Path::new(""), Path::new(w)) {
+                        self.error(
+                            &["build", "steps", &idx, "workdir"],
+                            format!("workdir {:?} escapes the release", w),
+                        );
+                    } else if !dir.join(w).is_dir() {
+                        self.warning(
+                            &["build", "steps", &idx, "workdir"],
+                            format!("workdir {:?} does not exist (yet)", w),
+                        );
+                    }
+                }
+            }
+
+            for (i, path) in build.cache.iter().flatten().enumerate() {
+                if path.is_empty() || !crate::is_contained(Path::new(""), Path::new(path)) {
+                    self.error(
+                        &["build", "cache", &i.to_string()],
+                        format!("cache path {:?} must be inside the release", path),
+                    );
+                }
+            }
+
+            if let Some(key) = &build.cache_key
+                && !dir.join(key).is_file()
+            {
+                self.warning(
+                    &["build", "cache_key"],
+                    format!("cache_key file {:?} not found", key),
+                );
+            }
+        }
+
+        if let Some(file) = &config.env_file {
+            if !crate::is_contained(Path::new(""), Path::new(file)) {
+                self.error(
+                    &["env_file"],
+                    format!("env_file {:?} must be inside the release", file),
+                );
+            } else if !dir.join(file).is_file() {
+                self.warning(&["env_file"], format!("env_file {:?} not found", file));
+            }
+        }
+
+        if let Some(web) = &config.web {
+            let root = web.root.as_deref().unwrap_or(".");
+            if !crate::is_contained(Path::new(""), Path::new(root)) {
+                self.error(
+                    &["web", "root"],
+                    format!("root {:?} escapes the release", root),
+                );
+            } else if !dir.join(root).is_dir() {
+                // [build] or an artifact may still produce it
+                self.warning(
+                    &["web", "root"],
+                    format!(
+                        "root {:?} does not exist (yet); [build] or an artifact must create it",
+                        root
+                    ),
+                );
+            }
+            if config.run.is_some() {
+                self.warning(&["run"], "[run] is ignored when [web] is set");
+            }
+        }
+
+        if let Some(run) = &config.run
+            && run.port == Some(0)
+        {
+            self.error(&["run", "port"], "port must be between 1 and 65535");
+        }
+
+        if let Some(run) = &config.run
+            && run.domain.is_some()
+            && run.port.is_none()
+        {
+            self.error(&["run", "domain"], "domain needs a port to route to");
+        }
+
+        if let Some(health) = &config.health {
+            if let Some(url) = &health.url {
+                self.http_url(&["health", "url"], url);
+                if health.readiness.is_some() {
+                    self.warning(
+                        &["health", "url"],
+                        "url is ignored when [health.readiness] is set",
+                    );
+                }
+            }
+            let port = config.run.as_ref().and_then(|r| r.port);
+            if let Some(probe) = &health.liveness {
+                self.probe("liveness", probe, port);
+            }
+            if let Some(probe) = &health.readiness {
+                self.probe("readiness", probe, port);
+            }
+            if config.run.is_none() {
+                self.warning(
+                    &["health"],
+                    "[health] needs [run] to have something to probe",
+                );
+            }
+        }
+
+        if let Some(db) = &config.database {
+            if let Some(preseed) = &db.preseed
+                && !dir.join(preseed).is_file()
+            {
+                self.warning(
+                    &["database", "preseed"],
+                    format!("preseed file {:?} not found (yet)", preseed),
+                );
+            }
+            if db.r#type == DatabaseType::Sqlite && db.port.is_some() {
+                self.warning(&["database", "port"], "port is ignored for sqlite");
+            }
+        }
+
+        if let Some(strategy) = &config.strategy {
+            if let Some(p) = strategy.percent
+                && !(1..=100).contains(&p)
+            {
+                self.error(
+                    &["strategy", "percent"],
+                    "percent must be between 1 and 100",
+                );
+            }
+            if let Some(r) = strategy.max_error_rate
+                && !(1..=100).contains(&r)
+            {
+                self.error(
+                    &["strategy", "max_error_rate"],
+                    "max_error_rate must be between 1 and 100",
+                );
+            }
+            if let Some(b) = &strategy.batch_size {
+                match crate::Share::parse(b) {
+                    Ok(crate::Share::Count(0) | crate::Share::Percent(0)) => self.error(
+                        &["strategy", "batch_size"],
+                        "batch_size must be at least one device",
+                    ),
+                    Ok(_) => {}
+                    Err(_) => self.error(
+                        &["strategy", "batch_size"],
+                        format!("invalid batch_size {:?}, expected e.g. 5 or 25%", b),
+                    ),
+                }
+            }
+            if let Some(m) = &strategy.max_failures
+                && crate::Share::parse(m).is_err()
+            {
+                self.error(
+                    &["strategy", "max_failures"],
+                    format!("invalid max_failures {:?}, expected e.g. 2 or 10%", m),
+                );
+            }
+            if let Some(w) = &strategy.wait_time
+                && crate::parse_duration(w).is_err()
+            {
+                self.error(
+                    &["strategy", "wait_time"],
+                    format!("invalid duration {:?}, expected e.g. 30s or 5m", w),
+                );
+            }
+            if strategy.r#type == StrategyType::Bluegreen
+                && config
+                    .run
+                    .as_ref()
+                    .is_none_or(|r| r.port.is_none() || r.domain.is_none())
+            {
+                self.error(
+                    &["strategy", "type"],
+                    "bluegreen needs [run] port and domain to switch the domain's traffic",
+                );
+            }
+            if strategy.r#type == StrategyType::Canary
+                && config
+                    .run
+                    .as_ref()
+                    .is_none_or(|r| r.port.is_none() || r.domain.is_none())
+            {
+                self.error(
+                    &["strategy", "type"],
+                    "canary needs [run] port and domain to split the domain's traffic",
+                );
+            }
+        }
+
+        if let Some(limits) = &config.resource_limits {
+            if let Some(m) = &limits.memory
+                && crate::parse_size(m).is_err()
+            {
+                self.error(
+                    &["resource_limits", "memory"],
+                    format!("invalid size {:?}, expected e.g. 512M", m),
+                );
+            }
+            if let Some(cpu) = &limits.cpu
+                && !cpu.parse::<f64>().is_ok_and(|c| c > 0.0)
+            {
+                self.error(
+                    &["resource_limits", "cpu"],
+                    format!("invalid cpu {:?}, expected e.g. 0.5", cpu),
+                );
+            }
+        }
+
+        if let Some(artifact) = &config.artifact {
+            for (target, location) in &artifact.targets {
+                let remote = location.starts_with("http://") || location.starts_with("https://");
+                if !remote && !crate::is_contained(Path::new(""), Path::new(location)) {
+                    self.error(
+                        &["artifact", target],
+                        format!("artifact path {:?} escapes the release", location),
+                    );
+                }
+            }
+        }
+    }
+
+    fn http_url(&mut self, path: &[&str], url: &str) {
+        let url = url.trim_start();
+        if !(url.starts_with("http://") || url.starts_with("https://") || url.starts_with("${")) {
+            self.error(path, "health url must start with http:// or https://");
+        }
+    }
+
+    // the fields a probe type needs are there, the others would be ignored
+    fn probe(&mut self, kind: &str, probe: &Probe, run_port: Option<u16>) {
+        let at = |key: &'static str| ["health", kind, key];
+        match probe.r#type {
+            ProbeType::Http => match &probe.url {
+                Some(url) => self.http_url(&at("url"), url),
+                None => self.error(&at("type"), "http probe needs url"),
+            },
+            ProbeType::Tcp => {
+                if probe.port.or(run_port).is_none() {
+                    self.error(&at("type"), "tcp probe needs port (or [run] port)");
+                }
+            }
+            ProbeType::Exec => {
+                if probe.command.as_deref().is_none_or(|c| c.trim().is_empty()) {
+                    self.error(&at("type"), "exec probe needs command");
+                }
+            }
+        }
+
+        if let Some(status) = probe.status
+            && !(100..=599).contains(&status)
+        {
+            self.error(&at("status"), "status must be between 100 and 599");
+        }
+        if probe.interval == Some(0) {
+            self.error(&at("interval"), "interval must be at least 1 second");
+        }
+        if probe.timeout == Some(0) {
+            self.error(&at("timeout"), "timeout must be at least 1 second");
+        }
+        if probe.failure_threshold == Some(0) {
+            self.error(
+                &at("failure_threshold"),
+                "failure_threshold must be at least 1",
+            );
+        }
+        if probe.success_threshold == Some(0) {
+            self.error(
+                &at("success_threshold"),
+                "success_threshold must be at least 1",
+            );
+        }
+
+        let ignored = match probe.r#type {
+            ProbeType::Http => [
+                ("port", probe.port.is_some()),
+                ("command", probe.command.is_some()),
+            ],
+            ProbeType::Tcp => [
+                ("url", probe.url.is_some()),
+                ("command", probe.command.is_some()),
+            ],
+            ProbeType::Exec => [("url", probe.url.is_some()), ("port", probe.port.is_some())],
+        };
+        for (key, set) in ignored {
+            if set {
+                self.warning(&at(key), format!("{} is ignored for this probe type", key));
+            }
+        }
+    }
+
+    // every overlay has to produce a valid config once merged
+    fn overlays(&mut self, raw: &toml::Value) {
+        let mut base = raw.clone();
+        if let Some(t) = base.as_table_mut() {
+            for s in ["profile", "tag", "device"] {
+                t.remove(s);
+            }
+        }
+
+        for section in ["profile", "tag", "device"] {
+            let entries = match raw.get(section).and_then(|v| v.as_table()) {
+                Some(t) => t,
+                None => continue,
+            };
+            for (name, overlay) in entries {
+                if !overlay.is_table() {
+                    self.error(
+                        &[section, name],
+                        format!("[{}.{}] must be a table", section, name),
+                    );
+                    continue;
+                }
+                let mut merged = base.clone();
+                crate::overlay::merge(&mut merged, overlay.clone());
+                if let Err(e) = merged.try_into::<AppConfig>() {
+                    self.error(
+                        &[section, name],
+                        format!("[{}.{}]: {}", section, name, e.message()),
+                    );
+                }
+            }
+        }
+    }
+
+    fn variables(&mut self, raw: &toml::Value) {
+        // overlays can add what a variable needs, so look at all of them
+        let mut sources = vec![raw];
+        for section in ["profile", "tag", "device"] {
+            if let Some(t) = raw.get(section).and_then(|v| v.as_table()) {
+                sources.extend(t.values());
+            }
+        }
+        let has = |path: &[&str]| {
+            sources
+                .iter()
+                .any(|s| path.iter().try_fold(*s, |v, k| v.get(k)).is_some())
+        };
+        let port = has(&["run", "port"]);
+        let db_types: Vec<&str> = sources
+            .iter()
+            .filter_map(|s| s.get("database")?.get("type")?.as_str())
+            .collect();
+        let secrets: Vec<String> = sources
+            .iter()
+            .filter_map(|s| s.get("secrets")?.as_table())
+            .flat_map(|t| t.keys().cloned())
+            .collect();
+
+        let mut strings = Vec::new();
+        collect_strings(raw, &mut Vec::new(), &mut strings);
+
+        for (path, s) in strings {
+            let path: Vec<&str> = path.iter().map(|p| p.as_str()).collect();
+            let names = match interpolate::names(&s) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.error(&path, e.to_string());
+                    continue;
+                }
+            };
+
+            for name in names {
+                let problem = match name.as_str() {
+                    "PORT" if !port => Some("${PORT} needs [run] port".to_string()),
+                    "DB_URL" | "DB_PORT" if db_types.is_empty() => {
+                        Some(format!("${{{}}} needs a [database] section", name))
+                    }
+                    "DB_PORT" if db_types.iter().all(|t| *t == "sqlite") => {
+                        Some("${DB_PORT} needs a postgres or mysql [database]".to_string())
+                    }
+                    n if interpolate::BUILTINS.contains(&n) => None,
+                    n if n.strip_prefix("env.").is_some_and(|v| !v.is_empty()) => None,
+                    n => match n.strip_prefix("secret.") {
+                        Some(key) if secrets.iter().any(|s| s == key) => None,
+                        Some(key) => Some(format!("${{{}}}: no {} in [secrets]", n, key)),
+                        None => Some(format!("unknown variable ${{{}}}", n)),
+                    },
+                };
+                if let Some(msg) = problem {
+                    self.error(&path, msg);
+                }
+            }
+        }
+    }
+}
+
+fn collect_strings(
+    value: &toml::Value,
+    path: &mut Vec<String>,
+    out: &mut Vec<(Vec<String>, String)>,
+) {
+    match value {
+        toml::Value::String(s) if s.contains('$') => out.push((path.clone(), s.clone())),
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                path.push(i.to_string());
+                collect_strings(item, path, out);
+                path.pop();
+            }
+        }
+        toml::Value::Table(t) => {
+            for (k, v) in t {
+                // [secrets] holds references, not templates
+                let overlay =
+                    path.len() == 2 && ["profile", "tag", "device"].contains(&path[0].as_str());
+                if k == "secrets" && (path.is_empty() || overlay) {
+                    continue;
+                }
+                path.push(k.clone());
+                collect_strings(v, path, out);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+// 1-based line and column of a byte offset
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an empty checkout: nothing [build] or an artifact makes exists yet
+    fn errors(text: &str) -> Vec<String> {
+        let dir = std::env::temp_dir().join(format!("flare-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        check(text, &dir)
+            .into_iter()
+            .filter(|p| p.is_error())
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn shipped_examples_pass() {
+        for text in [
+            include_str!("../../examples/full-example.toml"),
+            include_str!("../../examples/nodejs-api.toml"),
+            include_str!("../../examples/python-api.toml"),
+            include_str!("../../examples/website.toml"),
+        ] {
+            assert_eq!(errors(text), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn web_root_may_come_from_an_artifact() {
+        let text = r#"
+            [app]
+            name = "site"
+            version = "1"
+
+            [web]
+            domain = "site.local"
+            root = "dist"
+
+            [artifact]
+            x86_64 = "https://example.com/site.tar.gz"
+        "#;
+        assert_eq!(errors(text), Vec::<String>::new());
+    }
+    // each class of error points at the offending value or table name
+
+    #[test]
+    fn unknown_fields_are_located() {
+        let text = r#"
+[app]
+name = "api"
+version = "1"
+
+[helth]
+url = "http://localhost/"
+"#;
+        let errors = errors(text);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("6:2: error: unknown field `helth`"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn overlays_must_merge_into_a_valid_config() {
+        let text = r#"
+[app]
+name = "api"
+version = "1"
+
+[run]
+command = "./api"
+port = 8080
+
+[profile.staging]
+run = { port = "eighty" }
+
+[tag]
+gpu = 1
+"#;
+        let errors = errors(text);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(
+            errors[0].starts_with("10:10: error: [profile.staging]: invalid type"),
+            "{}",
+            errors[0]
+        );
+        assert_eq!(errors[1], "14:7: error: [tag.gpu] must be a table");
+    }
+
+    #[test]
+    fn unknown_variables_are_located() {
+        let text = r#"
+[app]
+name = "api"
+version = "1"
+
+[run]
+command = "./api --port ${PORT}"
+
+[env]
+URL = "${DB_URL}"
+KEY = "${secret.api_key}"
+HOME = "${NOPE}"
+"#;
+        assert_eq!(
+            errors(text),
+            [
+                "7:11: error: ${PORT} needs [run] port",
+                "10:7: error: ${DB_URL} needs a [database] section",
+                "11:7: error: ${secret.api_key}: no api_key in [secrets]",
+                "12:8: error: unknown variable ${NOPE}",
+            ]
+        );
+    }
+
+    #[test]
+    fn probes_need_their_fields() {
+        let text = r#"
+[app]
+name = "api"
+version = "1"
+
+[run]
+command = "./api"
+
+[health.liveness]
+type = "tcp"
+
+[health.readiness]
+type = "http"
+status = 700
+"#;
+        assert_eq!(
+            errors(text),
+            [
+                "10:8: error: tcp probe needs port (or [run] port)",
+                "13:8: error: http probe needs url",
+                "14:10: error: status must be between 100 and 599",
+            ]
+        );
+    }
+
+    #[test]
+    fn strategies_are_checked() {
+        let text = r#"
+[app]
+name = "api"
+version = "1"
+
+[run]
+command = "./api"
+port = 8080
+
+[strategy]
+type = "bluegreen"
+percent = 0
+batch_size = "a few"
+wait_time = "soon"
+"#;
+        assert_eq!(
+            errors(text),
+            [
+                "11:8: error: bluegreen needs [run] port and domain to switch the domain's traffic",
+                "12:11: error: percent must be between 1 and 100",
+                "13:14: error: invalid batch_size \"a few\", expected e.g. 5 or 25%",
+                "14:13: error: invalid duration \"soon\", expected e.g. 30s or 5m",
+            ]
+        );
+    }
+}
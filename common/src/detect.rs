@@ -0,0 +1,531 @@
+use crate::types::*;
+use std::path::Path;
+
+// Guesses [build]/[run]/[web] for common stacks from the files in a project.
+// `flare init` writes the result out; the daemon uses it for repos that come
+// without a flare.toml or leave [build]/[run] out.
+
+// what the daemon filled in, next to the release's own flare.toml
+pub const DETECTED: &str = "flare.detected.toml";
+
+pub struct Detection {
+    pub stack: &'static str,
+    pub config: AppConfig,
+}
+
+pub fn detect(dir: &Path) -> Option<Detection> {
+    let detectors: &[fn(&Path) -> Option<Detection>] = &[node, rust, python, go, static_site];
+    detectors.iter().find_map(|d| d(dir))
+}
+
+// flare.toml over what was detected for it, as the daemon deploys it
+pub fn load(dir: &Path) -> anyhow::Result<toml::Value> {
+    let path = dir.join("flare.toml");
+    let mut value = match std::fs::read_to_string(dir.join(DETECTED)) {
+        Ok(text) => toml::from_str(&text)?,
+        Err(_) => toml::Value::Table(Default::default()),
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(text) => crate::overlay::merge(&mut value, toml::from_str(&text)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && dir.join(DETECTED).exists() => {}
+        Err(e) => anyhow::bail!("Can't read {:?}: {}", path, e),
+    }
+    Ok(value)
+}
+
+// app section plus nothing else
+pub fn empty_config(name: &str, version: &str) -> AppConfig {
+    AppConfig {
+        app: AppSection {
+            name: name.into(),
+            version: version.into(),
+        },
+        ..Default::default()
+    }
+}
+
+fn service(
+    dir: &Path,
+    stack: &'static str,
+    name: Option<&str>,
+    version: Option<&str>,
+    build: Option<String>,
+    run: String,
+    port: u16,
+) -> Detection {
+    let mut config = empty_config(&app_name(dir, name), version.unwrap_or("0.1.0"));
+    config.build = build.map(|command| BuildSection {
+        command: Some(command),
+        ..Default::default()
+    });
+    config.run = Some(RunSection {
+        command: run,
+        port: Some(port),
+        ..Default::default()
+    });
+    // listening is all we know to expect, `/` may well be a 404
+    config.health = Some(HealthSection {
+        readiness: Some(Probe::of(ProbeType::Tcp)),
+        ..Default::default()
+    });
+    Detection { stack, config }
+}
+
+// keeps the build cache between releases when there is a lockfile to key it
+fn cache(config: &mut AppConfig, dir: &Path, dirs: &[&str], lockfile: &str) {
+    if let Some(build) = &mut config.build
+        && dir.join(lockfile).is_file()
+    {
+        build.cache = Some(dirs.iter().map(|d| d.to_string()).collect());
+        build.cache_key = Some(lockfile.into());
+    }
+}
+
+fn node(dir: &Path) -> Option<Detection> {
+    let text = std::fs::read_to_string(dir.join("package.json")).ok()?;
+    let pkg: serde_json::Value = serde_json::from_str(&text).ok()?;
+    let script = |s: &str| pkg.get("scripts").and_then(|v| v.get(s)).is_some();
+    let dep = |d: &str| {
+        ["dependencies", "devDependencies"]
+            .iter()
+            .any(|k| pkg.get(k).and_then(|v| v.get(d)).is_some())
+    };
+
+    let (pm, install, lockfile) = if dir.join("pnpm-lock.yaml").exists() {
+        ("pnpm", "pnpm install --frozen-lockfile", "pnpm-lock.yaml")
+    } else if dir.join("yarn.lock").exists() {
+        ("yarn", "yarn install --frozen-lockfile", "yarn.lock")
+    } else if dir.join("package-lock.json").exists() {
+        ("npm", "npm ci", "package-lock.json")
+    } else {
+        ("npm", "npm install", "package-lock.json")
+    };
+
+    let build = if script("build") {
+        format!("{} && {} run build", install, pm)
+    } else {
+        install.to_string()
+    };
+
+    let main = pkg.get("main").and_then(|m| m.as_str());
+    let entry = main.map(String::from).or_else(|| {
+        ["server.js", "index.js", "app.js"]
+            .iter()
+            .find(|f| dir.join(f).is_file())
+            .map(|f| f.to_string())
+    });
+
+    let name = pkg.get("name").and_then(|v| v.as_str());
+    let version = pkg.get("version").and_then(|v| v.as_str());
+
+    let run = if script("start") {
+        format!("{} start", pm)
+    } else if let Some(entry) = entry {
+        format!("node {}", entry)
+    } else if script("build") {
+        // nothing to run: a frontend that builds to static files
+        let root = if dep("react-scripts") {
+            "build"
+        } else if dep("next") {
+            "out"
+        } else {
+            "dist"
+        };
+        let mut config = empty_config(&app_name(dir, name), version.unwrap_or("0.1.0"));
+        config.build = Some(BuildSection {
+            command: Some(build),
+            ..Default::default()
+        });
+        config.web = Some(WebSection {
+            domain: format!("{}.local", config.app.name),
+            root: Some(root.into()),
+        });
+        cache(&mut config, dir, &["node_modules"], lockfile);
+        return Some(Detection {
+            stack: "Node.js static build",
+            config,
+        });
+    } else {
+        return None;
+    };
+
+    let mut d = service(dir, "Node.js", name, version, Some(build), run, 3000);
+    cache(&mut d.config, dir, &["node_modules"], lockfile);
+    Some(d)
+}
+
+fn rust(dir: &Path) -> Option<Detection> {
+    let text = std::fs::read_to_string(dir.join("Cargo.toml")).ok()?;
+    let manifest: toml::Value = toml::from_str(&text).ok()?;
+    let package = manifest.get("package")?;
+    let name = package.get("name")?.as_str()?;
+    let version = package.get("version").and_then(|v| v.as_str());
+
+    let mut d = service(
+        dir,
+        "Rust",
+        Some(name),
+        version,
+        Some("cargo build --release".into()),
+        format!("./target/release/{}", name),
+        8080,
+    );
+    cache(&mut d.config, dir, &["target"], "Cargo.lock");
+    Some(d)
+}
+
+fn python(dir: &Path) -> Option<Detection> {
+    let requirements = dir.join("requirements.txt").is_file();
+    let pyproject = std::fs::read_to_string(dir.join("pyproject.toml")).ok();
+    if !requirements && pyproject.is_none() {
+        return None;
+    }
+
+    let project: Option<toml::Value> = pyproject.and_then(|t| toml::from_str(&t).ok());
+    let field = |k: &str| {
+        project
+            .as_ref()
+            .and_then(|p| p.get("project"))
+            .and_then(|p| p.get(k))
+            .and_then(|v| v.as_str())
+    };
+
+    let install = if requirements {
+        ".venv/bin/pip install -r requirements.txt"
+    } else {
+        ".venv/bin/pip install ."
+    };
+    let build = format!("python3 -m venv .venv && {}", install);
+
+    let deps = std::fs::read_to_string(dir.join("requirements.txt"))
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let (run, port) = if dir.join("manage.py").is_file() {
+        (
+            ".venv/bin/python manage.py runserver 0.0.0.0:${PORT}".to_string(),
+            8000,
+        )
+    } else if deps.contains("uvicorn") && dir.join("main.py").is_file() {
+        (
+            ".venv/bin/uvicorn main:app --host 0.0.0.0 --port ${PORT}".to_string(),
+            8000,
+        )
+    } else {
+        let entry = ["app.py", "main.py", "server.py"]
+            .iter()
+            .find(|f| dir.join(f).is_file())?;
+        (format!(".venv/bin/python {}", entry), 5000)
+    };
+
+    let mut d = service(
+        dir,
+        "Python",
+        field("name"),
+        field("version"),
+        Some(build),
+        run,
+        port,
+    );
+    if requirements {
+        cache(&mut d.config, dir, &[".venv"], "requirements.txt");
+    }
+    Some(d)
+}
+
+fn go(dir: &Path) -> Option<Detection> {
+    let text = std::fs::read_to_string(dir.join("go.mod")).ok()?;
+    let module = text
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("module "))?
+        .trim();
+    let name = sanitize(module.rsplit('/').next().unwrap_or(module));
+
+    Some(service(
+        dir,
+        "Go",
+        Some(&name),
+        None,
+        Some(format!("go build -o {} .", name)),
+        format!("./{}", name),
+        8080,
+    ))
+}
+
+fn static_site(dir: &Path) -> Option<Detection> {
+    let root = ["", "public", "dist", "site"]
+        .iter()
+        .find(|r| dir.join(r).join("index.html").is_file())?;
+
+    let mut config = empty_config(&app_name(dir, None), "0.1.0");
+    config.web = Some(WebSection {
+        domain: format!("{}.local", config.app.name),
+        root: Some(if root.is_empty() { "." } else { root }.to_string()),
+    });
+    Some(Detection {
+        stack: "static site",
+        config,
+    })
+}
+
+// package name if there is one, else the directory name
+fn app_name(dir: &Path, name: Option<&str>) -> String {
+    let name = name
+        .map(|n| n.rsplit('/').next().unwrap_or(n).to_string())
+        .or_else(|| {
+            std::fs::canonicalize(dir)
+                .ok()?
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+        })
+        .unwrap_or_else(|| "app".into());
+    sanitize(&name)
+}
+
+fn sanitize(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("flare-detect-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, text) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn build(d: &Detection) -> &str {
+        d.config.build.as_ref().unwrap().command.as_deref().unwrap()
+    }
+
+    fn run(d: &Detection) -> (&str, Option<u16>) {
+        let run = d.config.run.as_ref().unwrap();
+        (run.command.as_str(), run.port)
+    }
+
+    fn cache(d: &Detection) -> (Option<Vec<String>>, Option<String>) {
+        let build = d.config.build.as_ref().unwrap();
+        (build.cache.clone(), build.cache_key.clone())
+    }
+
+    #[test]
+    fn node_service() {
+        let dir = fixture(
+            "node",
+            &[
+                (
+                    "package.json",
+                    r#"{"name": "@acme/api", "version": "2.1.0", "scripts": {"build": "tsc", "start": "node dist/index.js"}}"#,
+                ),
+                ("package-lock.json", "{}"),
+            ],
+        );
+        let d = detect(&dir).unwrap();
+        assert_eq!(d.stack, "Node.js");
+        assert_eq!(d.config.app.name, "api");
+        assert_eq!(d.config.app.version, "2.1.0");
+        assert_eq!(build(&d), "npm ci && npm run build");
+        assert_eq!(run(&d), ("npm start", Some(3000)));
+        assert_eq!(
+            cache(&d),
+            (
+                Some(vec!["node_modules".into()]),
+                Some("package-lock.json".into())
+            )
+        );
+    }
+
+    #[test]
+    fn node_static_build() {
+        let dir = fixture(
+            "spa",
+            &[
+                (
+                    "package.json",
+                    r#"{"name": "spa", "scripts": {"build": "react-scripts build"}, "dependencies": {"react-scripts": "5"}}"#,
+                ),
+                ("yarn.lock", ""),
+                // the built site wins over a checked-in index.html
+                ("public/index.html", ""),
+            ],
+        );
+        let d = detect(&dir).unwrap();
+        assert_eq!(d.stack, "Node.js static build");
+        assert_eq!(
+            build(&d),
+            "yarn install --frozen-lockfile && yarn run build"
+        );
+        assert!(d.config.run.is_none());
+        let web = d.config.web.as_ref().unwrap();
+        assert_eq!(web.domain, "spa.local");
+        assert_eq!(web.root.as_deref(), Some("build"));
+    }
+
+    #[test]
+    fn node_without_anything_to_run() {
+        let dir = fixture("lib", &[("package.json", r#"{"name": "lib"}"#)]);
+        assert!(detect(&dir).is_none());
+    }
+
+    #[test]
+    fn rust_binary() {
+        let dir = fixture(
+            "rust",
+            &[(
+                "Cargo.toml",
+                "[package]\nname = \"hello\"\nversion = \"0.3.0\"\n",
+            )],
+        );
+        let d = detect(&dir).unwrap();
+        assert_eq!(d.stack, "Rust");
+        assert_eq!(d.config.app.version, "0.3.0");
+        assert_eq!(build(&d), "cargo build --release");
+        assert_eq!(run(&d), ("./target/release/hello", Some(8080)));
+        // no Cargo.lock to key a cache on
+        assert_eq!(cache(&d), (None, None));
+
+        // a workspace root has no [package] to run
+        let dir = fixture("workspace", &[("Cargo.toml", "[workspace]\n")]);
+        assert!(detect(&dir).is_none());
+    }
+
+    #[test]
+    fn python_apps() {
+        let dir = fixture(
+            "uvicorn",
+            &[("requirements.txt", "FastAPI\nUvicorn\n"), ("main.py", "")],
+        );
+        let d = detect(&dir).unwrap();
+        assert_eq!(d.stack, "Python");
+        assert_eq!(
+            build(&d),
+            "python3 -m venv .venv && .venv/bin/pip install -r requirements.txt"
+        );
+        assert_eq!(
+            run(&d),
+            (
+                ".venv/bin/uvicorn main:app --host 0.0.0.0 --port ${PORT}",
+                Some(8000)
+            )
+        );
+        assert_eq!(
+            cache(&d),
+            (Some(vec![".venv".into()]), Some("requirements.txt".into()))
+        );
+
+        let dir = fixture(
+            "pyproject",
+            &[
+                (
+                    "pyproject.toml",
+                    "[project]\nname = \"Tasks\"\nversion = \"1.0\"\n",
+                ),
+                ("app.py", ""),
+            ],
+        );
+        let d = detect(&dir).unwrap();
+        assert_eq!(d.config.app.name, "tasks");
+        assert_eq!(
+            build(&d),
+            "python3 -m venv .venv && .venv/bin/pip install ."
+        );
+        assert_eq!(run(&d), (".venv/bin/python app.py", Some(5000)));
+        assert_eq!(cache(&d), (None, None));
+
+        // dependencies alone don't say what to start
+        let dir = fixture("deps", &[("requirements.txt", "requests\n")]);
+        assert!(detect(&dir).is_none());
+    }
+
+    #[test]
+    fn go_module() {
+        let dir = fixture(
+            "go",
+            &[("go.mod", "module github.com/acme/My.Tool\n\ngo 1.22\n")],
+        );
+        let d = detect(&dir).unwrap();
+        assert_eq!(d.stack, "Go");
+        assert_eq!(d.config.app.name, "my-tool");
+        assert_eq!(build(&d), "go build -o my-tool .");
+        assert_eq!(run(&d), ("./my-tool", Some(8080)));
+    }
+
+    #[test]
+    fn static_site() {
+        let dir = fixture("Static Site", &[("dist/index.html", "")]);
+        let d = detect(&dir).unwrap();
+        assert_eq!(d.stack, "static site");
+        let name = format!("flare-detect-static-site-{}", std::process::id());
+        assert_eq!(d.config.app.name, name);
+        let web = d.config.web.as_ref().unwrap();
+        assert_eq!(web.domain, format!("{}.local", name));
+        assert_eq!(web.root.as_deref(), Some("dist"));
+
+        let dir = fixture("empty", &[("README.md", "")]);
+        assert!(detect(&dir).is_none());
+    }
+
+    #[test]
+    fn flare_toml_wins_over_what_was_detected() {
+        let detected = r#"
+            [app]
+            name = "api"
+            version = "0.1.0"
+
+            [build]
+            command = "npm ci"
+
+            [run]
+            command = "npm start"
+            port = 3000
+        "#;
+        let dir = fixture(
+            "load",
+            &[
+                (DETECTED, detected),
+                (
+                    "flare.toml",
+                    "[app]\nname = \"shop\"\n\n[run]\nport = 8080\n",
+                ),
+            ],
+        );
+        let config: AppConfig = load(&dir).unwrap().try_into().unwrap();
+        assert_eq!(config.app.name, "shop");
+        assert_eq!(config.app.version, "0.1.0");
+        assert_eq!(config.build.unwrap().command.as_deref(), Some("npm ci"));
+        let run = config.run.unwrap();
+        assert_eq!(run.command, "npm start");
+        assert_eq!(run.port, Some(8080));
+
+        // the detected config alone deploys, nothing at all does not
+        std::fs::remove_file(dir.join("flare.toml")).unwrap();
+        let config: AppConfig = load(&dir).unwrap().try_into().unwrap();
+        assert_eq!(config.app.name, "api");
+        std::fs::remove_file(dir.join(DETECTED)).unwrap();
+        assert!(
+            load(&dir)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Can't read")
+        );
+    }
+}
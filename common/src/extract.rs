@@ -0,0 +1,477 @@
+use crate::Manifest;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tar::{Archive, EntryType};
+
+pub use crate::is_contained;
+
+// Archives come straight from a forge, so nothing in them is trusted:
+// every entry is checked before it touches the disk. Used by the daemon for
+// deploys and by the CLI for `flare bundle`.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    Deny,
+    Contained,
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_total_size: u64,
+    pub max_files: u64,
+    pub max_file_size: u64,
+    pub symlinks: SymlinkPolicy,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_total_size: 1 << 30,
+            max_files: 50_000,
+            max_file_size: 256 << 20,
+            symlinks: SymlinkPolicy::Contained,
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Result<Self> {
+        let mut limits = Self::default();
+
+        if let Ok(v) = std::env::var("FLARE_EXTRACT_MAX_SIZE") {
+            limits.max_total_size = crate::parse_size(&v)?;
+        }
+        if let Ok(v) = std::env::var("FLARE_EXTRACT_MAX_FILES") {
+            limits.max_files = v
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid FLARE_EXTRACT_MAX_FILES: {}", v))?;
+        }
+        if let Ok(v) = std::env::var("FLARE_EXTRACT_MAX_FILE_SIZE") {
+            limits.max_file_size = crate::parse_size(&v)?;
+        }
+        if let Ok(v) = std::env::var("FLARE_EXTRACT_SYMLINKS") {
+            limits.symlinks = match v.as_str() {
+                "deny" => SymlinkPolicy::Deny,
+                "contained" => SymlinkPolicy::Contained,
+                other => anyhow::bail!("Invalid FLARE_EXTRACT_SYMLINKS: {}", other),
+            };
+        }
+
+        Ok(limits)
+    }
+}
+
+#[derive(Debug)]
+pub enum Violation {
+    TooManyFiles(u64),
+    FileTooLarge(PathBuf, u64),
+    TotalTooLarge(u64),
+    PathTraversal(PathBuf),
+    SymlinkDenied(PathBuf),
+    SymlinkEscape(PathBuf, PathBuf),
+    ThroughSymlink(PathBuf),
+    HardlinkEscape(PathBuf, PathBuf),
+    DeviceNode(PathBuf),
+    Unsupported(PathBuf, EntryType),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Archive rejected: ")?;
+        match self {
+            Violation::TooManyFiles(max) => write!(f, "more than {} entries", max),
+            Violation::FileTooLarge(p, max) => {
+                write!(f, "{:?} is larger than {} bytes", p, max)
+            }
+            Violation::TotalTooLarge(max) => {
+                write!(f, "uncompressed size exceeds {} bytes", max)
+            }
+            Violation::PathTraversal(p) => write!(f, "{:?} escapes the release directory", p),
+            Violation::SymlinkDenied(p) => write!(f, "symlink {:?} not allowed", p),
+            Violation::SymlinkEscape(p, t) => {
+                write!(f, "symlink {:?} -> {:?} points outside the release", p, t)
+            }
+            Violation::ThroughSymlink(p) => {
+                write!(f, "{:?} is inside a symlink from the same archive", p)
+            }
+            Violation::HardlinkEscape(p, t) => {
+                write!(f, "hard link {:?} -> {:?} points outside the release", p, t)
+            }
+            Violation::DeviceNode(p) => write!(f, "{:?} is a device node or fifo", p),
+            Violation::Unsupported(p, kind) => {
+                write!(f, "{:?} has unsupported entry type {:?}", p, kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+pub fn unpack<R: Read>(reader: R, dest: &Path, limits: &Limits) -> Result<Stats> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(false);
+    archive.set_preserve_ownerships(false);
+    archive.set_unpack_xattrs(false);
+
+    let mut stats = Stats::default();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        let path = entry.path()?.into_owned();
+
+        match kind {
+            // pax metadata, not a file
+            EntryType::XGlobalHeader | EntryType::XHeader => continue,
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                return Err(Violation::DeviceNode(path).into());
+            }
+            EntryType::Regular
+            | EntryType::Continuous
+            | EntryType::Directory
+            | EntryType::Symlink
+            | EntryType::Link => {}
+            other => return Err(Violation::Unsupported(path, other).into()),
+        }
+
+        if !is_contained(Path::new(""), &path) {
+            return Err(Violation::PathTraversal(path).into());
+        }
+        // the containment checks are lexical, so nothing may go through a
+        // link already unpacked: `a -> ..` then `a/b -> ../..` would escape
+        if through_symlink(dest, &path) {
+            return Err(Violation::ThroughSymlink(path).into());
+        }
+
+        stats.files += 1;
+        if stats.files > limits.max_files {
+            return Err(Violation::TooManyFiles(limits.max_files).into());
+        }
+
+        if kind.is_file() {
+            let size = entry.size();
+            if size > limits.max_file_size {
+                return Err(Violation::FileTooLarge(path, limits.max_file_size).into());
+            }
+            stats.bytes += size;
+            if stats.bytes > limits.max_total_size {
+                return Err(Violation::TotalTooLarge(limits.max_total_size).into());
+            }
+        }
+
+        if kind.is_symlink() {
+            let target = link_target(&entry, &path)?;
+            if limits.symlinks == SymlinkPolicy::Deny {
+                return Err(Violation::SymlinkDenied(path).into());
+            }
+            let base = path.parent().unwrap_or(Path::new(""));
+            if !is_contained(base, &target) {
+                return Err(Violation::SymlinkEscape(path, target).into());
+            }
+        }
+
+        if kind.is_hard_link() {
+            // hard link targets are relative to the archive root
+            let target = link_target(&entry, &path)?;
+            if !is_contained(Path::new(""), &target) || through_symlink(dest, &target) {
+                return Err(Violation::HardlinkEscape(path, target).into());
+            }
+        }
+
+        entry.unpack_in(dest)?;
+    }
+
+    Ok(stats)
+}
+
+// any directory above `path` (relative to `dest`) is a symlink on disk
+fn through_symlink(dest: &Path, path: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .filter(|a| !a.as_os_str().is_empty())
+        .any(|a| dest.join(a).is_symlink())
+}
+
+// an uploaded manifest is held to the same limits as an archive, before any
+// of its blobs are sent
+pub fn check_manifest(manifest: &Manifest, limits: &Limits) -> Result<()> {
+    let entries = manifest.dirs.len() + manifest.files.len() + manifest.symlinks.len();
+    if entries as u64 > limits.max_files {
+        return Err(Violation::TooManyFiles(limits.max_files).into());
+    }
+
+    let mut total = 0u64;
+    for file in &manifest.files {
+        if file.size > limits.max_file_size {
+            return Err(
+                Violation::FileTooLarge(file.path.clone().into(), limits.max_file_size).into(),
+            );
+        }
+        total = total.saturating_add(file.size);
+        if total > limits.max_total_size {
+            return Err(Violation::TotalTooLarge(limits.max_total_size).into());
+        }
+    }
+
+    if limits.symlinks == SymlinkPolicy::Deny
+        && let Some(link) = manifest.symlinks.first()
+    {
+        return Err(Violation::SymlinkDenied(link.path.clone().into()).into());
+    }
+    // same reason as for archives: nothing may go through one of the links
+    let links: HashSet<&Path> = manifest
+        .symlinks
+        .iter()
+        .map(|l| Path::new(&l.path))
+        .collect();
+    let paths = manifest
+        .dirs
+        .iter()
+        .chain(manifest.files.iter().map(|f| &f.path));
+    for path in paths.chain(manifest.symlinks.iter().map(|l| &l.path)) {
+        if Path::new(path)
+            .ancestors()
+            .skip(1)
+            .any(|a| links.contains(a))
+        {
+            return Err(Violation::ThroughSymlink(path.into()).into());
+        }
+    }
+    Ok(())
+}
+
+fn link_target<R: Read>(entry: &tar::Entry<R>, path: &Path) -> Result<PathBuf> {
+    entry
+        .link_name()?
+        .map(|t| t.into_owned())
+        .ok_or_else(|| anyhow::anyhow!("Link {:?} has no target", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Item {
+        File(&'static str, &'static [u8]),
+        Symlink(&'static str, &'static str),
+        Hardlink(&'static str, &'static str),
+    }
+
+    fn archive(items: &[Item]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for item in items {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            // set_path/set_link_name refuse "..", which is what is tested here
+            let (path, data): (&str, &[u8]) = match item {
+                Item::File(path, data) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    (path, data)
+                }
+                Item::Symlink(path, target) | Item::Hardlink(path, target) => {
+                    let kind = match item {
+                        Item::Symlink(..) => EntryType::Symlink,
+                        _ => EntryType::Link,
+                    };
+                    header.set_entry_type(kind);
+                    header.set_size(0);
+                    let name = &mut header.as_old_mut().linkname;
+                    name[..target.len()].copy_from_slice(target.as_bytes());
+                    (path, &[])
+                }
+            };
+            let name = &mut header.as_old_mut().name;
+            name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    // a fresh directory per test, inside another one the archive may not touch
+    fn scratch(name: &str) -> PathBuf {
+        let root = std::env::temp_dir()
+            .join(format!("flare-extract-{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dest")).unwrap();
+        root.join("dest")
+    }
+
+    fn unpack_items(name: &str, items: &[Item], limits: &Limits) -> (PathBuf, Result<Stats>) {
+        let dest = scratch(name);
+        let data = archive(items);
+        let result = unpack(&data[..], &dest, limits);
+        (dest, result)
+    }
+
+    fn violation<T: fmt::Debug>(result: Result<T>) -> Violation {
+        result
+            .expect_err("archive should be rejected")
+            .downcast::<Violation>()
+            .expect("a Violation")
+    }
+
+    #[test]
+    fn unpacks_plain_files() {
+        let items = [
+            Item::File("a.txt", b"hello"),
+            Item::File("dir/b.txt", b"world!"),
+        ];
+        let (dest, result) = unpack_items("plain", &items, &Limits::default());
+        let stats = result.unwrap();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.bytes, 11);
+        assert_eq!(std::fs::read(dest.join("dir/b.txt")).unwrap(), b"world!");
+    }
+
+    #[test]
+    fn rejects_parent_traversal() {
+        let items = [Item::File("../evil", b"x")];
+        let (dest, result) = unpack_items("traversal", &items, &Limits::default());
+        assert!(matches!(violation(result), Violation::PathTraversal(_)));
+        assert!(!dest.parent().unwrap().join("evil").exists());
+    }
+
+    #[test]
+    fn rejects_escaping_symlink() {
+        let items = [Item::Symlink("link", "../../etc")];
+        let (_, result) = unpack_items("symlink", &items, &Limits::default());
+        assert!(matches!(violation(result), Violation::SymlinkEscape(..)));
+    }
+
+    #[test]
+    fn allows_contained_symlink() {
+        let items = [
+            Item::File("dir/real", b"x"),
+            Item::Symlink("dir/link", "real"),
+        ];
+        let (dest, result) = unpack_items("contained", &items, &Limits::default());
+        result.unwrap();
+        assert!(dest.join("dir/link").is_symlink());
+    }
+
+    #[test]
+    fn rejects_symlink_chain() {
+        // x/sub is the release root on disk, so x/sub/y -> ../.. is outside
+        let items = [
+            Item::Symlink("x/sub", ".."),
+            Item::Symlink("x/sub/y", "../.."),
+        ];
+        let (dest, result) = unpack_items("chain", &items, &Limits::default());
+        assert!(matches!(violation(result), Violation::ThroughSymlink(_)));
+        assert!(!dest.join("y").exists());
+    }
+
+    #[test]
+    fn rejects_file_written_through_symlink() {
+        let items = [Item::Symlink("up", "."), Item::File("up/f", b"x")];
+        let (_, result) = unpack_items("through", &items, &Limits::default());
+        assert!(matches!(violation(result), Violation::ThroughSymlink(_)));
+    }
+
+    #[test]
+    fn rejects_escaping_hardlink() {
+        let items = [Item::Hardlink("h", "../outside")];
+        let (_, result) = unpack_items("hardlink", &items, &Limits::default());
+        assert!(matches!(violation(result), Violation::HardlinkEscape(..)));
+    }
+
+    #[test]
+    fn denies_symlinks_by_policy() {
+        let limits = Limits {
+            symlinks: SymlinkPolicy::Deny,
+            ..Limits::default()
+        };
+        let items = [Item::Symlink("link", "target")];
+        let (_, result) = unpack_items("deny", &items, &limits);
+        assert!(matches!(violation(result), Violation::SymlinkDenied(_)));
+    }
+
+    #[test]
+    fn enforces_size_and_count_limits() {
+        let limits = Limits {
+            max_files: 1,
+            ..Limits::default()
+        };
+        let items = [Item::File("a", b"1"), Item::File("b", b"2")];
+        let (_, result) = unpack_items("count", &items, &limits);
+        assert!(matches!(violation(result), Violation::TooManyFiles(1)));
+
+        let limits = Limits {
+            max_file_size: 4,
+            ..Limits::default()
+        };
+        let items = [Item::File("big", b"12345")];
+        let (_, result) = unpack_items("file-size", &items, &limits);
+        assert!(matches!(violation(result), Violation::FileTooLarge(_, 4)));
+
+        let limits = Limits {
+            max_total_size: 5,
+            ..Limits::default()
+        };
+        let items = [Item::File("a", b"123"), Item::File("b", b"456")];
+        let (_, result) = unpack_items("total-size", &items, &limits);
+        assert!(matches!(violation(result), Violation::TotalTooLarge(5)));
+    }
+
+    fn manifest(files: &[(&str, u64)], symlinks: &[(&str, &str)]) -> Manifest {
+        Manifest {
+            dirs: Vec::new(),
+            files: files
+                .iter()
+                .map(|(path, size)| crate::FileEntry {
+                    path: path.to_string(),
+                    hash: "0".repeat(64),
+                    size: *size,
+                    executable: false,
+                })
+                .collect(),
+            symlinks: symlinks
+                .iter()
+                .map(|(path, target)| crate::LinkEntry {
+                    path: path.to_string(),
+                    target: target.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn checks_uploaded_manifests() {
+        let limits = Limits {
+            max_files: 2,
+            max_file_size: 10,
+            max_total_size: 15,
+            ..Limits::default()
+        };
+        let ok = manifest(&[("a", 10), ("b", 5)], &[]);
+        assert!(check_manifest(&ok, &limits).is_ok());
+
+        let many = manifest(&[("a", 1), ("b", 1), ("c", 1)], &[]);
+        let result = check_manifest(&many, &limits);
+        assert!(matches!(violation(result), Violation::TooManyFiles(2)));
+
+        let big = manifest(&[("a", 11)], &[]);
+        let result = check_manifest(&big, &limits);
+        assert!(matches!(violation(result), Violation::FileTooLarge(_, 10)));
+
+        let total = manifest(&[("a", 10), ("b", 6)], &[]);
+        let result = check_manifest(&total, &limits);
+        assert!(matches!(violation(result), Violation::TotalTooLarge(15)));
+
+        let through = manifest(&[("a/b", 1)], &[("a", "sub")]);
+        let result = check_manifest(&through, &limits);
+        assert!(matches!(violation(result), Violation::ThroughSymlink(_)));
+    }
+}
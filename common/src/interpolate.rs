@@ -0,0 +1,176 @@
+use anyhow::Result;
+
+// `${NAME}` placeholders in flare.toml string values, resolved on the device
+// at deploy time. `$${` writes a literal `${`.
+
+pub const BUILTINS: &[&str] = &[
+    "PORT",
+    "APP_DIR",
+    "RELEASE_ID",
+    "DB_URL",
+    "DB_PORT",
+    "DEVICE_NAME",
+];
+
+// [secrets] holds references ("env:NAME"), not templates
+const RAW_SECTIONS: &[&str] = &["secrets"];
+
+pub fn expand(s: &str, lookup: &mut dyn FnMut(&str) -> Result<String>) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+
+        if let Some(r) = after.strip_prefix("${") {
+            out.push_str("${");
+            rest = r;
+        } else if let Some(body) = after.strip_prefix('{') {
+            let end = body
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unclosed ${{ in {:?}", s))?;
+            out.push_str(&lookup(body[..end].trim())?);
+            rest = &body[end + 1..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+// names used by `s`, for checks that run without a device
+pub fn names(s: &str) -> Result<Vec<String>> {
+    let mut found = Vec::new();
+    expand(s, &mut |name| {
+        found.push(name.to_string());
+        Ok(String::new())
+    })?;
+    Ok(found)
+}
+
+// expands every string in the document, or only in `section` if given
+pub fn expand_value(
+    value: &mut toml::Value,
+    section: Option<&str>,
+    lookup: &mut dyn FnMut(&str) -> Result<String>,
+) -> Result<()> {
+    let table = match value.as_table_mut() {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    for (key, v) in table.iter_mut() {
+        if RAW_SECTIONS.contains(&key.as_str()) || section.is_some_and(|s| s != key) {
+            continue;
+        }
+        walk(v, key, lookup)?;
+    }
+    Ok(())
+}
+
+fn walk(
+    value: &mut toml::Value,
+    path: &str,
+    lookup: &mut dyn FnMut(&str) -> Result<String>,
+) -> Result<()> {
+    match value {
+        toml::Value::String(s) => {
+            *s = expand(s, lookup).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                walk(item, &format!("{}[{}]", path, i), lookup)?;
+            }
+        }
+        toml::Value::Table(t) => {
+            for (k, v) in t.iter_mut() {
+                walk(v, &format!("{}.{}", path, k), lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(name: &str) -> Result<String> {
+        match name {
+            "PORT" => Ok("8080".into()),
+            "APP_DIR" => Ok("/srv/app".into()),
+            other => anyhow::bail!("Unknown variable {}", other),
+        }
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let out = expand("http://localhost:${PORT}/health", &mut vars).unwrap();
+        assert_eq!(out, "http://localhost:8080/health");
+        let out = expand("${APP_DIR}/data:${ PORT }", &mut vars).unwrap();
+        assert_eq!(out, "/srv/app/data:8080");
+    }
+
+    #[test]
+    fn keeps_escapes_and_lone_dollars() {
+        let out = expand("$${PORT} costs $5 or $", &mut vars).unwrap();
+        assert_eq!(out, "${PORT} costs $5 or $");
+    }
+
+    #[test]
+    fn rejects_unclosed_and_unknown() {
+        assert!(expand("port ${PORT", &mut vars).is_err());
+        assert!(expand("${NOPE}", &mut vars).is_err());
+    }
+
+    #[test]
+    fn lists_names() {
+        let found = names("${PORT} $${ESCAPED} ${DB_URL}").unwrap();
+        assert_eq!(found, ["PORT", "DB_URL"]);
+    }
+
+    #[test]
+    fn expands_documents_but_not_secrets() {
+        let mut doc: toml::Value = toml::from_str(
+            r#"
+            [run]
+            command = "serve --port ${PORT}"
+            args = ["${APP_DIR}", 3]
+
+            [secrets]
+            TOKEN = "env:${PORT}"
+            "#,
+        )
+        .unwrap();
+        expand_value(&mut doc, None, &mut vars).unwrap();
+
+        assert_eq!(doc["run"]["command"].as_str(), Some("serve --port 8080"));
+        assert_eq!(doc["run"]["args"][0].as_str(), Some("/srv/app"));
+        assert_eq!(doc["secrets"]["TOKEN"].as_str(), Some("env:${PORT}"));
+    }
+
+    #[test]
+    fn expands_one_section_and_names_the_field() {
+        let mut doc: toml::Value = toml::from_str(
+            r#"
+            [run]
+            command = "${PORT}"
+
+            [health]
+            url = "${NOPE}"
+            "#,
+        )
+        .unwrap();
+        expand_value(&mut doc, Some("run"), &mut vars).unwrap();
+        assert_eq!(doc["run"]["command"].as_str(), Some("8080"));
+        assert_eq!(doc["health"]["url"].as_str(), Some("${NOPE}"));
+
+        let err = expand_value(&mut doc, None, &mut vars).unwrap_err();
+        assert!(err.to_string().starts_with("health.url:"), "{}", err);
+    }
+}
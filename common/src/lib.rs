@@ -1,7 +1,15 @@
+pub mod bundle;
+pub mod check;
+pub mod detect;
+pub mod extract;
+pub mod interpolate;
+pub mod manifest;
 pub mod network;
+pub mod overlay;
 pub mod types;
 pub mod utils;
 
+pub use manifest::{FileEntry, LinkEntry, Manifest};
 pub use network::*;
 pub use types::*;
 pub use types::{Device, FlareConfig};
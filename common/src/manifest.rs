@@ -0,0 +1,97 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// Describes a release tree by content hash so the daemon can tell which
+// files it already has in its store.
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Manifest {
+    pub dirs: Vec<String>,
+    pub files: Vec<FileEntry>,
+    pub symlinks: Vec<LinkEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileEntry {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    pub executable: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkEntry {
+    pub path: String,
+    pub target: String,
+}
+
+impl FileEntry {
+    // blobs are shared read-only, so the exec bit is part of the key
+    pub fn blob_key(&self) -> String {
+        if self.executable {
+            format!("{}-x", self.hash)
+        } else {
+            self.hash.clone()
+        }
+    }
+}
+
+impl Manifest {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+const SKIP: &[&str] = &[".git"];
+
+pub fn scan(root: &Path) -> Result<Manifest> {
+    let mut manifest = Manifest::default();
+    walk(root, Path::new(""), &mut manifest)?;
+    Ok(manifest)
+}
+
+fn walk(root: &Path, rel: &Path, manifest: &mut Manifest) -> Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(root.join(rel))?
+        .filter_map(|e| e.ok())
+        .collect();
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        if SKIP.iter().any(|s| name == *s) {
+            continue;
+        }
+
+        let path = rel.join(&name);
+        let path_str = path.to_string_lossy().to_string();
+        let meta = std::fs::symlink_metadata(entry.path())?;
+
+        if meta.file_type().is_symlink() {
+            let target = std::fs::read_link(entry.path())?;
+            manifest.symlinks.push(LinkEntry {
+                path: path_str,
+                target: target.to_string_lossy().into(),
+            });
+        } else if meta.is_dir() {
+            manifest.dirs.push(path_str);
+            walk(root, &path, manifest)?;
+        } else if meta.is_file() {
+            manifest.files.push(file_entry(&entry.path(), path_str)?);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn file_entry(file: &Path, path: String) -> Result<FileEntry> {
+    let data = std::fs::read(file)?;
+    let meta = std::fs::metadata(file)?;
+    Ok(FileEntry {
+        path,
+        hash: crate::sha256_hex(&data),
+        size: meta.len(),
+        executable: meta.permissions().mode() & 0o111 != 0,
+    })
+}
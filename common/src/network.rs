@@ -7,7 +7,8 @@ pub async fn send_msg<S>(stream: &mut S, data: &[u8]) -> Result<()>
 where
     S: AsyncWriteExt + Unpin,
 {
-    let len = data.len() as u32;
+    let len = u32::try_from(data.len())
+        .map_err(|_| anyhow::anyhow!("Message of {} bytes is too large to send", data.len()))?;
     stream.write_all(&len.to_be_bytes()).await?;
     stream.write_all(data).await?;
     Ok(())
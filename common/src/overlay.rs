@@ -0,0 +1,154 @@
+use anyhow::Result;
+
+// Overlay sections in flare.toml, deep-merged over the base config in this
+// order: [profile.<name>] picked with --profile, then [tag.<tag>] for each
+// tag of the device (sorted), then [device.<name>]. Tables merge key by key,
+// anything else (arrays included) replaces the base value.
+
+pub fn apply(
+    value: &mut toml::Value,
+    profile: Option<&str>,
+    device: &str,
+    tags: &[String],
+) -> Result<()> {
+    let table = match value.as_table_mut() {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let mut section = |name: &str| match table.remove(name) {
+        Some(toml::Value::Table(t)) => t,
+        _ => toml::Table::new(),
+    };
+    let mut profiles = section("profile");
+    let mut by_tag = section("tag");
+    let mut by_device = section("device");
+
+    let mut overlays = Vec::new();
+
+    if let Some(name) = profile {
+        let overlay = profiles
+            .remove(name)
+            .ok_or_else(|| anyhow::anyhow!("No [profile.{}] in flare.toml", name))?;
+        overlays.push(overlay);
+    }
+
+    let mut tags: Vec<&String> = tags.iter().collect();
+    tags.sort();
+    tags.dedup();
+    overlays.extend(tags.iter().filter_map(|t| by_tag.remove(t.as_str())));
+
+    overlays.extend(by_device.remove(device));
+
+    for overlay in overlays {
+        merge(value, overlay);
+    }
+    Ok(())
+}
+
+pub fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(b), toml::Value::Table(o)) => {
+            for (k, v) in o {
+                match b.get_mut(&k) {
+                    Some(existing) => merge(existing, v),
+                    None => {
+                        b.insert(k, v);
+                    }
+                }
+            }
+        }
+        (b, o) => *b = o,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [app]
+        name = "api"
+
+        [run]
+        command = "serve"
+        port = 8080
+        args = ["--base"]
+
+        [profile.staging.run]
+        port = 9090
+
+        [tag.outdoor.run]
+        args = ["--outdoor"]
+
+        [tag.gpu.run]
+        command = "serve --gpu"
+        args = ["--gpu"]
+
+        [device.pi1.run]
+        port = 7070
+    "#;
+
+    fn config() -> toml::Value {
+        toml::from_str(CONFIG).unwrap()
+    }
+
+    fn tags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn drops_overlay_sections_without_matches() {
+        let mut value = config();
+        apply(&mut value, None, "pi9", &[]).unwrap();
+
+        assert_eq!(value["run"]["port"].as_integer(), Some(8080));
+        assert!(value.get("profile").is_none());
+        assert!(value.get("tag").is_none());
+        assert!(value.get("device").is_none());
+    }
+
+    #[test]
+    fn applies_profile_then_tags_then_device() {
+        let mut value = config();
+        apply(
+            &mut value,
+            Some("staging"),
+            "pi1",
+            &tags(&["outdoor", "gpu"]),
+        )
+        .unwrap();
+
+        // the device wins over the profile, keys nobody overrides stay
+        assert_eq!(value["run"]["port"].as_integer(), Some(7070));
+        assert_eq!(value["run"]["command"].as_str(), Some("serve --gpu"));
+        assert_eq!(value["app"]["name"].as_str(), Some("api"));
+        // tags go in sorted order and arrays are replaced, not appended
+        let args = value["run"]["args"].as_array().unwrap();
+        assert_eq!(args, &[toml::Value::String("--outdoor".into())]);
+    }
+
+    #[test]
+    fn profile_alone() {
+        let mut value = config();
+        apply(&mut value, Some("staging"), "pi9", &[]).unwrap();
+        assert_eq!(value["run"]["port"].as_integer(), Some(9090));
+        assert_eq!(value["run"]["command"].as_str(), Some("serve"));
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        let mut value = config();
+        let err = apply(&mut value, Some("prod"), "pi1", &[]).unwrap_err();
+        assert!(err.to_string().contains("[profile.prod]"));
+    }
+
+    #[test]
+    fn merge_replaces_mismatched_types() {
+        let mut base: toml::Value = toml::from_str("a = { b = 1 }\nc = 2").unwrap();
+        let overlay: toml::Value = toml::from_str("a = 3\nc = { d = 4 }").unwrap();
+        merge(&mut base, overlay);
+        assert_eq!(base["a"].as_integer(), Some(3));
+        assert_eq!(base["c"]["d"].as_integer(), Some(4));
+    }
+}
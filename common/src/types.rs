@@ -1,5 +1,7 @@
+use crate::{FileEntry, Manifest};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeployRequest {
@@ -9,6 +11,30 @@ pub struct DeployRequest {
     pub auth_user: Option<String>,
     pub auth_password: Option<String>,
     pub daemon_token: Option<String>,
+    pub git_ref: Option<String>,
+    pub max_bandwidth: Option<String>, // bytes per second, e.g. "512K"
+    pub manifest: Option<Manifest>,    // set for uploads from a local directory
+    #[serde(default)]
+    pub resume: bool, // continue the last failed build instead of a new release
+    pub artifact: Option<FileEntry>,   // prebuilt upload, replaces [build]
+    pub path: Option<String>,          // app directory inside a monorepo
+    pub profile: Option<String>,       // [profile.<name>] overlay
+    #[serde(default)]
+    pub tags: Vec<String>, // [tag.<name>] overlays, FLARE_DEVICE_TAGS when empty
+    #[serde(default)]
+    pub progress: bool, // the CLI reads DeployProgress messages before the response
+}
+
+// daemon -> CLI while a deploy runs (canary checks, blue-green switch)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployProgress {
+    pub progress: String,
+}
+
+// daemon -> CLI after a manifest: blob keys to send, one message each
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncNeeded {
+    pub missing: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,19 +42,78 @@ pub struct DeployResponse {
     pub success: bool,
     pub message: String,
     pub app_dir: Option<String>,
+    #[serde(default)]
+    pub steps: Vec<StepReport>,
+    #[serde(default)]
+    pub apps: Vec<String>, // names of the apps deployed
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StepReport {
+    pub name: String,
+    pub status: String, // "ok", "failed", "timeout", "skipped", "resumed", "detected"
+    pub duration_ms: u64,
+    pub log: String, // tail of the step output
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManageRequest {
     pub msg_type: String, // "manage"
     pub app: String,
-    pub action: String, // "start", "stop", "restart"
+    pub action: String, // "start", "stop", "restart", "rollback", "status"
+    pub daemon_token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManageResponse {
     pub success: bool,
     pub message: String,
+    #[serde(default)]
+    pub state: Option<AppState>, // for "status"
+}
+
+// `flare env`: device-side variables kept across deploys
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvRequest {
+    pub msg_type: String, // "env"
+    pub app: String,
+    pub action: String, // "set", "unset", "list"
+    pub daemon_token: Option<String>,
+    #[serde(default)]
+    pub vars: HashMap<String, String>, // set
+    #[serde(default)]
+    pub keys: Vec<String>, // unset
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvResponse {
+    pub success: bool,
+    pub message: String,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+}
+
+// `flare logs`: the daemon answers with LogsChunk messages until `done`,
+// or keeps sending while `follow` is set
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogsRequest {
+    pub msg_type: String, // "logs"
+    pub app: String,
+    pub daemon_token: Option<String>,
+    #[serde(default)]
+    pub follow: bool,
+    pub since: Option<String>,   // "10m", "2h", or an RFC 3339 time
+    pub release: Option<String>, // versions/<id>, default: the active one
+    pub grep: Option<String>,    // plain substring
+    pub lines: Option<usize>,    // only the last N matching lines
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogsChunk {
+    pub lines: Vec<String>,
+    #[serde(default)]
+    pub done: bool,
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,14 +124,58 @@ pub struct AppState {
     pub pid: Option<u32>,
     pub port: Option<u16>,
     pub health_url: Option<String>,
-    pub isolation: Option<String>,
+    #[serde(default, deserialize_with = "lenient_isolation")]
+    pub isolation: Option<IsolationType>,
+    #[serde(default)]
+    pub db_port: Option<u16>, // actual port, may differ from [database] port
+    #[serde(default)]
+    pub db_url: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub exit_code: Option<i32>, // last exit, 128 + signal if killed
+    #[serde(default)]
+    pub restarts: u32, // by the supervisor since the last start
+    #[serde(default)]
+    pub health: Option<HealthState>, // kept up to date by the health monitor
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// results of the [health] probes for the running process
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct HealthState {
+    pub live: Option<bool>, // None without a liveness probe or before the first one
+    pub ready: Option<bool>, // None without a readiness probe
+    pub since: Option<String>, // last change of live/ready
+    pub message: Option<String>, // why the last probe failed
+}
+
+// state.toml from before isolation was checked may hold any string, which
+// must not make the app unmanageable
+fn lenient_isolation<'de, D>(d: D) -> Result<Option<IsolationType>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::IntoDeserializer;
+    let name = Option::<String>::deserialize(d)?;
+    Ok(name.and_then(|n| {
+        let d: serde::de::value::StrDeserializer<serde::de::value::Error> =
+            n.as_str().into_deserializer();
+        IsolationType::deserialize(d).ok()
+    }))
+}
+
+/// flare.toml
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct AppConfig {
+    /// Dotenv file in the release loaded before [env], e.g. ".env.production"
+    pub env_file: Option<String>,
     pub app: AppSection,
     pub build: Option<BuildSection>,
     pub run: Option<RunSection>,
+    /// Environment variables for the app
     pub env: Option<HashMap<String, String>>,
     pub web: Option<WebSection>,
     pub health: Option<HealthSection>,
@@ -59,45 +188,225 @@ pub struct AppConfig {
     pub hooks: Option<HooksSection>,
     pub metrics: Option<MetricsSection>,
     pub strategy: Option<StrategySection>,
+    pub artifact: Option<ArtifactSection>,
+    /// Monorepo root: directories deployed as separate apps
+    pub apps: Option<Vec<RepoApp>>,
+    // overlays merged over the sections above, see overlay.rs
+    /// Overlays selected with `flare deploy --profile <name>`
+    #[schemars(with = "Option<HashMap<String, serde_json::Value>>")]
+    pub profile: Option<HashMap<String, toml::Value>>,
+    /// Overlays for devices with a tag (saved device tags or FLARE_DEVICE_TAGS)
+    #[schemars(with = "Option<HashMap<String, serde_json::Value>>")]
+    pub tag: Option<HashMap<String, toml::Value>>,
+    /// Overlays for one device by name (FLARE_DEVICE_NAME or hostname)
+    #[schemars(with = "Option<HashMap<String, serde_json::Value>>")]
+    pub device: Option<HashMap<String, toml::Value>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// root flare.toml of a monorepo: every listed directory has its own
+// flare.toml and is deployed as a separate app
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct RepoConfig {
+    #[serde(default)]
+    pub apps: Vec<RepoApp>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RepoApp {
+    /// App directory with its own flare.toml
+    pub path: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct AppSection {
+    /// Unique on the device, used by start/stop/rollback
     pub name: String,
     pub version: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Build on the device: a single command or named steps
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct BuildSection {
+    pub command: Option<String>,
+    pub steps: Option<Vec<BuildStep>>,
+    /// Directories kept between releases
+    pub cache: Option<Vec<String>>,
+    /// Lockfile whose hash keys the cache
+    pub cache_key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BuildStep {
+    pub name: String,
     pub command: String,
+    /// Directory inside the release to run in
+    pub workdir: Option<String>,
+    pub env: Option<HashMap<String, String>>,
+    /// Seconds before the step is killed
+    pub timeout: Option<u64>,
+    /// Shell condition, the step runs if it exits 0
+    pub when: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Prebuilt artifacts by target, e.g. aarch64 = "dist/app-aarch64.tar.gz"
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct ArtifactSection {
+    /// Target (arch or triple) -> URL or path in the repo
+    pub targets: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct RunSection {
     pub command: String,
+    /// Port the app listens on, also `${PORT}`
     pub port: Option<u16>,
+    /// When the supervisor starts the app again after it exits (default: on-failure)
+    pub restart: Option<RestartPolicy>,
+    /// Quick restarts in a row before giving up (default: 5)
+    pub max_restarts: Option<u32>,
+    /// Start again when flared starts, if it was running before (default: true)
+    pub autostart: Option<bool>,
+    /// Sent to the app's process group on stop (default: SIGTERM)
+    pub stop_signal: Option<StopSignal>,
+    /// Seconds to wait after stop_signal before SIGKILL (default: 10)
+    pub stop_timeout: Option<u64>,
+    /// Gateway virtual host routed to `port` while the app is ready
+    pub domain: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum StopSignal {
+    #[serde(rename = "SIGTERM", alias = "TERM")]
+    Term,
+    #[serde(rename = "SIGINT", alias = "INT")]
+    Int,
+    #[serde(rename = "SIGQUIT", alias = "QUIT")]
+    Quit,
+    #[serde(rename = "SIGHUP", alias = "HUP")]
+    Hup,
+    #[serde(rename = "SIGUSR1", alias = "USR1")]
+    Usr1,
+    #[serde(rename = "SIGUSR2", alias = "USR2")]
+    Usr2,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// After any exit
+    Always,
+    /// After a non-zero exit or a signal
+    OnFailure,
+    /// Leave it stopped
+    Never,
+}
+
+/// Static site served by the gateway
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct WebSection {
     pub domain: String,
+    /// Directory with index.html (default: release root)
     pub root: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Probes run while the app is running
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct HealthSection {
-    pub url: String,
+    /// Shorthand for an http readiness probe
+    pub url: Option<String>,
+    /// Seconds
     pub timeout: Option<u64>,
+    /// Restarts the app when it fails
+    pub liveness: Option<Probe>,
+    /// Takes the app out of the gateway while it fails
+    pub readiness: Option<Probe>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Probe {
+    pub r#type: ProbeType,
+    /// http: URL to GET
+    pub url: Option<String>,
+    /// http: expected status (default: any 2xx)
+    pub status: Option<u16>,
+    /// http: text the response body must contain
+    pub body: Option<String>,
+    /// tcp: port on localhost (default: [run] port)
+    pub port: Option<u16>,
+    /// exec: shell command in the release, healthy when it exits 0
+    pub command: Option<String>,
+    /// Seconds between probes (default: 10)
+    pub interval: Option<u64>,
+    /// Seconds before a probe counts as failed (default: 5)
+    pub timeout: Option<u64>,
+    /// Seconds after the app starts before the first probe (default: 0)
+    pub initial_delay: Option<u64>,
+    /// Failures in a row before the probe fails (default: 3)
+    pub failure_threshold: Option<u32>,
+    /// Successes in a row before the probe passes again (default: 1)
+    pub success_threshold: Option<u32>,
+}
+
+impl Probe {
+    // every setting at its default
+    pub fn of(r#type: ProbeType) -> Probe {
+        Probe {
+            r#type,
+            url: None,
+            status: None,
+            body: None,
+            port: None,
+            command: None,
+            interval: None,
+            timeout: None,
+            initial_delay: None,
+            failure_threshold: None,
+            success_threshold: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeType {
+    /// GET `url`
+    Http,
+    /// Connect to `port`
+    Tcp,
+    /// Run `command`
+    Exec,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct IsolationSection {
-    pub r#type: String,
+    pub r#type: IsolationType,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum IsolationType {
+    /// Plain child process
+    None,
+    /// Transient systemd scope
+    Systemd,
+    /// chroot into the release
+    Chroot,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct StorageSection {
-    pub r#type: String,
+    pub r#type: StorageType,
     pub bucket: Option<String>,
     pub endpoint: Option<String>,
     pub access_key: Option<String>,
@@ -107,52 +416,107 @@ pub struct StorageSection {
     pub public: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageType {
+    S3,
+    Local,
+}
+
+/// Database container (or file) set up before the build
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DatabaseSection {
-    pub r#type: String,
+    pub r#type: DatabaseType,
     pub name: Option<String>,
     pub user: Option<String>,
     pub password: Option<String>,
+    /// Requested port, the next free one is used if busy (`${DB_PORT}`)
     pub port: Option<u16>,
+    /// SQL file run after setup
     pub preseed: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseType {
+    /// postgres:14-alpine in docker
+    Postgres,
+    /// mysql:8.0 in docker
+    Mysql,
+    /// File in the release
+    Sqlite,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct NotifySection {
     pub on_success: Option<Vec<String>>,
     pub on_fail: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Name -> "env:VAR", "file:/path" or a literal, used as `${secret.NAME}`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
 pub struct SecretsSection {
-    #[serde(flatten)]
     pub secrets: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ResourceLimitsSection {
+    /// e.g. 512M
     pub memory: Option<String>,
+    /// CPUs, e.g. 0.5
     pub cpu: Option<String>,
     pub timeout: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct HooksSection {
     pub pre_deploy: Option<String>,
+    /// Runs once the app is up (and healthy, with [health])
     pub post_deploy: Option<String>,
+    /// Runs after a failed health check put the previous release back;
+    /// the reason is in FLARE_ROLLBACK_REASON
+    pub on_rollback: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct MetricsSection {
     pub pushgateway: Option<String>,
     pub collect: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct StrategySection {
-    pub r#type: String,
+    pub r#type: StrategyType,
+    /// Share of traffic for canary deploys, 1-100
     pub percent: Option<u8>,
     pub wait_time: Option<String>,
+    /// Canary: share of 5xx responses, 1-100, that rolls it back (default 5)
+    pub max_error_rate: Option<u8>,
+    /// Rolling: devices per batch, a number or a percentage like "25%"
+    pub batch_size: Option<String>,
+    /// Rolling: failed devices tolerated before the rollout stops (default 0)
+    pub max_failures: Option<String>,
+    /// Rolling: roll back devices already updated when the rollout stops
+    pub rollback: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StrategyType {
+    /// Send part of the traffic to the new release first
+    Canary,
+    /// Start the new release next to the old one, then switch
+    #[serde(alias = "blue-green")]
+    Bluegreen,
+    /// Replace devices one batch at a time
+    Rolling,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -162,6 +526,12 @@ pub struct Device {
     pub host: String,
     pub port: u16,
     pub token: Option<String>,
+    pub max_bandwidth: Option<String>,
+    // for fleet deploys: --group, --tag key=value
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -170,6 +540,18 @@ pub struct FlareConfig {
     pub devices: Vec<Device>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InfoRequest {
+    pub msg_type: String, // "info"
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub version: String,
+    pub arch: String,
+    pub os: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterTokenRequest {
     pub msg_type: String,
@@ -180,3 +562,26 @@ pub struct RegisterTokenRequest {
 pub struct RegisterTokenResponse {
     pub success: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(isolation: &str) -> AppState {
+        let text = format!(
+            "name = \"api\"\nversion = \"1\"\nstatus = \"running\"\n{}",
+            isolation
+        );
+        toml::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn old_isolation_strings_still_load() {
+        assert_eq!(
+            state("isolation = \"systemd\"").isolation,
+            Some(IsolationType::Systemd)
+        );
+        assert_eq!(state("isolation = \"docker\"").isolation, None);
+        assert_eq!(state("").isolation, None);
+    }
+}
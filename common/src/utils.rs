@@ -3,7 +3,7 @@ use anyhow::Result;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use rand::RngCore;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 pub fn flare_dir() -> PathBuf {
     let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".into());
@@ -16,6 +16,8 @@ pub fn apps_dir() -> PathBuf {
         .unwrap_or_else(|_| flare_dir().join("apps"))
 }
 
+// "user/repo" lives in user_repo; deploys refuse a second name that
+// flattens to the same directory (see the daemon's monorepo::claim)
 pub fn app_dir(name: &str) -> PathBuf {
     apps_dir().join(name.replace("/", "_"))
 }
@@ -34,13 +36,16 @@ pub fn is_local_network(host: &str) -> bool {
         })
 }
 
-pub fn save_state(dir: &PathBuf, state: &AppState) -> Result<()> {
+pub fn save_state(dir: &Path, state: &AppState) -> Result<()> {
+    // written aside and renamed, the supervisor reads it from other threads
     let content = toml::to_string_pretty(state)?;
-    std::fs::write(dir.join("state.toml"), content)?;
+    let tmp = dir.join("state.toml.tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(tmp, dir.join("state.toml"))?;
     Ok(())
 }
 
-pub fn load_state(dir: &PathBuf) -> Result<Option<AppState>> {
+pub fn load_state(dir: &Path) -> Result<Option<AppState>> {
     let path = dir.join("state.toml");
     if !path.exists() {
         return Ok(None);
@@ -49,7 +54,7 @@ pub fn load_state(dir: &PathBuf) -> Result<Option<AppState>> {
     Ok(Some(toml::from_str(&content)?))
 }
 
-pub fn load_app_config(dir: &PathBuf) -> Result<AppConfig> {
+pub fn load_app_config(dir: &Path) -> Result<AppConfig> {
     let path = dir.join("flare.toml");
     let content = std::fs::read_to_string(&path)
         .map_err(|e| anyhow::anyhow!("Can't read {:?}: {}", path, e))?;
@@ -85,10 +90,10 @@ pub fn get_device(id_or_name: &str) -> Result<Device> {
     let config = load_config()?;
 
     // try by id
-    if let Ok(id) = id_or_name.parse::<u32>() {
-        if let Some(d) = config.devices.iter().find(|d| d.id == id) {
-            return Ok(d.clone());
-        }
+    if let Ok(id) = id_or_name.parse::<u32>()
+        && let Some(d) = config.devices.iter().find(|d| d.id == id)
+    {
+        return Ok(d.clone());
     }
 
     // try by name
@@ -130,3 +135,155 @@ pub fn verify_token(token: &str, hash: &str) -> bool {
         .verify_password(token.as_bytes(), &parsed_hash)
         .is_ok()
 }
+
+// "512M", "1G", "64KB" or plain bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let upper = s.to_ascii_uppercase();
+    let digits = upper.trim_end_matches('B');
+    let (num, mult) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1u64 << 10),
+        Some('M') => (&digits[..digits.len() - 1], 1 << 20),
+        Some('G') => (&digits[..digits.len() - 1], 1 << 30),
+        _ => (digits, 1),
+    };
+
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|v| v.checked_mul(mult))
+        .ok_or_else(|| anyhow::anyhow!("Invalid size: {}", s))
+}
+
+// "30s", "10m", "2h", "1d" or plain seconds
+pub fn parse_duration(s: &str) -> Result<std::time::Duration> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1u64),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        Some('d') => (&s[..s.len() - 1], 86400),
+        _ => (s, 1),
+    };
+
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|v| v.checked_mul(mult))
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| anyhow::anyhow!("Invalid duration: {}", s))
+}
+
+// a number of devices, or a percentage of them: "5" or "20%"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Share {
+    Count(usize),
+    Percent(u8),
+}
+
+impl Share {
+    pub fn parse(s: &str) -> Result<Share> {
+        let s = s.trim();
+        let invalid = || anyhow::anyhow!("Invalid count or percentage: {}", s);
+        match s.strip_suffix('%') {
+            Some(p) => match p.trim().parse::<u8>() {
+                Ok(p) if p <= 100 => Ok(Share::Percent(p)),
+                _ => Err(invalid()),
+            },
+            None => s.parse().map(Share::Count).map_err(|_| invalid()),
+        }
+    }
+
+    // percentages round up, so 10% of 5 devices is one
+    pub fn of(self, total: usize) -> usize {
+        match self {
+            Share::Count(n) => n,
+            Share::Percent(p) => (total * p as usize).div_ceil(100),
+        }
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(data))
+}
+
+// lexically resolves `rel` against `base` (both relative to the release root)
+// and checks that it never climbs above the root
+pub fn is_contained(base: &Path, rel: &Path) -> bool {
+    let mut depth: usize = 0;
+
+    for c in base.components().chain(rel.components()) {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration(" 2h ").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        for bad in ["", "m", "10x", "-5s", "1.5h", "ten"] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
+        }
+        let err = parse_duration("99999999999999999d").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid duration: 99999999999999999d");
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64KB").unwrap(), 64 << 10);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("1g").unwrap(), 1 << 30);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("5T").is_err());
+        let err = parse_size("99999999999999G").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid size: 99999999999999G");
+    }
+
+    #[test]
+    fn parses_shares() {
+        assert_eq!(Share::parse("5").unwrap(), Share::Count(5));
+        assert_eq!(Share::parse(" 20% ").unwrap(), Share::Percent(20));
+        assert_eq!(Share::parse("100%").unwrap(), Share::Percent(100));
+        for bad in ["", "%", "101%", "-1", "2.5", "ten%"] {
+            assert!(Share::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn shares_of_a_fleet() {
+        assert_eq!(Share::Count(3).of(10), 3);
+        assert_eq!(Share::Percent(25).of(8), 2);
+        // rounded up, so a small percentage still picks a device
+        assert_eq!(Share::Percent(10).of(5), 1);
+        assert_eq!(Share::Percent(34).of(3), 2);
+        assert_eq!(Share::Percent(100).of(7), 7);
+        assert_eq!(Share::Percent(0).of(7), 0);
+        assert_eq!(Share::Percent(50).of(0), 0);
+    }
+}
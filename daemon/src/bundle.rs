@@ -0,0 +1,401 @@
+use anyhow::Result;
+use common::bundle::{self, BundleManifest};
+use common::{DeployRequest, FileEntry};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{error, info, warn};
+
+use crate::deploy::{self, Report, extract, store};
+use crate::server::Routes;
+
+// Imports bundles made by `flare bundle` on devices without network access,
+// either with `flared import <file>` or by dropping them into FLARE_DROP_DIR.
+// `flared import` only hands the file to the running daemon over a local
+// socket: the daemon owns the routes and supervises what it starts.
+
+#[derive(Serialize, Deserialize)]
+struct ImportRequest {
+    file: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ImportResponse {
+    success: bool,
+    message: String,
+}
+
+fn socket_path() -> PathBuf {
+    common::flare_dir().join("flared.sock")
+}
+
+pub async fn listen(routes: Routes) {
+    let path = socket_path();
+    let _ = std::fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Can't listen on {:?}: {}", path, e);
+            return;
+        }
+    };
+    // deploying is for the daemon's own user
+    if let Err(e) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)) {
+        warn!("Can't restrict {:?}: {}", path, e);
+    }
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((s, _)) => s,
+            Err(e) => {
+                warn!("Import connection failed: {}", e);
+                continue;
+            }
+        };
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            let resp = match common::recv_json::<_, ImportRequest>(&mut stream).await {
+                Ok(req) => {
+                    info!("Importing {:?}", req.file);
+                    match import(&req.file, routes).await {
+                        Ok(release) => ImportResponse {
+                            success: true,
+                            message: release.display().to_string(),
+                        },
+                        Err(e) => {
+                            error!("Import of {:?} failed: {:#}", req.file, e);
+                            ImportResponse {
+                                success: false,
+                                message: format!("{:#}", e),
+                            }
+                        }
+                    }
+                }
+                Err(e) => ImportResponse {
+                    success: false,
+                    message: e.to_string(),
+                },
+            };
+            let _ = common::send_json(&mut stream, &resp).await;
+        });
+    }
+}
+
+// `flared import <file>`: the release the daemon deployed it to
+pub async fn request_import(file: &Path) -> Result<String> {
+    // the daemon runs somewhere else
+    let file = std::fs::canonicalize(file)?;
+    let path = socket_path();
+    let mut stream = UnixStream::connect(&path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Can't reach flared at {:?}, is it running? ({})", path, e))?;
+
+    common::send_json(&mut stream, &ImportRequest { file }).await?;
+    let resp: ImportResponse = common::recv_json(&mut stream).await?;
+    if !resp.success {
+        anyhow::bail!(resp.message);
+    }
+    Ok(resp.message)
+}
+
+pub async fn import(file: &Path, routes: Routes) -> Result<PathBuf> {
+    let staging = common::flare_dir().join("imports").join(format!(
+        "{}-{}",
+        std::process::id(),
+        chrono::Utc::now().timestamp_millis()
+    ));
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::create_dir_all(&staging)?;
+
+    let result = import_from(file, &staging, routes).await;
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+async fn import_from(file: &Path, staging: &Path, routes: Routes) -> Result<PathBuf> {
+    let limits = extract::Limits::from_env()?;
+    extract::unpack(GzDecoder::new(std::fs::File::open(file)?), staging, &limits)?;
+
+    let (manifest, config) = open(file, staging, &bundle::load_trusted_keys())?;
+    let config_hash = manifest.config_hash.clone();
+    let store = store::store_dir();
+
+    // file contents are checked against the signed hashes on the way in
+    let mut source = manifest.source.unwrap_or_default();
+    let keys = source
+        .files
+        .iter()
+        .chain(&manifest.artifact)
+        .map(|f| f.blob_key());
+    let _pin = store::pin(keys);
+    for f in &source.files {
+        if !extract::is_contained(Path::new(""), Path::new(&f.path)) {
+            anyhow::bail!("Bundle path {:?} escapes the release", f.path);
+        }
+        add_blob(&store, f, &staging.join("source").join(&f.path))?;
+    }
+
+    // the bundled flare.toml wins over whatever the source tree had
+    let config_path = match &manifest.path {
+        Some(p) => format!("{}/flare.toml", deploy::monorepo::normalize(p)?),
+        None => "flare.toml".into(),
+    };
+    let entry = FileEntry {
+        path: config_path.trim_start_matches('/').into(),
+        hash: config_hash,
+        size: config.len() as u64,
+        executable: false,
+    };
+    let _config_pin = store::pin([entry.blob_key()]);
+    add_blob(&store, &entry, &staging.join("flare.toml"))?;
+    source.files.retain(|f| f.path != entry.path);
+    source.files.push(entry);
+
+    if let Some(a) = &manifest.artifact {
+        if Path::new(&a.path).file_name() != Some(std::ffi::OsStr::new(&a.path)) {
+            anyhow::bail!("Invalid artifact name {:?}", a.path);
+        }
+        add_blob(&store, a, &staging.join("artifact").join(&a.path))?;
+    }
+
+    let req = DeployRequest {
+        msg_type: "deploy".into(),
+        repo: manifest.app.clone(),
+        forge: "bundle".into(),
+        auth_user: None,
+        auth_password: None,
+        daemon_token: None,
+        git_ref: None,
+        max_bandwidth: None,
+        manifest: Some(source),
+        resume: false,
+        artifact: manifest.artifact,
+        path: manifest.path,
+        profile: manifest.profile,
+        tags: Vec::new(),
+        progress: false,
+    };
+
+    let mut report = Report::default();
+    let dirs = deploy::run(&req, routes, &mut report).await?;
+    dirs.into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Bundle deployed no app"))
+}
+
+// the signed bundle.json, and flare.toml checked against it
+fn open(file: &Path, staging: &Path, trusted: &[String]) -> Result<(BundleManifest, Vec<u8>)> {
+    let json = std::fs::read(staging.join("bundle.json"))
+        .map_err(|_| anyhow::anyhow!("{:?} is not a Flare bundle", file))?;
+    let sig = std::fs::read_to_string(staging.join("bundle.sig"))
+        .map_err(|_| anyhow::anyhow!("{:?} is not signed", file))?;
+
+    let signer = bundle::verify(&json, &sig, trusted)?;
+    let manifest: BundleManifest = serde_json::from_slice(&json)?;
+    info!(
+        "Bundle for {} created {}, signed by {}",
+        manifest.app,
+        manifest.created,
+        &signer[..16]
+    );
+
+    let config = std::fs::read(staging.join("flare.toml"))?;
+    if common::sha256_hex(&config) != manifest.config_hash {
+        anyhow::bail!("flare.toml does not match the signed bundle");
+    }
+    Ok((manifest, config))
+}
+
+fn add_blob(store: &Path, entry: &FileEntry, staged: &Path) -> Result<()> {
+    let data =
+        std::fs::read(staged).map_err(|_| anyhow::anyhow!("Bundle is missing {:?}", entry.path))?;
+    // reject tampered bundles even when the store already has the blob
+    if common::sha256_hex(&data) != entry.hash {
+        anyhow::bail!("{:?} does not match the signed bundle", entry.path);
+    }
+
+    let key = entry.blob_key();
+    if store::blob_in(store, &key).exists() {
+        return Ok(());
+    }
+    store::write_blob_in(store, &key, &data)
+}
+
+// a file is picked up once its size stops changing between polls, so
+// half-copied bundles are left alone
+pub async fn watch(dir: PathBuf, routes: Routes) {
+    info!("Watching {:?} for bundles", dir);
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Can't read drop dir {:?}: {}", dir, e);
+                continue;
+            }
+        };
+
+        let mut seen = HashMap::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != bundle::BUNDLE_EXT) {
+                continue;
+            }
+            let size = match entry.metadata() {
+                Ok(m) if m.is_file() => m.len(),
+                _ => continue,
+            };
+            seen.insert(path.clone(), size);
+
+            if sizes.get(&path) != Some(&size) {
+                continue;
+            }
+
+            info!("Importing {:?}", path);
+            let outcome = import(&path, routes.clone()).await;
+            let target = match &outcome {
+                Ok(release) => {
+                    info!("Imported {:?} as {:?}", path, release);
+                    "imported"
+                }
+                Err(e) => {
+                    error!("Import of {:?} failed: {}", path, e);
+                    "failed"
+                }
+            };
+
+            if let Err(e) = file_away(&path, &dir.join(target), outcome.err()) {
+                error!("Can't move {:?} out of the drop dir: {}", path, e);
+            }
+            seen.remove(&path);
+        }
+        sizes = seen;
+    }
+}
+
+fn file_away(path: &Path, dest: &Path, err: Option<anyhow::Error>) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    let target = dest.join(path.file_name().unwrap());
+    std::fs::rename(path, &target)?;
+    if let Some(e) = err {
+        std::fs::write(target.with_extension("error"), format!("{:#}\n", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::bundle::SigningKey;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("flare-bundle-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // an unpacked bundle signed by `key`
+    fn staged(name: &str, key: &SigningKey) -> PathBuf {
+        let dir = scratch(name);
+        let config = "[app]\nname = \"demo\"\nversion = \"1\"\n";
+        let manifest = BundleManifest {
+            app: "demo".into(),
+            created: "2026-01-01T00:00:00Z".into(),
+            config_hash: common::sha256_hex(config.as_bytes()),
+            source: None,
+            artifact: None,
+            public_key: bundle::public_key_hex(key),
+            path: None,
+            profile: None,
+        };
+        let json = serde_json::to_vec(&manifest).unwrap();
+        std::fs::write(dir.join("bundle.json"), &json).unwrap();
+        std::fs::write(dir.join("bundle.sig"), bundle::sign(key, &json)).unwrap();
+        std::fs::write(dir.join("flare.toml"), config).unwrap();
+        dir
+    }
+
+    fn entry(path: &str, content: &str) -> FileEntry {
+        FileEntry {
+            path: path.into(),
+            hash: common::sha256_hex(content.as_bytes()),
+            size: content.len() as u64,
+            executable: false,
+        }
+    }
+
+    #[test]
+    fn opens_a_signed_bundle() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = staged("ok", &key);
+        let trusted = vec![bundle::public_key_hex(&key)];
+        let (manifest, config) = open(Path::new("b.flare"), &dir, &trusted).unwrap();
+        assert_eq!(manifest.app, "demo");
+        assert_eq!(common::sha256_hex(&config), manifest.config_hash);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_an_untrusted_signer() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = staged("untrusted", &key);
+        let other = vec![bundle::public_key_hex(&SigningKey::from_bytes(&[2; 32]))];
+        let err = open(Path::new("b.flare"), &dir, &other).unwrap_err();
+        assert!(err.to_string().contains("not from a trusted key"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_a_flipped_byte_in_bundle_json() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = staged("flipped", &key);
+        let mut json = std::fs::read(dir.join("bundle.json")).unwrap();
+        json[10] ^= 1;
+        std::fs::write(dir.join("bundle.json"), json).unwrap();
+        let trusted = vec![bundle::public_key_hex(&key)];
+        assert!(open(Path::new("b.flare"), &dir, &trusted).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_a_flare_toml_that_was_swapped() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let dir = staged("config", &key);
+        std::fs::write(dir.join("flare.toml"), "[app]\nname = \"evil\"\n").unwrap();
+        let trusted = vec![bundle::public_key_hex(&key)];
+        let err = open(Path::new("b.flare"), &dir, &trusted).unwrap_err();
+        assert!(err.to_string().contains("does not match the signed bundle"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_a_tampered_blob_the_store_already_has() {
+        let dir = scratch("blob");
+        let store = dir.join("store");
+        let signed = entry("app.js", "original");
+        store::write_blob_in(&store, &signed.blob_key(), b"original").unwrap();
+
+        let staged = dir.join("app.js");
+        std::fs::write(&staged, "tampered").unwrap();
+        let err = add_blob(&store, &signed, &staged).unwrap_err();
+        assert!(err.to_string().contains("does not match the signed bundle"));
+
+        std::fs::write(&staged, "original").unwrap();
+        add_blob(&store, &signed, &staged).unwrap();
+        let fresh = entry("new.js", "fresh");
+        std::fs::write(&staged, "fresh").unwrap();
+        add_blob(&store, &fresh, &staged).unwrap();
+        assert!(store::blob_in(&store, &fresh.blob_key()).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
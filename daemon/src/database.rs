@@ -1,19 +1,24 @@
 use anyhow::Result;
-use common::DatabaseSection;
-use std::path::PathBuf;
+use common::{DatabaseSection, DatabaseType};
+use std::path::Path;
 use std::process::Command;
 use tracing::info;
 
-pub fn setup(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
-    match db.r#type.as_str() {
-        "postgres" => postgres(db, dir),
-        "mysql" => mysql(db, dir),
-        "sqlite" => sqlite(db, dir),
-        t => anyhow::bail!("Unknown database: {}", t),
+// what the app should connect to, after free-port fallback
+pub struct Connection {
+    pub port: Option<u16>,
+    pub url: String,
+}
+
+pub fn setup(db: &DatabaseSection, dir: &Path) -> Result<Connection> {
+    match db.r#type {
+        DatabaseType::Postgres => postgres(db, dir),
+        DatabaseType::Mysql => mysql(db, dir),
+        DatabaseType::Sqlite => sqlite(db, dir),
     }
 }
 
-fn postgres(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
+fn postgres(db: &DatabaseSection, dir: &Path) -> Result<Connection> {
     let name = db.name.as_deref().unwrap_or("postgres");
     let user = db.user.as_deref().unwrap_or("postgres");
     let pass = db.password.as_deref().unwrap_or("password");
@@ -58,10 +63,16 @@ fn postgres(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
     run_preseed(&container, db, dir, &["psql", "-U", user, "-d", name])?;
 
     info!("PostgreSQL ready on port {}", actual_port);
-    Ok(())
+    Ok(Connection {
+        port: Some(actual_port),
+        url: format!(
+            "postgres://{}:{}@127.0.0.1:{}/{}",
+            user, pass, actual_port, name
+        ),
+    })
 }
 
-fn mysql(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
+fn mysql(db: &DatabaseSection, dir: &Path) -> Result<Connection> {
     let name = db.name.as_deref().unwrap_or("mysql");
     let user = db.user.as_deref().unwrap_or("root");
     let pass = db.password.as_deref().unwrap_or("password");
@@ -103,10 +114,13 @@ fn mysql(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
     )?;
 
     info!("MySQL ready on port {}", port);
-    Ok(())
+    Ok(Connection {
+        port: Some(port),
+        url: format!("mysql://{}:{}@127.0.0.1:{}/{}", user, pass, port, name),
+    })
 }
 
-fn sqlite(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
+fn sqlite(db: &DatabaseSection, dir: &Path) -> Result<Connection> {
     let name = db.name.as_deref().unwrap_or("app.db");
     let path = dir.join(name);
 
@@ -125,7 +139,10 @@ fn sqlite(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
     }
 
     info!("SQLite ready: {:?}", path);
-    Ok(())
+    Ok(Connection {
+        port: None,
+        url: format!("sqlite://{}", path.display()),
+    })
 }
 
 fn stop_container(name: &str) {
@@ -133,7 +150,7 @@ fn stop_container(name: &str) {
     let _ = Command::new("docker").args(["rm", name]).status();
 }
 
-fn run_preseed(container: &str, db: &DatabaseSection, dir: &PathBuf, cmd: &[&str]) -> Result<()> {
+fn run_preseed(container: &str, db: &DatabaseSection, dir: &Path, cmd: &[&str]) -> Result<()> {
     let preseed = match &db.preseed {
         Some(p) => p,
         None => return Ok(()),
@@ -155,7 +172,7 @@ fn run_preseed(container: &str, db: &DatabaseSection, dir: &PathBuf, cmd: &[&str
     Ok(())
 }
 
-fn find_free_port(start: u16) -> Result<u16> {
+pub fn find_free_port(start: u16) -> Result<u16> {
     for port in start..65535 {
         if port_available(port) {
             return Ok(port);
@@ -165,8 +182,5 @@ fn find_free_port(start: u16) -> Result<u16> {
 }
 
 fn port_available(port: u16) -> bool {
-    match std::net::TcpListener::bind(("0.0.0.0", port)) {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    std::net::TcpListener::bind(("0.0.0.0", port)).is_ok()
 }
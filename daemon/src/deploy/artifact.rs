@@ -0,0 +1,168 @@
+use anyhow::Result;
+use common::{AppConfig, ArtifactSection, DeployRequest};
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use super::{download, extract, store};
+
+// Prebuilt artifacts replace `[build]`: either uploaded with
+// `flare deploy --artifact`, or picked from `[artifact]` by device arch.
+
+pub struct Artifact {
+    pub path: PathBuf,
+    pub name: String,
+    // a downloaded one is only in the store until it is installed
+    _pin: Option<store::Pin>,
+}
+
+// rust calls 32-bit arm "arm", users write armv7
+pub fn arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "arm" => "armv7",
+        other => other,
+    }
+}
+
+fn select(section: &ArtifactSection) -> Option<(&String, &String)> {
+    let arch = arch();
+    let prefix = format!("{}-", arch);
+
+    // exact name first, then full target triples like aarch64-unknown-linux-gnu
+    section
+        .targets
+        .iter()
+        .find(|(k, _)| k.as_str() == arch)
+        .or_else(|| section.targets.iter().find(|(k, _)| k.starts_with(&prefix)))
+}
+
+pub async fn resolve(
+    req: &DeployRequest,
+    config: &AppConfig,
+    release: &Path,
+) -> Result<Option<Artifact>> {
+    if let Some(upload) = &req.artifact {
+        // received into the store before the deploy started
+        return Ok(Some(Artifact {
+            path: store::blob_path(&upload.blob_key()),
+            name: file_name(&upload.path)?,
+            _pin: None,
+        }));
+    }
+
+    let section = match &config.artifact {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let (target, location) = match select(section) {
+        Some(t) => t,
+        None => {
+            info!("No artifact for {}, falling back to [build]", arch());
+            return Ok(None);
+        }
+    };
+
+    info!("Using {} artifact: {}", target, location);
+
+    if location.starts_with("http://") || location.starts_with("https://") {
+        // only send forge credentials back to the forge itself
+        let data =
+            download::fetch_url(location, target, req, same_origin(location, &req.forge)).await?;
+
+        let hash = common::sha256_hex(&data);
+        let pin = store::pin([hash.clone()]);
+        if !store::blob_path(&hash).exists() {
+            store::write_blob(&hash, &data)?;
+        }
+        let name = location.rsplit('/').next().unwrap_or(location);
+        return Ok(Some(Artifact {
+            path: store::blob_path(&hash),
+            name: file_name(name.split('?').next().unwrap_or(name))?,
+            _pin: Some(pin),
+        }));
+    }
+
+    if !extract::is_contained(Path::new(""), Path::new(location)) {
+        anyhow::bail!("Artifact path {:?} escapes the release", location);
+    }
+    Ok(Some(Artifact {
+        path: release.join(location),
+        name: file_name(location)?,
+        _pin: None,
+    }))
+}
+
+// scheme, host and port all match the forge's: a prefix check would let
+// https://forge.example.com.evil.net/ have the token
+fn same_origin(location: &str, forge: &str) -> bool {
+    let forge = match forge {
+        "github" => "https://github.com",
+        other => other,
+    };
+    match (reqwest::Url::parse(location), reqwest::Url::parse(forge)) {
+        (Ok(a), Ok(b)) => {
+            a.scheme() == b.scheme()
+                && a.host_str() == b.host_str()
+                && a.port_or_known_default() == b.port_or_known_default()
+        }
+        _ => false,
+    }
+}
+
+fn file_name(path: &str) -> Result<String> {
+    Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow::anyhow!("Invalid artifact name: {:?}", path))
+}
+
+// tarballs are unpacked over the release, anything else is a single binary
+pub fn install(artifact: &Artifact, release: &Path) -> Result<()> {
+    let mut magic = [0u8; 2];
+    let is_gzip = std::fs::File::open(&artifact.path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && magic == [0x1f, 0x8b];
+
+    if is_gzip {
+        let limits = extract::Limits::from_env()?;
+        let gz = GzDecoder::new(std::fs::File::open(&artifact.path)?);
+        let stats = extract::unpack(gz, release, &limits)?;
+        info!(
+            "Unpacked artifact {} ({} entries)",
+            artifact.name, stats.files
+        );
+        return Ok(());
+    }
+
+    let target = release.join(&artifact.name);
+    let _ = std::fs::remove_file(&target);
+    std::fs::copy(&artifact.path, &target)?;
+
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755))?;
+
+    info!("Installed binary {}", artifact.name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_only_go_to_the_forge() {
+        let forge = "https://git.example.com";
+        assert!(same_origin("https://git.example.com/a/b.tar.gz", forge));
+        assert!(same_origin("https://GIT.example.com:443/a", forge));
+        assert!(same_origin("https://github.com/o/r/x", "github"));
+
+        assert!(!same_origin("https://git.example.com.evil.net/a", forge));
+        assert!(!same_origin("https://git.example.com@evil.net/a", forge));
+        assert!(!same_origin("http://git.example.com/a", forge));
+        assert!(!same_origin("https://git.example.com:8443/a", forge));
+        assert!(!same_origin("https://github.com.evil.net/x", "github"));
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::Result;
+use common::{AppConfig, StepReport, StrategyType};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::Report;
+use super::gate::Previous;
+use crate::server::Routes;
+
+// [strategy] type = "bluegreen": the new release starts on a free port next
+// to the running one (PORT and ${PORT} point at it) and has to pass its
+// readiness probe, or accept connections without one, within [health]
+// timeout. Then `current`, the gateway route for [run] domain and state.toml
+// switch to it at once, and the old process gets wait_time (default 10s) to
+// finish its requests before it is stopped. A new release that doesn't get
+// ready is stopped and the old one never notices.
+
+const DEFAULT_TIMEOUT: u64 = 60;
+const DEFAULT_DRAIN: Duration = Duration::from_secs(10);
+
+// there has to be a running process and a domain to switch, otherwise the
+// deploy starts the app as usual
+pub fn applies(app: &Path, config: &AppConfig) -> bool {
+    let bluegreen = config
+        .strategy
+        .as_ref()
+        .is_some_and(|s| s.r#type == StrategyType::Bluegreen);
+    let routed = config
+        .run
+        .as_ref()
+        .is_some_and(|r| r.port.is_some() && r.domain.is_some());
+    let running = common::load_state(app)
+        .ok()
+        .flatten()
+        .is_some_and(|s| s.status == "running" && s.pid.is_some());
+
+    bluegreen && routed && config.web.is_none() && running && crate::supervisor::is_supervised(app)
+}
+
+pub async fn switch(
+    app: &Path,
+    dir: &Path,
+    config: &AppConfig,
+    mut previous: Option<Previous>,
+    routes: Routes,
+    report: &mut Report,
+) -> Result<()> {
+    let drain = config
+        .strategy
+        .as_ref()
+        .and_then(|s| s.wait_time.as_deref())
+        .map(common::parse_duration)
+        .transpose()?
+        .unwrap_or(DEFAULT_DRAIN);
+
+    let next = start_next(app, dir, config, &mut previous, "bluegreen", report).await?;
+    report.steps.push(StepReport {
+        name: "bluegreen".into(),
+        status: "ok".into(),
+        duration_ms: next.started.elapsed().as_millis() as u64,
+        log: String::new(),
+    });
+
+    super::activate(app, dir)?;
+    if let Some(domain) = next.config.run.as_ref().and_then(|r| r.domain.clone()) {
+        routes.write().await.proxy_routes.insert(domain, next.port);
+    }
+
+    let pid = crate::supervisor::promote(app, drain)?;
+    let health_url = next.config.health.as_ref().and_then(|h| h.url.clone());
+    crate::supervisor::update(app, |s| s.health_url = health_url);
+    report.progress(format!(
+        "Blue-green: switched to PID {} on port {}, stopping the old one in {:?}",
+        pid, next.port, drain
+    ));
+    Ok(())
+}
+
+// the new release, running next to the old one
+pub struct Next {
+    pub config: AppConfig,
+    pub env: HashMap<String, String>,
+    pub port: u16,
+    pub old_port: Option<u16>,
+    pub standby: crate::health::Standby,
+    pub started: Instant,
+}
+
+// starts the release on a free port and waits until it is ready; when it
+// doesn't get there it is stopped again and the step fails
+pub async fn start_next(
+    app: &Path,
+    dir: &Path,
+    config: &AppConfig,
+    previous: &mut Option<Previous>,
+    step: &str,
+    report: &mut Report,
+) -> Result<Next> {
+    let old_port = previous.as_ref().and_then(|p| p.state.as_ref()?.port);
+    let base = config.run.as_ref().and_then(|r| r.port).unwrap_or_default();
+    let mut port = crate::database::find_free_port(base)?;
+    if Some(port) == old_port {
+        port = crate::database::find_free_port(port + 1)?;
+    }
+
+    let started = Instant::now();
+    let config = crate::supervisor::load_release(app, dir, Some(port))?;
+    let env = crate::env::resolve(app, dir, &config)?;
+    let pid = crate::supervisor::start_standby(app, dir, port)?;
+    report.progress(format!(
+        "Started PID {} on port {}, next to port {}",
+        pid,
+        port,
+        old_port.unwrap_or_default()
+    ));
+
+    let timeout = config
+        .health
+        .as_ref()
+        .and_then(|h| h.timeout)
+        .unwrap_or(DEFAULT_TIMEOUT);
+    let mut next = Next {
+        standby: crate::health::Standby::new(&config, dir, env.clone()),
+        config,
+        env,
+        port,
+        old_port,
+        started,
+    };
+    let ready = crate::health::wait_ready(&mut next.standby, Duration::from_secs(timeout), || {
+        crate::supervisor::standby_running(app)
+    })
+    .await;
+
+    match ready {
+        Ok(()) => {
+            report.progress(format!("Port {} is ready", port));
+            Ok(next)
+        }
+        Err(why) => Err(fail(app, dir, &next, previous.take(), step, &why, report).await),
+    }
+}
+
+// stops the new release, puts back the state of the old one, which never
+// stopped, and reports `why` as the failed step
+pub async fn fail(
+    app: &Path,
+    dir: &Path,
+    next: &Next,
+    previous: Option<Previous>,
+    step: &str,
+    why: &str,
+    report: &mut Report,
+) -> anyhow::Error {
+    let log = crate::logs::path(app, dir);
+    let tail = super::build::tail(&log);
+    report.steps.push(StepReport {
+        name: step.into(),
+        status: "failed".into(),
+        duration_ms: next.started.elapsed().as_millis() as u64,
+        log: format!("{}\n{}", why, tail.trim_end()),
+    });
+
+    let standby = app.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || crate::supervisor::stop_standby(&standby)).await;
+    // the deploy saved the new release's state
+    if let Some(state) = previous.and_then(|p| p.state)
+        && let Err(e) = common::save_state(app, &state)
+    {
+        return e;
+    }
+    crate::hooks::run_rollback(&next.config, dir, &next.env, &log, why);
+    anyhow::anyhow!(
+        "{} is not healthy on port {}: {}. Still running the previous release on port {}",
+        next.config.app.name,
+        next.port,
+        why,
+        next.old_port.unwrap_or_default()
+    )
+}
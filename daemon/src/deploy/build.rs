@@ -0,0 +1,234 @@
+use anyhow::Result;
+use common::{BuildSection, BuildStep, StepReport};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Runs `[build]` as a list of steps. Each step logs to
+// <app>/logs/<release>/build-<n>-<name>.log, and a failure is recorded in
+// <app>/build.toml so `flare deploy --resume` can continue from that step.
+
+const LOG_TAIL: usize = 4096;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Progress {
+    pub release: PathBuf,
+    pub completed: Vec<String>,
+    // step name -> command it completed with
+    #[serde(default)]
+    pub commands: HashMap<String, String>,
+    pub failed: String,
+}
+
+impl Progress {
+    // a step whose command was changed since has to run again
+    fn done(&self, step: &BuildStep) -> bool {
+        self.completed.contains(&step.name)
+            && self
+                .commands
+                .get(&step.name)
+                .is_none_or(|c| *c == step.command)
+    }
+}
+
+fn progress_path(app: &Path) -> PathBuf {
+    app.join("build.toml")
+}
+
+pub fn load_progress(app: &Path) -> Result<Option<Progress>> {
+    let path = progress_path(app);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(toml::from_str(&std::fs::read_to_string(path)?)?))
+}
+
+pub fn clear_progress(app: &Path) {
+    let _ = std::fs::remove_file(progress_path(app));
+}
+
+pub fn pipeline(build: &BuildSection) -> Result<Vec<BuildStep>> {
+    match (&build.command, &build.steps) {
+        (Some(_), Some(_)) => anyhow::bail!("[build] has both command and steps, pick one"),
+        (Some(cmd), None) => Ok(vec![BuildStep {
+            name: "build".into(),
+            command: cmd.clone(),
+            workdir: None,
+            env: None,
+            timeout: None,
+            when: None,
+        }]),
+        (None, Some(steps)) => Ok(steps.clone()),
+        (None, None) => Ok(Vec::new()),
+    }
+}
+
+pub fn run(
+    app: &Path,
+    release: &Path,
+    build: &BuildSection,
+    env: &HashMap<String, String>,
+    resumed: Option<&Progress>,
+    reports: &mut Vec<StepReport>,
+) -> Result<()> {
+    let steps = pipeline(build)?;
+    let logs = log_dir(app, release);
+    std::fs::create_dir_all(&logs)?;
+
+    let mut completed = Vec::new();
+    let mut commands = HashMap::new();
+    // once a step runs again, the ones after it work on its new output
+    let mut resuming = true;
+
+    for (i, step) in steps.iter().enumerate() {
+        resuming = resuming && resumed.is_some_and(|p| p.done(step));
+        if resuming {
+            info!("Step {}: done in previous attempt", step.name);
+            completed.push(step.name.clone());
+            commands.insert(step.name.clone(), step.command.clone());
+            reports.push(StepReport {
+                name: step.name.clone(),
+                status: "resumed".into(),
+                duration_ms: 0,
+                log: String::new(),
+            });
+            continue;
+        }
+
+        let log = logs.join(format!("build-{}-{}.log", i + 1, sanitize(&step.name)));
+        let report = run_step(step, release, env, &log, &crate::logs::path(app, release))?;
+        let ok = report.status == "ok" || report.status == "skipped";
+        reports.push(report);
+
+        if !ok {
+            let progress = Progress {
+                release: release.to_path_buf(),
+                completed,
+                commands,
+                failed: step.name.clone(),
+            };
+            std::fs::write(progress_path(app), toml::to_string_pretty(&progress)?)?;
+            anyhow::bail!("Build step '{}' failed", step.name);
+        }
+        completed.push(step.name.clone());
+        commands.insert(step.name.clone(), step.command.clone());
+    }
+
+    clear_progress(app);
+    Ok(())
+}
+
+fn run_step(
+    step: &BuildStep,
+    release: &Path,
+    env: &HashMap<String, String>,
+    log: &Path,
+    app_log: &Path,
+) -> Result<StepReport> {
+    let workdir = match &step.workdir {
+        Some(w) if super::extract::is_contained(Path::new(""), Path::new(w)) => release.join(w),
+        Some(w) => anyhow::bail!("Step '{}': workdir {:?} escapes the release", step.name, w),
+        None => release.to_path_buf(),
+    };
+
+    let started = Instant::now();
+
+    if let Some(cond) = &step.when {
+        let pass = shell(cond, &workdir, env, step)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?
+            .success();
+        if !pass {
+            info!("Step {}: skipped ({})", step.name, cond);
+            return Ok(StepReport {
+                name: step.name.clone(),
+                status: "skipped".into(),
+                duration_ms: 0,
+                log: String::new(),
+            });
+        }
+    }
+
+    info!("Step {}: {}", step.name, step.command);
+
+    // the step log keeps the raw output, app.log gets it with timestamps
+    let file = std::fs::File::create(log)?;
+    let source = format!("build:{}", step.name);
+    let mut child = shell(&step.command, &workdir, env, step)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    let pipes = [
+        child
+            .stdout
+            .take()
+            .map(|s| crate::logs::pipe(app_log, &source, s, file.try_clone().ok())),
+        child
+            .stderr
+            .take()
+            .map(|s| crate::logs::pipe(app_log, &source, s, file.try_clone().ok())),
+    ];
+
+    let timeout = step.timeout.map(Duration::from_secs);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break if status.success() { "ok" } else { "failed" };
+        }
+        if timeout.is_some_and(|t| started.elapsed() > t) {
+            warn!("Step {}: timed out", step.name);
+            // the whole group, so nothing keeps the output pipes open
+            unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+            let _ = child.wait();
+            break "timeout";
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    // background processes left by the step may hold the pipes, don't wait on them
+    for rx in pipes.into_iter().flatten() {
+        let _ = rx.recv_timeout(Duration::from_secs(2));
+    }
+
+    Ok(StepReport {
+        name: step.name.clone(),
+        status: status.into(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        log: tail(log),
+    })
+}
+
+fn shell(cmd: &str, dir: &Path, env: &HashMap<String, String>, step: &BuildStep) -> Command {
+    let mut c = Command::new("sh");
+    c.args(["-c", cmd]).current_dir(dir).envs(env);
+    if let Some(env) = &step.env {
+        c.envs(env);
+    }
+    c
+}
+
+pub fn log_dir(app: &Path, release: &Path) -> PathBuf {
+    app.join("logs").join(super::release_id(app, release))
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+pub fn tail(log: &Path) -> String {
+    let data = std::fs::read(log).unwrap_or_default();
+    let start = data.len().saturating_sub(LOG_TAIL);
+    let text = String::from_utf8_lossy(&data[start..]);
+    match (start, text.find('\n')) {
+        // drop the partial first line
+        (s, Some(i)) if s > 0 => text[i + 1..].to_string(),
+        _ => text.to_string(),
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::Result;
+use common::BuildSection;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::info;
+
+// Build outputs listed in `[build] cache` survive between releases under
+// <app>/cache/<key>/, where key is "default" or the hash of `cache_key`.
+// Restoring copies them into the new release and leaves the slot alone, so
+// a failed build (which saves nothing) doesn't cost the next deploy its
+// cache; saving replaces the slot after a successful one. Copies share
+// extents where the filesystem supports it.
+
+fn cache_root(app: &Path) -> PathBuf {
+    app.join("cache")
+}
+
+// computed before the build so restore and save agree on the slot
+pub fn key(release: &Path, build: &BuildSection) -> Result<String> {
+    match &build.cache_key {
+        Some(lockfile) => {
+            let path = release.join(checked(lockfile)?);
+            match std::fs::read(&path) {
+                Ok(data) => Ok(common::sha256_hex(&data)[..16].to_string()),
+                // no lockfile yet, nothing to key on
+                Err(_) => Ok("default".into()),
+            }
+        }
+        None => Ok("default".into()),
+    }
+}
+
+fn checked(path: &str) -> Result<&Path> {
+    let p = Path::new(path);
+    if p.as_os_str().is_empty() || !super::extract::is_contained(Path::new(""), p) {
+        anyhow::bail!("Invalid cache path: {:?}", path);
+    }
+    Ok(p)
+}
+
+pub fn restore(app: &Path, release: &Path, build: &BuildSection, key: &str) -> Result<()> {
+    let dirs = match &build.cache {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+
+    let slot = cache_root(app).join(key);
+    if !slot.exists() {
+        info!("Build cache miss");
+        return Ok(());
+    }
+
+    for dir in dirs {
+        let rel = checked(dir)?;
+        let cached = slot.join(rel);
+        let target = release.join(rel);
+        if !cached.exists() || target.exists() {
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        copy(&cached, &target)?;
+        info!("Restored {} from build cache", dir);
+    }
+
+    Ok(())
+}
+
+pub fn save(app: &Path, release: &Path, build: &BuildSection, key: &str) -> Result<()> {
+    let dirs = match &build.cache {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+
+    let root = cache_root(app);
+    let slot = root.join(key);
+    let tmp = root.join(format!("{}.tmp", key));
+
+    let _ = std::fs::remove_dir_all(&tmp);
+    std::fs::create_dir_all(&tmp)?;
+
+    for dir in dirs {
+        let rel = checked(dir)?;
+        let source = release.join(rel);
+        if !source.exists() {
+            continue;
+        }
+
+        let target = tmp.join(rel);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        copy(&source, &target)?;
+    }
+
+    let _ = std::fs::remove_dir_all(&slot);
+    std::fs::rename(&tmp, &slot)?;
+
+    // a new lockfile hash makes every older slot stale
+    for entry in std::fs::read_dir(&root)?.filter_map(|e| e.ok()) {
+        if entry.file_name() != key {
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+
+    info!("Saved build cache ({})", key);
+    Ok(())
+}
+
+// shares extents on btrfs and XFS; busybox cp has no --reflink
+fn copy(from: &Path, to: &Path) -> Result<()> {
+    for args in [&["-a", "--reflink=auto"][..], &["-a"]] {
+        let status = Command::new("cp")
+            .args(args)
+            .arg(from)
+            .arg(to)
+            .stderr(Stdio::null())
+            .status()?;
+        if status.success() {
+            return Ok(());
+        }
+        let _ = std::fs::remove_dir_all(to);
+    }
+    anyhow::bail!("Copy {:?} -> {:?} failed", from, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flare-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_failed_build_keeps_the_cache_for_the_next_deploy() {
+        let root = scratch("failed");
+        let app = root.join("app");
+        let build = BuildSection {
+            cache: Some(vec!["node_modules".into()]),
+            ..Default::default()
+        };
+
+        let first = root.join("v1");
+        std::fs::create_dir_all(first.join("node_modules")).unwrap();
+        std::fs::write(first.join("node_modules/dep.js"), "cached").unwrap();
+        save(&app, &first, &build, "default").unwrap();
+
+        // restored, then the build fails and nothing is saved
+        let failed = root.join("v2");
+        std::fs::create_dir_all(&failed).unwrap();
+        restore(&app, &failed, &build, "default").unwrap();
+        assert!(failed.join("node_modules/dep.js").exists());
+
+        let fixed = root.join("v3");
+        std::fs::create_dir_all(&fixed).unwrap();
+        restore(&app, &fixed, &build, "default").unwrap();
+        let dep = std::fs::read_to_string(fixed.join("node_modules/dep.js")).unwrap();
+        assert_eq!(dep, "cached");
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
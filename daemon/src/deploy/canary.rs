@@ -0,0 +1,187 @@
+use anyhow::Result;
+use common::{AppConfig, StepReport, StrategyType};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::Report;
+use super::bluegreen::{self, Next};
+use super::gate::Previous;
+use crate::gateway::{Split, Stats};
+use crate::server::Routes;
+
+// [strategy] type = "canary": the new release starts next to the running one
+// like a blue-green standby. Once it is ready the gateway sends `percent`
+// (default 10) of the requests for [run] domain to it, for wait_time (default
+// 60s). It is rolled back as soon as it stops passing its readiness probe,
+// exits, or answers more than max_error_rate percent (default 5) of its
+// requests with a 5xx while doing worse than the old release; after 10
+// requests, or at the end of wait_time with fewer. Otherwise it gets all the
+// traffic and the old process is stopped after a short drain.
+
+const DEFAULT_PERCENT: u8 = 10;
+const DEFAULT_WAIT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_ERROR_RATE: u8 = 5;
+const MIN_REQUESTS: u64 = 10;
+const DRAIN: Duration = Duration::from_secs(10);
+const TICK: Duration = Duration::from_secs(1);
+const REPORT_EVERY: Duration = Duration::from_secs(10);
+
+// without a domain there is no traffic to split
+pub fn applies(app: &Path, config: &AppConfig) -> bool {
+    let canary = config
+        .strategy
+        .as_ref()
+        .is_some_and(|s| s.r#type == StrategyType::Canary);
+    let routed = config
+        .run
+        .as_ref()
+        .is_some_and(|r| r.port.is_some() && r.domain.is_some());
+    let running = common::load_state(app)
+        .ok()
+        .flatten()
+        .is_some_and(|s| s.status == "running" && s.pid.is_some());
+
+    canary && routed && config.web.is_none() && running && crate::supervisor::is_supervised(app)
+}
+
+pub async fn run(
+    app: &Path,
+    dir: &Path,
+    config: &AppConfig,
+    mut previous: Option<Previous>,
+    routes: Routes,
+    report: &mut Report,
+) -> Result<()> {
+    let strategy = config.strategy.as_ref();
+    let percent = strategy.and_then(|s| s.percent).unwrap_or(DEFAULT_PERCENT);
+    let max_error_rate = strategy
+        .and_then(|s| s.max_error_rate)
+        .unwrap_or(DEFAULT_MAX_ERROR_RATE);
+    let wait = strategy
+        .and_then(|s| s.wait_time.as_deref())
+        .map(common::parse_duration)
+        .transpose()?
+        .unwrap_or(DEFAULT_WAIT);
+    let domain = config
+        .run
+        .as_ref()
+        .and_then(|r| r.domain.clone())
+        .unwrap_or_default();
+
+    let mut next = bluegreen::start_next(app, dir, config, &mut previous, "canary", report).await?;
+
+    let split = Split::new(next.port, percent);
+    routes
+        .write()
+        .await
+        .canaries
+        .insert(domain.clone(), split.clone());
+    report.progress(format!(
+        "Canary: {}% of {} to port {} for {:?}",
+        percent, domain, next.port, wait
+    ));
+
+    let verdict = observe(app, &mut next, &split, max_error_rate, wait, report).await;
+    let summary = format!(
+        "canary {}, old release {}",
+        rate(&split.canary),
+        rate(&split.stable)
+    );
+
+    if let Err(why) = verdict {
+        routes.write().await.canaries.remove(&domain);
+        report.progress(format!("Canary: rolling back, {}", why));
+        let why = format!("{} ({})", why, summary);
+        return Err(bluegreen::fail(app, dir, &next, previous, "canary", &why, report).await);
+    }
+
+    report.steps.push(StepReport {
+        name: "canary".into(),
+        status: "ok".into(),
+        duration_ms: next.started.elapsed().as_millis() as u64,
+        log: summary,
+    });
+
+    super::activate(app, dir)?;
+    {
+        let mut routes = routes.write().await;
+        routes.canaries.remove(&domain);
+        routes.proxy_routes.insert(domain, next.port);
+    }
+    let pid = crate::supervisor::promote(app, DRAIN)?;
+    let health_url = next.config.health.as_ref().and_then(|h| h.url.clone());
+    crate::supervisor::update(app, |s| s.health_url = health_url);
+    report.progress(format!(
+        "Canary: promoted PID {} on port {} to 100%, stopping the old one in {:?}",
+        pid, next.port, DRAIN
+    ));
+    Ok(())
+}
+
+// Err with the reason to roll back
+async fn observe(
+    app: &Path,
+    next: &mut Next,
+    split: &Split,
+    max_error_rate: u8,
+    wait: Duration,
+    report: &Report,
+) -> Result<(), String> {
+    let started = Instant::now();
+    let mut reported = started;
+
+    loop {
+        if !crate::supervisor::standby_running(app) {
+            return Err("the new process exited".into());
+        }
+        if next.standby.poll().await == Some(false) {
+            let why = next.standby.error().unwrap_or("failing");
+            return Err(format!("readiness probe failing: {}", why));
+        }
+
+        let done = started.elapsed() >= wait;
+        let (requests, errors) = split.canary.get();
+        if (requests >= MIN_REQUESTS || done) && failing(split, max_error_rate) {
+            return Err(format!(
+                "{} of {} requests failed, more than {}%",
+                errors, requests, max_error_rate
+            ));
+        }
+        if done {
+            return Ok(());
+        }
+
+        if reported.elapsed() >= REPORT_EVERY {
+            reported = Instant::now();
+            report.progress(format!(
+                "Canary: {}s of {}s, canary {}, old release {}",
+                started.elapsed().as_secs(),
+                wait.as_secs(),
+                rate(&split.canary),
+                rate(&split.stable)
+            ));
+        }
+        tokio::time::sleep(TICK).await;
+    }
+}
+
+// over the limit, and worse than what the old release does anyway
+fn failing(split: &Split, max_error_rate: u8) -> bool {
+    let (requests, errors) = split.canary.get();
+    if requests == 0 {
+        return false;
+    }
+    let canary = errors as f64 * 100.0 / requests as f64;
+    let (old_requests, old_errors) = split.stable.get();
+    let old = match old_requests {
+        0 => 0.0,
+        n => old_errors as f64 * 100.0 / n as f64,
+    };
+    canary > max_error_rate as f64 && canary > old
+}
+
+fn rate(stats: &Stats) -> String {
+    let (requests, errors) = stats.get();
+    format!("{} requests, {} errors", requests, errors)
+}
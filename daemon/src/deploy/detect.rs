@@ -0,0 +1,91 @@
+use anyhow::Result;
+use common::StepReport;
+use common::detect::DETECTED;
+use std::path::Path;
+use tracing::info;
+
+// Zero-config deploys: when the app root has no flare.toml, or one without
+// [build]/[run], the missing sections come from common::detect. They go to
+// flare.detected.toml in the release, which vars.rs reads under flare.toml;
+// the user's own file (and its comments) stays as it was.
+
+pub fn fill(app: &Path, dir: &Path) -> Result<Option<StepReport>> {
+    // a resumed release may have one from before flare.toml changed
+    let _ = std::fs::remove_file(dir.join(DETECTED));
+
+    let path = dir.join("flare.toml");
+    let (config, missing) = match std::fs::read_to_string(&path) {
+        // broken files are left to the checks that follow
+        Ok(text) => match toml::from_str::<toml::Value>(&text) {
+            Ok(v) => (v, false),
+            Err(_) => return Ok(None),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            (toml::Value::Table(Default::default()), true)
+        }
+        Err(e) => anyhow::bail!("Can't read {:?}: {}", path, e),
+    };
+
+    let table = match config.as_table() {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    // static sites and monorepo roots don't run anything
+    if table.contains_key("web") || table.contains_key("apps") {
+        return Ok(None);
+    }
+    let has_build = table.contains_key("build") || table.contains_key("artifact");
+    let has_run = table.contains_key("run");
+    if has_build && has_run {
+        return Ok(None);
+    }
+
+    let detection = match common::detect::detect(dir) {
+        Some(d) => d,
+        None if missing => anyhow::bail!(
+            "No flare.toml and the project type couldn't be detected (run `flare init` to write one)"
+        ),
+        None => return Ok(None),
+    };
+
+    let mut detected = toml::Table::try_from(&detection.config)?;
+    if table.contains_key("app") {
+        detected.remove("app");
+    } else if let Some(name) = app.file_name() {
+        // the release directory name is a timestamp, name it like the app
+        let mut section = toml::Table::new();
+        section.insert("name".into(), name.to_string_lossy().to_string().into());
+        section.insert("version".into(), "0.1.0".into());
+        detected.insert("app".into(), toml::Value::Table(section));
+    }
+    if has_build {
+        detected.remove("build");
+    }
+    if has_run {
+        // a [run] of our own would clash with the user's, and so would [web]
+        detected.remove("run");
+        detected.remove("health");
+        detected.remove("web");
+    } else if has_build {
+        detected.remove("web");
+    }
+    detected.retain(|k, _| !table.contains_key(k) || k == "app");
+    if !detected.keys().any(|k| k != "app") {
+        return Ok(None);
+    }
+
+    let synthesized = toml::to_string_pretty(&detected)?;
+    let header = format!("# Detected {} by flared\n\n", detection.stack);
+    std::fs::write(dir.join(DETECTED), format!("{}{}", header, synthesized))?;
+
+    info!(
+        "Detected {}, config written to {}",
+        detection.stack, DETECTED
+    );
+    Ok(Some(StepReport {
+        name: format!("detect: {}", detection.stack),
+        status: "detected".into(),
+        duration_ms: 0,
+        log: synthesized,
+    }))
+}
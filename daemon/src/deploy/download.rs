@@ -0,0 +1,372 @@
+use anyhow::Result;
+use common::DeployRequest;
+use reqwest::StatusCode;
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Downloads land in ~/.flare/cache/downloads/<sha256(url@ref)>.part and are
+// resumed with HTTP Range requests, so a dropped LTE link doesn't throw away
+// what was already transferred. A link that stalls without closing the
+// connection counts as dropped once nothing arrived for FLARE_DOWNLOAD_TIMEOUT.
+
+const DEFAULT_RETRIES: u32 = 5;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BACKOFF_SECS: u64 = 60;
+
+struct Settings {
+    retries: u32,
+    timeout: Duration, // connecting, and between two chunks
+    limit: Option<u64>,
+}
+
+enum Failure {
+    Fatal(anyhow::Error),
+    Retry(anyhow::Error),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for Failure {
+    fn from(e: E) -> Self {
+        Failure::Retry(e.into())
+    }
+}
+
+pub async fn fetch(req: &DeployRequest) -> Result<Vec<u8>> {
+    let git_ref = req.git_ref.as_deref().unwrap_or("main");
+    let url = archive_url(req, git_ref);
+    fetch_url(&url, git_ref, req, true).await
+}
+
+// `req` supplies the bandwidth limit, and forge credentials when `auth` is set
+pub async fn fetch_url(
+    url: &str,
+    git_ref: &str,
+    req: &DeployRequest,
+    auth: bool,
+) -> Result<Vec<u8>> {
+    let limit = req
+        .max_bandwidth
+        .as_deref()
+        .map(common::parse_size)
+        .transpose()?
+        .filter(|&bps| bps > 0);
+    let retries = match std::env::var("FLARE_DOWNLOAD_RETRIES") {
+        Ok(v) => v
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid FLARE_DOWNLOAD_RETRIES: {}", v))?,
+        Err(_) => DEFAULT_RETRIES,
+    };
+    let timeout = match std::env::var("FLARE_DOWNLOAD_TIMEOUT") {
+        Ok(v) => common::parse_duration(&v)
+            .ok()
+            .filter(|t| !t.is_zero())
+            .ok_or_else(|| anyhow::anyhow!("Invalid FLARE_DOWNLOAD_TIMEOUT: {}", v))?,
+        Err(_) => DEFAULT_TIMEOUT,
+    };
+
+    let cache = cache_dir();
+    std::fs::create_dir_all(&cache)?;
+    let key = common::sha256_hex(format!("{}@{}", url, git_ref).as_bytes());
+    let part = cache.join(format!("{}.part", key));
+    let etag = cache.join(format!("{}.etag", key));
+
+    // deploys of the same repo and ref would write the same .part
+    let _lock = lock(cache.join(format!("{}.lock", key)), url).await?;
+
+    info!("Downloading {}", url);
+    if let Some(bps) = limit {
+        info!("Bandwidth limited to {} bytes/s", bps);
+    }
+
+    let settings = Settings {
+        retries,
+        timeout,
+        limit,
+    };
+    transfer(url, auth.then_some(req), &part, &etag, &settings).await?;
+
+    let data = std::fs::read(&part)?;
+    discard(&part, &etag);
+    Ok(data)
+}
+
+// fills `part`, retrying with backoff and resuming where the last try stopped
+async fn transfer(
+    url: &str,
+    auth: Option<&DeployRequest>,
+    part: &Path,
+    etag: &Path,
+    settings: &Settings,
+) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(settings.timeout)
+        .build()?;
+    let retries = settings.retries;
+    let mut attempt = 0;
+
+    loop {
+        match try_fetch(&client, url, auth, part, etag, settings).await {
+            Ok(()) => break,
+            Err(Failure::Fatal(e)) => {
+                discard(part, etag);
+                return Err(e);
+            }
+            Err(Failure::Retry(e)) if attempt < retries => {
+                attempt += 1;
+                let delay = (1u64 << attempt.min(6)).min(MAX_BACKOFF_SECS);
+                warn!(
+                    "Download interrupted ({}), retry {}/{} in {}s",
+                    e, attempt, retries, delay
+                );
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+            }
+            Err(Failure::Retry(e)) => {
+                anyhow::bail!("Download failed after {} retries: {}", retries, e)
+            }
+        }
+    }
+    Ok(())
+}
+
+// held until the download is read, then released by dropping the file
+async fn lock(path: PathBuf, url: &str) -> Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    let fd = file.as_raw_fd();
+    if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(file);
+    }
+
+    info!("Waiting for another deploy downloading {}", url);
+    tokio::task::spawn_blocking(move || {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(file)
+    })
+    .await?
+}
+
+fn archive_url(req: &DeployRequest, git_ref: &str) -> String {
+    if req.forge == "github" {
+        format!(
+            "https://api.github.com/repos/{}/tarball/{}",
+            req.repo, git_ref
+        )
+    } else if req.git_ref.is_some() {
+        format!("{}/git/{}/archive?ref={}", req.forge, req.repo, git_ref)
+    } else {
+        format!("{}/git/{}/archive", req.forge, req.repo)
+    }
+}
+
+fn cache_dir() -> PathBuf {
+    common::flare_dir().join("cache").join("downloads")
+}
+
+fn discard(part: &Path, etag: &Path) {
+    let _ = std::fs::remove_file(part);
+    let _ = std::fs::remove_file(etag);
+}
+
+async fn try_fetch(
+    client: &reqwest::Client,
+    url: &str,
+    auth: Option<&DeployRequest>,
+    part: &Path,
+    etag: &Path,
+    settings: &Settings,
+) -> Result<(), Failure> {
+    // only resume when we can prove the remote file hasn't changed
+    let validator = std::fs::read_to_string(etag).ok();
+    let offset = match validator {
+        Some(_) => std::fs::metadata(part).map(|m| m.len()).unwrap_or(0),
+        None => 0,
+    };
+
+    let mut r = client.get(url).header("User-Agent", "Flared");
+
+    if let Some(req) = auth
+        && let Some(pass) = &req.auth_password
+    {
+        if req.forge == "github" {
+            r = r.bearer_auth(pass);
+        } else if let Some(user) = &req.auth_user {
+            r = r.basic_auth(user, Some(pass));
+        }
+    }
+
+    if offset > 0
+        && let Some(tag) = &validator
+    {
+        info!("Resuming at byte {}", offset);
+        r = r
+            .header(RANGE, format!("bytes={}-", offset))
+            .header(IF_RANGE, tag.as_str());
+    }
+
+    let mut resp = idle(settings.timeout, r.send()).await?;
+    let status = resp.status();
+
+    let append = match status {
+        StatusCode::PARTIAL_CONTENT => {
+            if range_start(&resp) != Some(offset) {
+                discard(part, etag);
+                return Err(Failure::Retry(anyhow::anyhow!("unexpected Content-Range")));
+            }
+            true
+        }
+        StatusCode::OK => {
+            match resp.headers().get(ETAG).and_then(|v| v.to_str().ok()) {
+                Some(tag) => std::fs::write(etag, tag)?,
+                None => {
+                    let _ = std::fs::remove_file(etag);
+                }
+            }
+            false
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            discard(part, etag);
+            return Err(Failure::Retry(anyhow::anyhow!("stale partial download")));
+        }
+        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+            return Err(Failure::Retry(anyhow::anyhow!("HTTP {}", status)));
+        }
+        s if s.is_client_error() => {
+            return Err(Failure::Fatal(anyhow::anyhow!("HTTP {}", s)));
+        }
+        s => return Err(Failure::Retry(anyhow::anyhow!("HTTP {}", s))),
+    };
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(part)?;
+
+    let started = Instant::now();
+    let mut received: u64 = 0;
+
+    while let Some(chunk) = idle(settings.timeout, resp.chunk()).await? {
+        file.write_all(&chunk)?;
+        received += chunk.len() as u64;
+
+        if let Some(bps) = settings.limit {
+            let expected = Duration::from_secs_f64(received as f64 / bps as f64);
+            let elapsed = started.elapsed();
+            if expected > elapsed {
+                tokio::time::sleep(expected - elapsed).await;
+            }
+        }
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+// the connection stays open on a stalled link, nothing ever errors
+async fn idle<T>(
+    timeout: Duration,
+    f: impl std::future::Future<Output = reqwest::Result<T>>,
+) -> Result<T, Failure> {
+    match tokio::time::timeout(timeout, f).await {
+        Ok(r) => Ok(r?),
+        Err(_) => Err(Failure::Retry(anyhow::anyhow!(
+            "no data for {}s",
+            timeout.as_secs_f64()
+        ))),
+    }
+}
+
+// "bytes <start>-<end>/<total>"
+fn range_start(resp: &reqwest::Response) -> Option<u64> {
+    resp.headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes "))
+        .and_then(|v| v.split('-').next())
+        .and_then(|v| v.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("flare-download-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut buf = Vec::new();
+        let mut byte = [0u8];
+        while !buf.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+            buf.push(byte[0]);
+        }
+        String::from_utf8_lossy(&buf).to_lowercase()
+    }
+
+    #[tokio::test]
+    async fn retries_a_stalled_transfer_from_where_it_stopped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/archive", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            // half the body, then nothing while the connection stays open
+            let (mut first, _) = listener.accept().unwrap();
+            read_request(&mut first);
+            let head = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nETag: \"v1\"\r\n\r\n";
+            first
+                .write_all(format!("{}hello", head).as_bytes())
+                .unwrap();
+
+            let (mut second, _) = listener.accept().unwrap();
+            let request = read_request(&mut second);
+            let head = "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\nContent-Length: 5\r\n\r\n";
+            second
+                .write_all(format!("{}world", head).as_bytes())
+                .unwrap();
+            drop(first);
+            request
+        });
+
+        let dir = scratch("stall");
+        let (part, etag) = (dir.join("a.part"), dir.join("a.etag"));
+        let settings = Settings {
+            retries: 1,
+            timeout: Duration::from_millis(300),
+            limit: None,
+        };
+        transfer(&url, None, &part, &etag, &settings).await.unwrap();
+
+        assert!(server.join().unwrap().contains("range: bytes=5-"));
+        assert_eq!(std::fs::read(&part).unwrap(), b"helloworld");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_second_download_of_the_same_key_waits() {
+        let dir = scratch("lock");
+        let path = dir.join("a.lock");
+        let first = lock(path.clone(), "x").await.unwrap();
+
+        let waiting = tokio::spawn(lock(path, "x"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::Result;
+use common::{AppConfig, AppState, StepReport};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::server::Routes;
+
+// A deploy with [health] only succeeds once the new release passes its
+// readiness probe (or liveness, without one) within [health] timeout. The
+// result comes from the health monitor through state.toml. Otherwise the
+// release is stopped and the one that was active before takes over again.
+
+const DEFAULT_TIMEOUT: u64 = 60;
+const POLL: Duration = Duration::from_millis(500);
+
+// what was active before the deploy
+pub struct Previous {
+    pub dir: PathBuf,
+    pub state: Option<AppState>,
+}
+
+impl Previous {
+    pub fn load(app: &Path) -> Option<Previous> {
+        let dir = std::fs::read_link(app.join("current")).ok()?;
+        let state = common::load_state(app).ok().flatten();
+        Some(Previous { dir, state })
+    }
+}
+
+// None when there is nothing to wait for
+pub async fn wait(app: &Path, config: &AppConfig) -> Option<StepReport> {
+    let health = config.health.as_ref()?;
+    config.run.as_ref()?;
+    let readiness = health.readiness.is_some() || health.url.is_some();
+    if !readiness && health.liveness.is_none() {
+        return None;
+    }
+
+    let timeout = health.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let started = Instant::now();
+    info!(
+        "Waiting up to {}s for {} to be healthy",
+        timeout, config.app.name
+    );
+
+    let result = loop {
+        let state = common::load_state(app).ok().flatten();
+        if let Some(s) = &state {
+            if matches!(
+                s.status.as_str(),
+                "crashed" | "failed" | "exited" | "stopped"
+            ) {
+                break Err(match s.exit_code {
+                    Some(code) => format!("app {} with exit code {}", s.status, code),
+                    None => format!("app {}", s.status),
+                });
+            }
+            let h = s.health.as_ref();
+            let passed = if readiness {
+                h.and_then(|h| h.ready)
+            } else {
+                h.and_then(|h| h.live)
+            };
+            if passed == Some(true) {
+                break Ok(());
+            }
+        }
+
+        if started.elapsed() >= Duration::from_secs(timeout) {
+            let probe = if readiness { "readiness" } else { "liveness" };
+            let why = state
+                .and_then(|s| s.health)
+                .and_then(|h| h.message)
+                .unwrap_or_else(|| "no result yet".into());
+            break Err(format!(
+                "{} probe not passing after {}s: {}",
+                probe, timeout, why
+            ));
+        }
+        tokio::time::sleep(POLL).await;
+    };
+
+    let (status, log) = match result {
+        Ok(()) => ("ok", String::new()),
+        Err(why) => {
+            // the app's last words explain more than the probe
+            let release = std::fs::read_link(app.join("current")).unwrap_or_default();
+            let tail = super::build::tail(&crate::logs::path(app, &release));
+            ("failed", format!("{}\n{}", why, tail.trim_end()))
+        }
+    };
+    Some(StepReport {
+        name: "health".into(),
+        status: status.into(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        log,
+    })
+}
+
+// stops the new release and brings back the previous one the way it was:
+// same state, started again if it was running
+pub async fn rollback(app: &Path, previous: Option<Previous>, routes: Routes) -> Result<String> {
+    let dir = app.to_path_buf();
+    tokio::task::spawn_blocking(move || crate::supervisor::stop(&dir)).await?;
+
+    let previous = match previous {
+        Some(p) => p,
+        None => {
+            crate::supervisor::update(app, |s| {
+                s.status = "failed".into();
+                s.pid = None;
+                s.health = None;
+            });
+            return Ok("Stopped, no previous release to roll back to".into());
+        }
+    };
+
+    super::activate(app, &previous.dir)?;
+    let release = super::release_id(app, &previous.dir);
+
+    let mut state = match previous.state {
+        Some(s) => s,
+        None => return Ok(format!("Rolled back to release {}", release)),
+    };
+    let was_running = matches!(state.status.as_str(), "running" | "restarting");
+    state.pid = None;
+    state.health = None;
+    state.restarts = 0;
+    if !was_running {
+        common::save_state(app, &state)?;
+        return Ok(format!("Rolled back to release {} (not started)", release));
+    }
+
+    state.status = "running".into();
+    common::save_state(app, &state)?;
+    let (config, dir) = crate::supervisor::load(app)?;
+    super::start(&config, app, &dir, routes).await?;
+
+    warn!("{:?}: rolled back to release {}", app, release);
+    Ok(format!("Rolled back to release {}", release))
+}
@@ -0,0 +1,519 @@
+use anyhow::Result;
+use common::{AppConfig, AppState, DeployRequest, IsolationType, StepReport};
+use common::{app_dir, save_state};
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::info;
+
+use crate::server::Routes;
+
+pub mod artifact;
+pub mod bluegreen;
+pub mod build;
+pub mod cache;
+pub mod canary;
+pub mod detect;
+pub mod download;
+pub mod gate;
+pub mod monorepo;
+pub mod store;
+pub mod vars;
+
+pub use common::extract;
+
+#[derive(Default)]
+pub struct Report {
+    pub steps: Vec<StepReport>,
+    // lines for the deploying CLI, when it asked for them
+    pub progress: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+}
+
+impl Report {
+    pub fn progress(&self, line: String) {
+        info!("{}", line);
+        if let Some(tx) = &self.progress {
+            let _ = tx.send(line);
+        }
+    }
+}
+
+// builds, hooks, extraction and release copies take as long as they take;
+// off the runtime the gateway, probes and log streams keep going meanwhile
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+pub async fn run(req: &DeployRequest, routes: Routes, report: &mut Report) -> Result<Vec<PathBuf>> {
+    if req.resume {
+        let path = monorepo::normalize(req.path.as_deref().unwrap_or(""))?;
+        let name = monorepo::app_name(&req.repo, &path);
+        let app = app_dir(&name);
+        monorepo::claim(&app, &name)?;
+
+        let p = build::load_progress(&app)?
+            .ok_or_else(|| anyhow::anyhow!("No failed build to resume for {}", name))?;
+        if !p.release.exists() {
+            anyhow::bail!("Release {:?} no longer exists", p.release);
+        }
+        info!("Resuming {:?} at step {}", p.release, p.failed);
+
+        // the fixed sources and flare.toml go over the half-built tree
+        let (source, _pin) = source(req).await?;
+        let id = release_id(&app, &p.release);
+        let old = std::fs::read(store::manifest_path(&app, &id))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        let (dest, a) = (app.join("versions").join(&id), app.clone());
+        blocking(move || {
+            store::update(&old, &source, &dest)?;
+            record(&a, &id, &source)
+        })
+        .await?;
+
+        let root = p.release.clone();
+        return Ok(vec![
+            deploy_app(req, &app, &root, Some(p), routes, report).await?,
+        ]);
+    }
+
+    let (source, _pin) = source(req).await?;
+    let source = Arc::new(source);
+
+    let paths = monorepo::targets(req.path.as_deref(), &source)?;
+    if paths.len() > 1 && req.artifact.is_some() {
+        anyhow::bail!("An uploaded artifact needs --path to pick one app");
+    }
+
+    let mut deployed = Vec::new();
+    let mut failed = Vec::new();
+
+    for path in &paths {
+        let name = monorepo::app_name(&req.repo, path);
+        let app = app_dir(&name);
+        std::fs::create_dir_all(&app)?;
+        build::clear_progress(&app);
+
+        let first_step = report.steps.len();
+        let (a, s, n) = (app.clone(), source.clone(), name.clone());
+        let result = match blocking(move || {
+            monorepo::claim(&a, &n)?;
+            new_release(&a, &s)
+        })
+        .await
+        {
+            Ok(release) => {
+                deploy_app(req, &app, &release.join(path), None, routes.clone(), report).await
+            }
+            Err(e) => Err(e),
+        };
+
+        if paths.len() > 1 {
+            for step in &mut report.steps[first_step..] {
+                step.name = format!("{}:{}", path, step.name);
+            }
+        }
+
+        match result {
+            Ok(dir) => deployed.push(dir),
+            Err(e) if paths.len() == 1 => return Err(e),
+            Err(e) => {
+                tracing::warn!("Deploy of {} failed: {}", name, e);
+                failed.push(format!("{}: {}", name, e));
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} of {} apps failed: {}",
+            failed.len(),
+            paths.len(),
+            failed.join("; ")
+        );
+    }
+    Ok(deployed)
+}
+
+// `dir` is the app root inside the release: the release itself, or a
+// subdirectory of it for apps from a monorepo
+async fn deploy_app(
+    req: &DeployRequest,
+    app: &Path,
+    dir: &Path,
+    progress: Option<build::Progress>,
+    routes: Routes,
+    report: &mut Report,
+) -> Result<PathBuf> {
+    let log = crate::logs::path(app, dir);
+    let (a, d, l) = (app.to_path_buf(), dir.to_path_buf(), log.clone());
+    let (profile, tags) = (req.profile.clone(), req.tags.clone());
+    let (detected, config, env, db) = blocking(move || {
+        // no flare.toml, or no [build]/[run]: fill in what the project looks like
+        let detected = detect::fill(&a, &d)?;
+
+        // same checks as `flare check`, before anything runs
+        for warning in common::check::validate(&d)? {
+            tracing::warn!("flare.toml:{}", warning);
+        }
+
+        // the database comes first so its actual port can be interpolated
+        let profile = profile.as_deref();
+        let db = match vars::database(&a, &d, profile, &tags)? {
+            Some(section) => Some(crate::database::setup(&section, &d)?),
+            None => None,
+        };
+        let config = vars::load(&a, &d, profile, &tags, db.as_ref(), None)?;
+        let env = crate::env::resolve(&a, &d, &config)?;
+
+        crate::hooks::run_pre(&config, &d, &env, &l);
+        Ok((detected, Arc::new(config), env, db))
+    })
+    .await?;
+    report.steps.extend(detected);
+
+    let artifact = artifact::resolve(req, &config, dir).await?;
+
+    // steps of a failed build are reported too
+    let (a, d, c, e) = (
+        app.to_path_buf(),
+        dir.to_path_buf(),
+        config.clone(),
+        env.clone(),
+    );
+    let (steps, built) = blocking(move || {
+        let mut steps = Vec::new();
+        let built = install_or_build(&a, &d, &c, &e, artifact, progress, &mut steps);
+        Ok((steps, built))
+    })
+    .await?;
+    report.steps.extend(steps);
+    built?;
+
+    let previous = gate::Previous::load(app);
+    let bluegreen = bluegreen::applies(app, &config);
+    let canary = canary::applies(app, &config);
+    if !bluegreen && !canary {
+        activate(app, dir)?;
+    }
+
+    // saved first, the supervisor fills in the PID and follows the process
+    let mut state = AppState {
+        name: config.app.name.clone(),
+        version: config.app.version.clone(),
+        status: "running".into(),
+        pid: None,
+        port: config.run.as_ref().and_then(|r| r.port),
+        health_url: config.health.as_ref().and_then(|h| h.url.clone()),
+        isolation: config.isolation.as_ref().map(|i| i.r#type),
+        db_port: db.as_ref().and_then(|d| d.port),
+        db_url: db.map(|d| d.url),
+        profile: req.profile.clone(),
+        tags: req.tags.clone(),
+        exit_code: None,
+        restarts: 0,
+        health: None,
+    };
+
+    if bluegreen || canary {
+        // the old process is the one recorded until the switch
+        if let Some(old) = previous.as_ref().and_then(|p| p.state.as_ref()) {
+            state.pid = old.pid;
+            state.port = old.port;
+            state.health = old.health.clone();
+        }
+        save_state(app, &state)?;
+        if canary {
+            canary::run(app, dir, &config, previous, routes, report).await?;
+        } else {
+            bluegreen::switch(app, dir, &config, previous, routes, report).await?;
+        }
+    } else {
+        save_state(app, &state)?;
+        start(&config, app, dir, routes.clone()).await?;
+
+        if let Some(step) = gate::wait(app, &config).await {
+            let failed = step.status == "failed";
+            let why = step.log.lines().next().unwrap_or_default().to_string();
+            report.steps.push(step);
+
+            if failed {
+                let outcome = match gate::rollback(app, previous, routes).await {
+                    Ok(m) => m,
+                    Err(e) => format!("Rollback failed: {}", e),
+                };
+                let (c, d, e, l, w) = (config.clone(), dir.to_path_buf(), env, log, why.clone());
+                blocking(move || {
+                    crate::hooks::run_rollback(&c, &d, &e, &l, &w);
+                    Ok(())
+                })
+                .await?;
+                anyhow::bail!("{} is not healthy: {}. {}", config.app.name, why, outcome);
+            }
+        }
+    }
+
+    let (a, d) = (app.to_path_buf(), dir.to_path_buf());
+    blocking(move || {
+        crate::hooks::run_post(&config, &d, &env, &log);
+        if let Err(e) = prune(&a) {
+            tracing::warn!("Prune failed: {}", e);
+        }
+        Ok(())
+    })
+    .await?;
+
+    Ok(dir.to_path_buf())
+}
+
+// a prebuilt artifact replaces [build]
+fn install_or_build(
+    app: &Path,
+    dir: &Path,
+    config: &AppConfig,
+    env: &HashMap<String, String>,
+    artifact: Option<artifact::Artifact>,
+    progress: Option<build::Progress>,
+    steps: &mut Vec<StepReport>,
+) -> Result<()> {
+    if let Some(a) = &artifact {
+        artifact::install(a, dir)?;
+        if config.build.is_some() {
+            info!(
+                "Prebuilt artifact for {}, skipping [build]",
+                artifact::arch()
+            );
+        }
+    } else if let Some(b) = &config.build {
+        let key = cache::key(dir, b)?;
+        if progress.is_none()
+            && let Err(e) = cache::restore(app, dir, b, &key)
+        {
+            tracing::warn!("Build cache restore failed: {}", e);
+        }
+
+        build::run(app, dir, b, env, progress.as_ref(), steps)?;
+
+        if let Err(e) = cache::save(app, dir, b, &key) {
+            tracing::warn!("Build cache save failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// blobs of an upload were received (and pinned) before we got here
+async fn source(req: &DeployRequest) -> Result<(common::Manifest, Option<store::Pin>)> {
+    match &req.manifest {
+        Some(manifest) => Ok((manifest.clone(), None)),
+        None => {
+            let archive = download::fetch(req).await?;
+            let repo = req.repo.clone();
+            let (manifest, pin) = blocking(move || unpack(&repo, &archive)).await?;
+            Ok((manifest, Some(pin)))
+        }
+    }
+}
+
+// extracts a downloaded archive into the store and returns its manifest
+fn unpack(repo: &str, data: &[u8]) -> Result<(common::Manifest, store::Pin)> {
+    // one per deploy, two deploys of the same repo may overlap
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let staging = common::flare_dir().join("staging").join(format!(
+        "{}-{}-{}",
+        repo.replace('/', "_"),
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::create_dir_all(&staging)?;
+
+    let result = unpack_in(data, &staging);
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+fn unpack_in(data: &[u8], staging: &Path) -> Result<(common::Manifest, store::Pin)> {
+    let limits = extract::Limits::from_env()?;
+    let gz = GzDecoder::new(Cursor::new(data));
+    let stats = extract::unpack(gz, staging, &limits)?;
+
+    info!("Extracted {} entries ({} bytes)", stats.files, stats.bytes);
+
+    let root = source_root(staging)?;
+    let manifest = common::manifest::scan(&root)?;
+    let pin = store::pin(manifest.files.iter().map(|f| f.blob_key()));
+    store::ingest(&root, &manifest)?;
+    Ok((manifest, pin))
+}
+
+// forge tarballs wrap everything in a single "<repo>-<sha>/" directory
+fn source_root(staging: &Path) -> Result<PathBuf> {
+    let entries: Vec<_> = std::fs::read_dir(staging)?.filter_map(|e| e.ok()).collect();
+
+    if let [only] = entries.as_slice()
+        && only.file_type()?.is_dir()
+    {
+        return Ok(only.path());
+    }
+    Ok(staging.to_path_buf())
+}
+
+fn new_release(app: &Path, manifest: &common::Manifest) -> Result<PathBuf> {
+    let versions = app.join("versions");
+    let mut id = chrono::Utc::now().timestamp();
+    while versions.join(id.to_string()).exists() {
+        id += 1;
+    }
+
+    let dir = versions.join(id.to_string());
+    store::materialize(manifest, &dir)?;
+    record(app, &id.to_string(), manifest)?;
+
+    info!(
+        "Release {} ({} files, {} bytes)",
+        id,
+        manifest.files.len(),
+        manifest.total_size()
+    );
+    Ok(dir)
+}
+
+// its blobs stay in the store for the next delta upload and --resume
+fn record(app: &Path, id: &str, manifest: &common::Manifest) -> Result<()> {
+    let path = store::manifest_path(app, id);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, serde_json::to_vec(manifest)?)?;
+    Ok(())
+}
+
+// versions/<id> the app root belongs to
+pub fn release_id(app: &Path, dir: &Path) -> String {
+    dir.strip_prefix(app.join("versions"))
+        .ok()
+        .and_then(|r| r.components().next())
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// points `current` at the release, swapping the symlink atomically
+pub fn activate(app: &Path, release: &Path) -> Result<()> {
+    let tmp = app.join("current.tmp");
+    let _ = std::fs::remove_file(&tmp);
+    std::os::unix::fs::symlink(release, &tmp)?;
+    std::fs::rename(&tmp, app.join("current"))?;
+    Ok(())
+}
+
+fn prune(app: &Path) -> Result<()> {
+    let keep: usize = std::env::var("FLARE_KEEP_RELEASES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5);
+
+    let current = std::fs::read_link(app.join("current")).ok();
+    let mut releases: Vec<_> = std::fs::read_dir(app.join("versions"))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    releases.sort();
+
+    let excess = releases.len().saturating_sub(keep);
+    for old in releases.into_iter().take(excess) {
+        // `current` may point into a subdirectory of the release
+        if current.as_ref().is_some_and(|c| c.starts_with(&old)) {
+            continue;
+        }
+        info!("Removing old release {:?}", old);
+        make_writable(&old)?;
+        std::fs::remove_dir_all(&old)?;
+
+        // build and app logs of the release go with it, and its blobs once
+        // no other release uses them
+        if let Some(id) = old.file_name() {
+            let logs = app.join("logs").join(id);
+            crate::logs::close(&logs);
+            let _ = std::fs::remove_dir_all(logs);
+            let _ = std::fs::remove_file(store::manifest_path(app, &id.to_string_lossy()));
+        }
+    }
+
+    store::gc()
+}
+
+// builds may leave read-only directories behind (e.g. Go module cache)
+fn make_writable(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let meta = std::fs::symlink_metadata(dir)?;
+    if meta.is_dir() {
+        let mut perms = meta.permissions();
+        perms.set_mode(perms.mode() | 0o700);
+        std::fs::set_permissions(dir, perms)?;
+        for entry in std::fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+            make_writable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+async fn start(config: &AppConfig, app: &Path, dir: &Path, routes: Routes) -> Result<()> {
+    if let Some(web) = &config.web {
+        // nothing left running from a release that had [run]
+        let app = app.to_path_buf();
+        tokio::task::spawn_blocking(move || crate::supervisor::stop(&app)).await?;
+        let root = dir.join(web.root.as_deref().unwrap_or("."));
+        routes
+            .write()
+            .await
+            .static_sites
+            .insert(web.domain.clone(), root.to_string_lossy().into());
+        info!("Static site: {} -> {:?}", web.domain, root);
+        return Ok(());
+    }
+
+    // stopping the previous process waits for it to exit
+    if config.run.is_some() {
+        let app = app.to_path_buf();
+        let pid = tokio::task::spawn_blocking(move || crate::supervisor::start(&app)).await??;
+        info!("Started PID {}", pid);
+    }
+    Ok(())
+}
+
+// every app gets its own process group, so a stop reaches whatever the
+// shell started, not just the shell
+pub fn build_run_command(run: &common::RunSection, config: &AppConfig, dir: &Path) -> Command {
+    use std::os::unix::process::CommandExt;
+
+    let isolation = config.isolation.as_ref().map(|i| i.r#type);
+
+    let mut cmd = match isolation {
+        Some(IsolationType::Systemd) => {
+            let mut cmd = Command::new("systemd-run");
+            cmd.args(["--user", "--scope", "sh", "-c", &run.command])
+                .current_dir(dir);
+            cmd
+        }
+        Some(IsolationType::Chroot) => {
+            let mut cmd = Command::new("chroot");
+            cmd.arg(dir).args(["sh", "-c", &run.command]);
+            cmd
+        }
+        _ => {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", &run.command]).current_dir(dir);
+            cmd
+        }
+    };
+    cmd.process_group(0);
+    cmd
+}
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use common::{Manifest, RepoConfig};
+use std::path::Path;
+
+use super::{extract, store};
+
+// Picks the app directories to deploy from one repository tree: the
+// `--path` from the request, the `[[apps]]` list in the root flare.toml,
+// or the root itself. "" stands for the root.
+
+pub fn targets(path: Option<&str>, source: &Manifest) -> Result<Vec<String>> {
+    if let Some(p) = path {
+        return Ok(vec![checked(p, source)?]);
+    }
+
+    let root = match source.files.iter().find(|f| f.path == "flare.toml") {
+        Some(f) => f,
+        // no config at all, detect::fill guesses one for the root
+        None => return Ok(vec![String::new()]),
+    };
+
+    let text = std::fs::read_to_string(store::blob_path(&root.blob_key()))?;
+    let repo: RepoConfig = toml::from_str(&text)?;
+    if repo.apps.is_empty() {
+        return Ok(vec![String::new()]);
+    }
+
+    let mut paths = Vec::new();
+    for app in &repo.apps {
+        let p = checked(&app.path, source)?;
+        if paths.contains(&p) {
+            anyhow::bail!("[[apps]] lists {:?} twice", app.path);
+        }
+        paths.push(p);
+    }
+    Ok(paths)
+}
+
+pub fn normalize(path: &str) -> Result<String> {
+    let p = path.trim().trim_start_matches("./").trim_end_matches('/');
+    let p = if p == "." { "" } else { p };
+
+    if !extract::is_contained(Path::new(""), Path::new(p)) {
+        anyhow::bail!("App path {:?} escapes the repository", path);
+    }
+    Ok(p.to_string())
+}
+
+fn checked(path: &str, source: &Manifest) -> Result<String> {
+    let p = normalize(path)?;
+    if !p.is_empty() && !source.dirs.contains(&p) {
+        anyhow::bail!("No directory {:?} in the repository", p);
+    }
+    Ok(p)
+}
+
+// apps from a monorepo are named after their directory: user/repo/services/api
+pub fn app_name(repo: &str, path: &str) -> String {
+    if path.is_empty() {
+        repo.to_string()
+    } else {
+        format!("{}/{}", repo, path)
+    }
+}
+
+// app_dir() flattens "org/a/b" and "org/a_b" into the same directory, so the
+// first name deployed there keeps it and the other one is refused
+pub fn claim(app: &Path, name: &str) -> Result<()> {
+    let path = app.join("name");
+    match std::fs::read_to_string(&path) {
+        Ok(owner) if owner.trim() != name => anyhow::bail!(
+            "App {} would share {:?} with {}, rename one of them",
+            name,
+            app,
+            owner.trim()
+        ),
+        Ok(_) => Ok(()),
+        Err(_) => {
+            std::fs::create_dir_all(app)?;
+            std::fs::write(path, name)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_that_flatten_alike_cant_share_an_app() {
+        let root = std::env::temp_dir().join(format!("flare-claim-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let app = root.join("org_a_b");
+
+        claim(&app, "org/a/b").unwrap();
+        claim(&app, "org/a/b").unwrap();
+        let err = claim(&app, "org/a_b").unwrap_err();
+        assert!(err.to_string().contains("would share"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,314 @@
+use anyhow::Result;
+use common::{FileEntry, Manifest};
+use std::collections::{HashMap, HashSet};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::info;
+
+// Content-addressed blob store shared by every app on the device, so a
+// deploy only uploads the files that changed. Releases record their manifest
+// next to them; gc keeps the blobs those manifests and the deploys still in
+// flight (see `pin`) refer to.
+//
+// Release files are reflinked from the store (FICLONE: btrfs, XFS), or
+// copied where the filesystem can't, as on ext4. Not hard links: apps
+// write to files they ship (sqlite, config), and a write through a link
+// would change the blob under every other release and fail its hash; a
+// read-only blob would fail the app instead. On ext4 a release costs its
+// full size, bounded by the releases kept per app.
+
+// blob keys per in-flight deploy, guarded together with gc
+static PINS: Mutex<Vec<(u64, HashSet<String>)>> = Mutex::new(Vec::new());
+static NEXT_PIN: AtomicU64 = AtomicU64::new(0);
+
+// keeps gc away from a deploy's blobs from the moment it checks which ones
+// exist until it is done
+pub struct Pin(u64);
+
+pub fn pin<I: IntoIterator<Item = String>>(keys: I) -> Pin {
+    let id = NEXT_PIN.fetch_add(1, Ordering::Relaxed);
+    let keys = keys.into_iter().collect();
+    PINS.lock().unwrap().push((id, keys));
+    Pin(id)
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        if let Ok(mut pins) = PINS.lock() {
+            pins.retain(|(id, _)| *id != self.0);
+        }
+    }
+}
+
+pub fn store_dir() -> PathBuf {
+    common::flare_dir().join("store")
+}
+
+pub fn blob_path(key: &str) -> PathBuf {
+    blob_in(&store_dir(), key)
+}
+
+pub fn blob_in(root: &Path, key: &str) -> PathBuf {
+    root.join(&key[..2]).join(key)
+}
+
+pub fn valid_key(key: &str) -> bool {
+    let hash = key.strip_suffix("-x").unwrap_or(key);
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn missing(files: &[&FileEntry]) -> Result<Vec<String>> {
+    let mut seen = HashSet::new();
+    let mut missing = Vec::new();
+
+    for file in files {
+        let key = file.blob_key();
+        if !valid_key(&key) {
+            anyhow::bail!("Manifest rejected: invalid hash for {:?}", file.path);
+        }
+        if seen.insert(key.clone()) && !blob_path(&key).exists() {
+            missing.push(key);
+        }
+    }
+    Ok(missing)
+}
+
+pub fn write_blob(key: &str, data: &[u8]) -> Result<()> {
+    write_blob_in(&store_dir(), key, data)
+}
+
+pub fn write_blob_in(root: &Path, key: &str, data: &[u8]) -> Result<()> {
+    if !valid_key(key) {
+        anyhow::bail!("Invalid blob key: {}", key);
+    }
+
+    let hash = key.strip_suffix("-x").unwrap_or(key);
+    if common::sha256_hex(data) != hash {
+        anyhow::bail!("Blob {} failed hash check", key);
+    }
+
+    let path = blob_in(root, key);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    seal(&tmp, key)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+// moves already-extracted files into the store, keeping the first copy
+pub fn ingest(src: &Path, manifest: &Manifest) -> Result<()> {
+    for file in &manifest.files {
+        let key = file.blob_key();
+        let path = blob_path(&key);
+        if path.exists() {
+            continue;
+        }
+
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let staged = src.join(&file.path);
+        if std::fs::rename(&staged, &path).is_err() {
+            // different filesystem
+            std::fs::copy(&staged, &path)?;
+        }
+        seal(&path, &key)?;
+    }
+    Ok(())
+}
+
+pub fn materialize(manifest: &Manifest, dest: &Path) -> Result<()> {
+    update(&Manifest::default(), manifest, dest)
+}
+
+// turns a release laid out from `old` into one of `new`, leaving unchanged
+// files and whatever the build added alone
+pub fn update(old: &Manifest, new: &Manifest, dest: &Path) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+
+    let old_files: HashMap<&str, String> = old
+        .files
+        .iter()
+        .map(|f| (f.path.as_str(), f.blob_key()))
+        .collect();
+    let old_links: HashMap<&str, &str> = old
+        .symlinks
+        .iter()
+        .map(|l| (l.path.as_str(), l.target.as_str()))
+        .collect();
+    let wanted: HashSet<&str> = new
+        .files
+        .iter()
+        .map(|f| f.path.as_str())
+        .chain(new.symlinks.iter().map(|l| l.path.as_str()))
+        .collect();
+
+    for path in old_files.keys().chain(old_links.keys()) {
+        if !wanted.contains(path) {
+            let _ = std::fs::remove_file(dest.join(checked(path)?));
+        }
+    }
+
+    for dir in &new.dirs {
+        std::fs::create_dir_all(dest.join(checked(dir)?))?;
+    }
+
+    for file in &new.files {
+        let target = dest.join(checked(&file.path)?);
+        let key = file.blob_key();
+        if !valid_key(&key) {
+            anyhow::bail!("Manifest rejected: invalid hash for {:?}", file.path);
+        }
+        if old_files.get(file.path.as_str()) == Some(&key) {
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let _ = std::fs::remove_file(&target);
+        clone_file(&blob_path(&key), &target)?;
+        let mode = if file.executable { 0o755 } else { 0o644 };
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(mode))?;
+    }
+
+    for link in &new.symlinks {
+        let path = checked(&link.path)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        if !super::extract::is_contained(base, Path::new(&link.target)) {
+            anyhow::bail!(
+                "Manifest rejected: symlink {:?} -> {:?} points outside the release",
+                link.path,
+                link.target
+            );
+        }
+        if old_links.get(link.path.as_str()) == Some(&link.target.as_str()) {
+            continue;
+        }
+        let target = dest.join(path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let _ = std::fs::remove_file(&target);
+        std::os::unix::fs::symlink(&link.target, target)?;
+    }
+
+    Ok(())
+}
+
+// shares the blob's extents where the filesystem supports it
+fn clone_file(src: &Path, dest: &Path) -> Result<()> {
+    let from = std::fs::File::open(src)?;
+    let to = std::fs::File::create(dest)?;
+    if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    std::io::copy(&mut &from, &mut &to)?;
+    Ok(())
+}
+
+// kept next to the release, for gc
+pub fn manifest_path(app: &Path, id: &str) -> PathBuf {
+    app.join("manifests").join(format!("{}.json", id))
+}
+
+// removes blobs no release and no deploy in flight refers to
+pub fn gc() -> Result<()> {
+    let root = store_dir();
+    if !root.exists() {
+        return Ok(());
+    }
+
+    // held throughout, so no deploy can start relying on a blob we remove
+    let pins = PINS.lock().unwrap();
+    let mut keep = referenced()?;
+    for (_, keys) in pins.iter() {
+        keep.extend(keys.iter().cloned());
+    }
+
+    let mut freed = 0u64;
+    for shard in std::fs::read_dir(&root)?.filter_map(|e| e.ok()) {
+        for blob in std::fs::read_dir(shard.path())?.filter_map(|e| e.ok()) {
+            let name = blob.file_name().to_string_lossy().to_string();
+            // blobs still being written are <key>.tmp
+            if !valid_key(&name) || keep.contains(&name) {
+                continue;
+            }
+            let meta = blob.metadata()?;
+            std::fs::remove_file(blob.path())?;
+            freed += meta.len();
+        }
+    }
+
+    if freed > 0 {
+        info!("Store gc freed {} bytes", freed);
+    }
+    Ok(())
+}
+
+// blob keys in the manifests of every app's releases
+fn referenced() -> Result<HashSet<String>> {
+    let mut keys = HashSet::new();
+    let apps = common::apps_dir();
+    if !apps.exists() {
+        return Ok(keys);
+    }
+
+    for app in std::fs::read_dir(apps)?.filter_map(|e| e.ok()) {
+        let dir = app.path().join("manifests");
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+            let text = std::fs::read_to_string(entry.path())?;
+            let manifest: Manifest = serde_json::from_str(&text)?;
+            keys.extend(manifest.files.iter().map(|f| f.blob_key()));
+        }
+    }
+    Ok(keys)
+}
+
+fn checked(path: &str) -> Result<&Path> {
+    let p = Path::new(path);
+    if !super::extract::is_contained(Path::new(""), p) {
+        anyhow::bail!(
+            "Manifest rejected: {:?} escapes the release directory",
+            path
+        );
+    }
+    Ok(p)
+}
+
+// a blob is only ever replaced by a new one, never edited
+fn seal(path: &Path, key: &str) -> Result<()> {
+    let mode = if key.ends_with("-x") { 0o555 } else { 0o444 };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+// asks the CLI for blobs we don't have and stores them as they arrive
+pub async fn receive<S>(stream: &mut S, files: &[&FileEntry]) -> Result<()>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let missing = missing(files)?;
+    info!("Sync: {} of {} files needed", missing.len(), files.len());
+
+    let need = common::SyncNeeded {
+        missing: missing.clone(),
+    };
+    common::send_json(stream, &need).await?;
+
+    let sizes: HashMap<String, u64> = files.iter().map(|f| (f.blob_key(), f.size)).collect();
+    for key in &missing {
+        let data = common::recv_msg(stream).await?;
+        // the limits were checked against the sizes in the manifest
+        if sizes.get(key) != Some(&(data.len() as u64)) {
+            anyhow::bail!("Blob {} does not match the size in the manifest", key);
+        }
+        write_blob(key, &data)?;
+    }
+    Ok(())
+}
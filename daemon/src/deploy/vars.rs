@@ -0,0 +1,145 @@
+use anyhow::Result;
+use common::{AppConfig, DatabaseSection};
+use std::path::Path;
+
+use crate::database::Connection;
+
+// Loads flare.toml from an app root with overlays for the profile, the tags
+// and this device merged in (see common::overlay), then `${...}` resolved:
+//   PORT         [run] port, or the port a blue-green instance runs on
+//   APP_DIR      ~/.flare/apps/<app>, stable across releases
+//   RELEASE_ID   versions/<id> of the release being deployed
+//   DB_URL       connection URL of the [database] that was set up
+//   DB_PORT      its actual port (may differ from [database] port)
+//   DEVICE_NAME  FLARE_DEVICE_NAME or the hostname
+//   env.X        daemon environment
+//   secret.X     [secrets] entry: "env:VAR", "file:/path" or a literal
+
+// `port` replaces [run] port
+pub fn load(
+    app: &Path,
+    dir: &Path,
+    profile: Option<&str>,
+    tags: &[String],
+    db: Option<&Connection>,
+    port: Option<u16>,
+) -> Result<AppConfig> {
+    Ok(resolve(app, dir, profile, tags, db, port, None)?.try_into()?)
+}
+
+// [database] alone, needed to set it up before the rest can be resolved
+pub fn database(
+    app: &Path,
+    dir: &Path,
+    profile: Option<&str>,
+    tags: &[String],
+) -> Result<Option<DatabaseSection>> {
+    let value = resolve(app, dir, profile, tags, None, None, Some("database"))?;
+    Ok(match value.get("database") {
+        Some(db) => Some(db.clone().try_into()?),
+        None => None,
+    })
+}
+
+fn resolve(
+    app: &Path,
+    dir: &Path,
+    profile: Option<&str>,
+    tags: &[String],
+    db: Option<&Connection>,
+    port: Option<u16>,
+    section: Option<&str>,
+) -> Result<toml::Value> {
+    let mut value = common::detect::load(dir)?;
+    // tags sent with the deploy, or the ones the daemon was started with
+    let tags = match tags {
+        [] => device_tags(),
+        sent => sent.to_vec(),
+    };
+    common::overlay::apply(&mut value, profile, &device_name(), &tags)?;
+
+    if let Some(p) = port
+        && let Some(run) = value.get_mut("run").and_then(|r| r.as_table_mut())
+    {
+        run.insert("port".into(), toml::Value::Integer(p.into()));
+    }
+
+    let port = value
+        .get("run")
+        .and_then(|r| r.get("port"))
+        .and_then(|p| p.as_integer());
+    let secrets = value
+        .get("secrets")
+        .and_then(|s| s.as_table())
+        .cloned()
+        .unwrap_or_default();
+    let release_id = super::release_id(app, dir);
+
+    let mut lookup = |name: &str| -> Result<String> {
+        match name {
+            "PORT" => port
+                .map(|p| p.to_string())
+                .ok_or_else(|| anyhow::anyhow!("${{PORT}} needs [run] port")),
+            "APP_DIR" => Ok(app.to_string_lossy().into()),
+            "RELEASE_ID" => Ok(release_id.clone()),
+            "DEVICE_NAME" => Ok(device_name()),
+            "DB_URL" => db
+                .map(|d| d.url.clone())
+                .ok_or_else(|| anyhow::anyhow!("${{DB_URL}} needs a [database] section")),
+            "DB_PORT" => db
+                .and_then(|d| d.port)
+                .map(|p| p.to_string())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("${{DB_PORT}} needs a postgres or mysql [database]")
+                }),
+            _ => {
+                if let Some(key) = name.strip_prefix("env.") {
+                    return std::env::var(key)
+                        .map_err(|_| anyhow::anyhow!("${{{}}} is not set on the device", name));
+                }
+                if let Some(key) = name.strip_prefix("secret.") {
+                    let reference = secrets.get(key).and_then(|v| v.as_str()).ok_or_else(|| {
+                        anyhow::anyhow!("${{{}}}: no such entry in [secrets]", name)
+                    })?;
+                    return secret(key, reference);
+                }
+                anyhow::bail!("Unknown variable ${{{}}}", name)
+            }
+        }
+    };
+
+    common::interpolate::expand_value(&mut value, section, &mut lookup)?;
+    Ok(value)
+}
+
+fn secret(key: &str, reference: &str) -> Result<String> {
+    if let Some(var) = reference.strip_prefix("env:") {
+        return std::env::var(var)
+            .map_err(|_| anyhow::anyhow!("Secret {}: {} is not set on the device", key, var));
+    }
+    if let Some(file) = reference.strip_prefix("file:") {
+        let data = std::fs::read_to_string(file)
+            .map_err(|e| anyhow::anyhow!("Secret {}: can't read {}: {}", key, file, e))?;
+        return Ok(data.trim_end().to_string());
+    }
+    Ok(reference.to_string())
+}
+
+pub fn device_name() -> String {
+    if let Ok(name) = std::env::var("FLARE_DEVICE_NAME") {
+        return name;
+    }
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|_| "flare".into())
+}
+
+// FLARE_DEVICE_TAGS=arm,kitchen selects [tag.arm] and [tag.kitchen]
+fn device_tags() -> Vec<String> {
+    std::env::var("FLARE_DEVICE_TAGS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
@@ -0,0 +1,186 @@
+use anyhow::Result;
+use common::AppConfig;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+// Environment for every process spawned for an app (build steps, hooks, the
+// app itself), later entries winning:
+//   PORT          [run] port, or the port of a blue-green instance
+//   env_file      dotenv file in the release, e.g. ".env.production"
+//   [env]         from flare.toml, `${...}` already resolved
+//   overrides     `flare env set`, kept in <app>/env.toml across deploys
+
+fn overrides_path(app: &Path) -> PathBuf {
+    app.join("env.toml")
+}
+
+pub fn load_overrides(app: &Path) -> Result<BTreeMap<String, String>> {
+    let path = overrides_path(app);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+pub fn save_overrides(app: &Path, vars: &BTreeMap<String, String>) -> Result<()> {
+    let path = overrides_path(app);
+    if vars.is_empty() {
+        let _ = std::fs::remove_file(path);
+        return Ok(());
+    }
+    std::fs::write(path, toml::to_string(vars)?)?;
+    Ok(())
+}
+
+pub fn resolve(app: &Path, dir: &Path, config: &AppConfig) -> Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+
+    if let Some(port) = config.run.as_ref().and_then(|r| r.port) {
+        env.insert("PORT".into(), port.to_string());
+    }
+
+    if let Some(file) = &config.env_file {
+        if !common::is_contained(Path::new(""), Path::new(file)) {
+            anyhow::bail!("env_file {:?} escapes the release", file);
+        }
+        let path = dir.join(file);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Can't read env_file {:?}: {}", path, e))?;
+        env.extend(parse(&text)?);
+    }
+
+    env.extend(config.env.clone().unwrap_or_default());
+    env.extend(load_overrides(app)?);
+    Ok(env)
+}
+
+// KEY=value lines; `export`, comments, and single or double quotes are
+// understood, nothing is expanded
+fn parse(text: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("env_file line {}: expected KEY=value", n + 1))?;
+        let key = key.trim();
+        if !valid_key(key) {
+            anyhow::bail!("env_file line {}: invalid key {:?}", n + 1, key);
+        }
+
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(q @ ('"' | '\'')) if value.len() > 1 && value.ends_with(q) => {
+                value[1..value.len() - 1].to_string()
+            }
+            // unquoted values may end with a comment
+            _ => value
+                .split_once(" #")
+                .map_or(value, |(v, _)| v.trim_end())
+                .to_string(),
+        };
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+pub fn valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flare-env-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_dotenv_lines() {
+        for (line, key, value) in [
+            ("A=1", "A", "1"),
+            ("  export B = two  ", "B", "two"),
+            (
+                "C=\"quoted # not a comment\"",
+                "C",
+                "quoted # not a comment",
+            ),
+            ("D='single'", "D", "single"),
+            ("E=\"", "E", "\""),
+            ("F=plain # comment", "F", "plain"),
+            ("G=a#b", "G", "a#b"),
+            (
+                "H=postgres://u:p@h/db?sslmode=require",
+                "H",
+                "postgres://u:p@h/db?sslmode=require",
+            ),
+            ("I=", "I", ""),
+            ("_J2=x", "_J2", "x"),
+        ] {
+            let vars = parse(line).unwrap();
+            assert_eq!(vars, [(key.to_string(), value.to_string())], "{}", line);
+        }
+
+        assert!(parse("# comment\n\n   # indented\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for (text, error) in [
+            ("A=1\nnot a pair", "env_file line 2: expected KEY=value"),
+            ("=value", "env_file line 1: invalid key \"\""),
+            ("1A=x", "env_file line 1: invalid key \"1A\""),
+            ("MY-KEY=x", "env_file line 1: invalid key \"MY-KEY\""),
+            ("A B=x", "env_file line 1: invalid key \"A B\""),
+        ] {
+            assert_eq!(parse(text).unwrap_err().to_string(), error, "{}", text);
+        }
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let app = scratch("app");
+        let dir = scratch("release");
+        std::fs::write(dir.join(".env"), "PORT=1\nA=file\nB=file\nC=file\n").unwrap();
+        save_overrides(&app, &BTreeMap::from([("C".into(), "override".into())])).unwrap();
+
+        let config: AppConfig = toml::from_str(
+            r#"
+            env_file = ".env"
+
+            [app]
+            name = "api"
+            version = "1"
+
+            [run]
+            command = "./api"
+            port = 8080
+
+            [env]
+            B = "config"
+            C = "config"
+            "#,
+        )
+        .unwrap();
+
+        let env = resolve(&app, &dir, &config).unwrap();
+        // env_file may even set PORT, it is only the default
+        assert_eq!(env["PORT"], "1");
+        assert_eq!(env["A"], "file");
+        assert_eq!(env["B"], "config");
+        assert_eq!(env["C"], "override");
+    }
+}
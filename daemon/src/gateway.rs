@@ -6,13 +6,75 @@ use axum::{
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
 };
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
 use tokio::net::TcpListener;
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::server::Routes;
 
+// redirects go back to the client as they are
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_default()
+});
+
+// a canary deploy sends `percent` of a domain's requests to the new release
+// on `port`, the rest to its route in proxy_routes; responses are counted per
+// side so the deploy can compare error rates
+pub struct Split {
+    pub port: u16,
+    pub percent: u8,
+    requests: AtomicU64,
+    pub canary: Stats,
+    pub stable: Stats,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Split {
+    pub fn new(port: u16, percent: u8) -> Arc<Split> {
+        Arc::new(Split {
+            port,
+            percent,
+            requests: AtomicU64::new(0),
+            canary: Stats::default(),
+            stable: Stats::default(),
+        })
+    }
+
+    // spread evenly: 20% is every fifth request, not the first 20 of 100
+    fn to_canary(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        (n * self.percent as u64) % 100 < self.percent as u64
+    }
+}
+
+impl Stats {
+    // (requests, 5xx responses)
+    pub fn get(&self) -> (u64, u64) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        )
+    }
+
+    fn record(&self, status: StatusCode) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status.is_server_error() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
 pub async fn run(routes: Routes) -> Result<()> {
     let app = Router::new().fallback(handler).with_state(routes);
 
@@ -34,9 +96,71 @@ async fn handler(State(routes): State<Routes>, Host(host): Host, req: Request<Bo
         };
     }
 
-    if let Some(port) = state.proxy_routes.get(&host) {
-        return (StatusCode::OK, format!("proxy -> localhost:{}", port)).into_response();
+    if let Some(split) = state.canaries.get(&host).cloned() {
+        let stable = state.proxy_routes.get(&host).copied();
+        drop(state);
+        let (port, stats) = match stable {
+            Some(port) if !split.to_canary() => (port, &split.stable),
+            _ => (split.port, &split.canary),
+        };
+        let resp = proxy(port, req).await;
+        stats.record(resp.status());
+        return resp;
+    }
+
+    if let Some(port) = state.proxy_routes.get(&host).copied() {
+        // a deploy may switch the route while the request is in flight
+        drop(state);
+        return proxy(port, req).await;
     }
 
     (StatusCode::NOT_FOUND, "Not found").into_response()
 }
+
+// bodies are streamed both ways, so uploads and downloads of any size pass
+// without being held in memory
+async fn proxy(port: u16, req: Request<Body>) -> Response {
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let url = format!("http://127.0.0.1:{}{}", port, path);
+
+    let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes())
+        .unwrap_or(reqwest::Method::GET);
+    let mut upstream = CLIENT.request(method, url);
+    for (name, value) in req.headers() {
+        if !hop_by_hop(name.as_str()) {
+            upstream = upstream.header(name.as_str(), value.as_bytes());
+        }
+    }
+
+    let body = reqwest::Body::wrap_stream(req.into_body().into_data_stream());
+    let resp = match upstream.body(body).send().await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Proxy to port {}: {}", port, e);
+            return (StatusCode::BAD_GATEWAY, "Bad gateway").into_response();
+        }
+    };
+
+    let mut out = Response::builder().status(resp.status().as_u16());
+    for (name, value) in resp.headers() {
+        if !hop_by_hop(name.as_str()) && name != "content-length" {
+            out = out.header(name.as_str(), value.as_bytes());
+        }
+    }
+    out.body(Body::from_stream(resp.bytes_stream()))
+        .unwrap_or_else(|e| (StatusCode::BAD_GATEWAY, e.to_string()).into_response())
+}
+
+fn hop_by_hop(name: &str) -> bool {
+    matches!(
+        name,
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "te"
+            | "trailer"
+            | "transfer-encoding"
+            | "upgrade"
+    )
+}
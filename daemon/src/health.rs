@@ -0,0 +1,498 @@
+use common::{AppConfig, HealthState, Probe, ProbeType};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::server::Routes;
+
+// Probes every running app according to [health]:
+//   liveness    failing failure_threshold times in a row stops the process,
+//               the restart policy brings it back (supervisor::kill_unhealthy)
+//   readiness   [run] domain is routed by the gateway only while it passes;
+//               it starts out failing and passes after success_threshold
+//               successes in a row, probed every second until then
+// `url` alone is an http readiness probe. Without a readiness probe an app
+// is routed as soon as it runs. Results are kept in state.toml (`health`),
+// changes also go to the app log.
+
+const SCAN: Duration = Duration::from_secs(1);
+const DEFAULT_INTERVAL: u64 = 10;
+const DEFAULT_TIMEOUT: u64 = 5;
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_SUCCESS_THRESHOLD: u32 = 1;
+
+// one task per running process; a new PID (restart, deploy) gets a new task
+// with the config of its release
+pub async fn run(routes: Routes) {
+    let mut watched: HashMap<PathBuf, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        watched.retain(|_, task| !task.is_finished());
+
+        if let Ok(entries) = std::fs::read_dir(common::apps_dir()) {
+            for app in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if watched.contains_key(&app) {
+                    continue;
+                }
+                if let Some(pid) = running(&app) {
+                    let task = tokio::spawn(watch(app.clone(), pid, routes.clone()));
+                    watched.insert(app, task);
+                }
+            }
+        }
+
+        tokio::time::sleep(SCAN).await;
+    }
+}
+
+fn running(app: &Path) -> Option<u32> {
+    let state = common::load_state(app).ok()??;
+    if state.status != "running" {
+        return None;
+    }
+    state.pid
+}
+
+struct Check {
+    name: &'static str,
+    probe: Probe,
+    next: Instant,
+    passes: u32,
+    fails: u32,
+    ok: Option<bool>,
+    error: Option<String>, // of the last probe
+    starting: bool,        // readiness not passed yet, probed every second
+}
+
+impl Check {
+    fn new(name: &'static str, probe: Probe, ok: Option<bool>) -> Check {
+        let delay = Duration::from_secs(probe.initial_delay.unwrap_or(0));
+        Check {
+            name,
+            probe,
+            next: Instant::now() + delay,
+            passes: 0,
+            fails: 0,
+            ok,
+            error: None,
+            starting: ok == Some(false),
+        }
+    }
+
+    fn due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+
+    // counts a result, returns the new state when the thresholds flip it
+    fn record(&mut self, result: &Result<(), String>) -> Option<bool> {
+        let interval = match self.starting {
+            true => 1,
+            false => self.probe.interval.unwrap_or(DEFAULT_INTERVAL),
+        };
+        self.next = Instant::now() + Duration::from_secs(interval);
+        self.error = result.as_ref().err().cloned();
+
+        let (count, threshold, passed) = match result {
+            Ok(()) => {
+                self.fails = 0;
+                self.passes += 1;
+                let t = self.probe.success_threshold;
+                (self.passes, t.unwrap_or(DEFAULT_SUCCESS_THRESHOLD), true)
+            }
+            Err(_) => {
+                self.passes = 0;
+                self.fails += 1;
+                let t = self.probe.failure_threshold;
+                (self.fails, t.unwrap_or(DEFAULT_FAILURE_THRESHOLD), false)
+            }
+        };
+        if self.ok != Some(passed) && count >= threshold {
+            self.ok = Some(passed);
+            self.starting = false;
+            return Some(passed);
+        }
+        None
+    }
+}
+
+struct Target {
+    dir: PathBuf,
+    env: HashMap<String, String>,
+    port: Option<u16>,
+    client: reqwest::Client,
+}
+
+async fn watch(app: PathBuf, pid: u32, routes: Routes) {
+    let name = app
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let loaded = crate::supervisor::load(&app).and_then(|(config, release)| {
+        let env = crate::env::resolve(&app, &release, &config)?;
+        Ok((config, release, env))
+    });
+    let (config, release, env) = match loaded {
+        Ok(l) => l,
+        Err(e) => {
+            warn!("{}: no health checks: {}", name, e);
+            return gone(&app, pid).await;
+        }
+    };
+    let run = match &config.run {
+        Some(r) => r,
+        None => return gone(&app, pid).await,
+    };
+
+    let health = config.health.as_ref();
+    let mut liveness = health
+        .and_then(|h| h.liveness.clone())
+        .map(|p| Check::new("liveness", p, None));
+    let mut readiness = readiness_probe(&config).map(|p| Check::new("readiness", p, Some(false)));
+
+    let target = Target {
+        dir: release,
+        env,
+        port: run.port,
+        client: reqwest::Client::new(),
+    };
+    let route = run.domain.clone().zip(run.port);
+
+    let mut state = HealthState {
+        live: None,
+        ready: readiness.as_ref().map(|_| false),
+        since: Some(now()),
+        message: None,
+    };
+    save(&app, pid, &state);
+    // a blue-green switch routed this process already
+    if readiness.is_none() {
+        set_route(&routes, route.as_ref(), true).await;
+    }
+
+    loop {
+        let next = [liveness.as_ref(), readiness.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|c| c.next)
+            .min()
+            .unwrap_or_else(Instant::now);
+        tokio::time::sleep_until(next.min(Instant::now() + SCAN)).await;
+        if running(&app) != Some(pid) {
+            break;
+        }
+
+        let before = state.clone();
+
+        if let Some(check) = &mut readiness
+            && check.due()
+        {
+            let result = probe(&check.probe, &target).await;
+            if let Some(ready) = record(&app, &name, check, &result) {
+                state.ready = Some(ready);
+                state.since = Some(now());
+                set_route(&routes, route.as_ref(), ready).await;
+            }
+        }
+
+        let mut unhealthy = false;
+        if let Some(check) = &mut liveness
+            && check.due()
+        {
+            let result = probe(&check.probe, &target).await;
+            if let Some(live) = record(&app, &name, check, &result) {
+                state.live = Some(live);
+                state.since = Some(now());
+                unhealthy = !live;
+            }
+        }
+
+        // a failing probe explains itself even while another one passes
+        state.message = [readiness.as_ref(), liveness.as_ref()]
+            .into_iter()
+            .flatten()
+            .find_map(|c| c.error.clone());
+        if state != before {
+            save(&app, pid, &state);
+        }
+        if unhealthy {
+            restart(&app, pid, &name).await;
+        }
+    }
+
+    set_route(&routes, route.as_ref(), false).await;
+    // stopped or crashed, nothing left to be healthy
+    crate::supervisor::update(&app, |s| {
+        if s.pid.is_none() {
+            s.health = None;
+        }
+    });
+}
+
+// counts a probe result and logs when it flips the check
+fn record(app: &Path, name: &str, check: &mut Check, result: &Result<(), String>) -> Option<bool> {
+    let passed = check.record(result)?;
+    let text = match result {
+        Ok(()) => format!("{} probe passed", check.name),
+        Err(e) => format!("{} probe failed {} times: {}", check.name, check.fails, e),
+    };
+    if passed {
+        info!("{}: {}", name, text);
+    } else {
+        warn!("{}: {}", name, text);
+    }
+    crate::supervisor::event(app, &text);
+    Some(passed)
+}
+
+async fn restart(app: &Path, pid: u32, name: &str) {
+    let dir = app.to_path_buf();
+    let killed = tokio::task::spawn_blocking(move || crate::supervisor::kill_unhealthy(&dir, pid))
+        .await
+        .unwrap_or(false);
+
+    if !killed {
+        warn!("{}: PID {} is not supervised, not restarting", name, pid);
+    }
+}
+
+// [health.readiness], or [health] url
+fn readiness_probe(config: &AppConfig) -> Option<Probe> {
+    let health = config.health.as_ref()?;
+    health.readiness.clone().or_else(|| {
+        health.url.clone().map(|url| Probe {
+            url: Some(url),
+            ..Probe::of(ProbeType::Http)
+        })
+    })
+}
+
+// a process the monitor doesn't route: a blue-green standby or a canary.
+// Probed with its readiness probe, or a tcp probe on its port when there is
+// none, starting out not ready
+pub struct Standby {
+    check: Check,
+    target: Target,
+}
+
+impl Standby {
+    pub fn new(config: &AppConfig, dir: &Path, env: HashMap<String, String>) -> Standby {
+        let readiness = readiness_probe(config).unwrap_or_else(|| Probe::of(ProbeType::Tcp));
+        Standby {
+            check: Check::new("readiness", readiness, Some(false)),
+            target: Target {
+                dir: dir.to_path_buf(),
+                env,
+                port: config.run.as_ref().and_then(|r| r.port),
+                client: reqwest::Client::new(),
+            },
+        }
+    }
+
+    // probes when due, returns the new state when it flips
+    pub async fn poll(&mut self) -> Option<bool> {
+        if !self.check.due() {
+            return None;
+        }
+        let result = probe(&self.check.probe, &self.target).await;
+        self.check.record(&result)
+    }
+
+    // of the last probe
+    pub fn error(&self) -> Option<&str> {
+        self.check.error.as_deref()
+    }
+}
+
+// until the standby passes its readiness probe; the error is the last failure
+pub async fn wait_ready(
+    standby: &mut Standby,
+    timeout: Duration,
+    running: impl Fn() -> bool,
+) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if !running() {
+            return Err("the new process exited".into());
+        }
+        if standby.poll().await == Some(true) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            let why = standby.error().unwrap_or("no result yet");
+            return Err(format!("not ready after {}s: {}", timeout.as_secs(), why));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+async fn probe(probe: &Probe, target: &Target) -> Result<(), String> {
+    let timeout = probe.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let check = async {
+        match probe.r#type {
+            ProbeType::Http => http(probe, target).await,
+            ProbeType::Tcp => tcp(probe, target).await,
+            ProbeType::Exec => exec(probe, target).await,
+        }
+    };
+
+    match tokio::time::timeout(Duration::from_secs(timeout), check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no answer within {}s", timeout)),
+    }
+}
+
+async fn http(probe: &Probe, target: &Target) -> Result<(), String> {
+    let url = probe.url.as_deref().ok_or("no url")?;
+    let resp = target
+        .client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let status = resp.status();
+    let expected = match probe.status {
+        Some(s) => status.as_u16() == s,
+        None => status.is_success(),
+    };
+    if !expected {
+        return Err(format!("HTTP {}", status.as_u16()));
+    }
+
+    if let Some(body) = &probe.body {
+        let text = resp.text().await.map_err(|e| e.to_string())?;
+        if !text.contains(body.as_str()) {
+            return Err(format!("response doesn't contain {:?}", body));
+        }
+    }
+    Ok(())
+}
+
+async fn tcp(probe: &Probe, target: &Target) -> Result<(), String> {
+    let port = probe.port.or(target.port).ok_or("no port")?;
+    tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("port {}: {}", port, e))
+}
+
+async fn exec(probe: &Probe, target: &Target) -> Result<(), String> {
+    let command = probe.command.as_deref().ok_or("no command")?;
+    let output = tokio::process::Command::new("sh")
+        .args(["-c", command])
+        .current_dir(&target.dir)
+        .envs(&target.env)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+    // last line of what it printed says the most
+    let text = [&output.stderr, &output.stdout]
+        .into_iter()
+        .map(|o| String::from_utf8_lossy(o).trim().to_string())
+        .find(|t| !t.is_empty())
+        .and_then(|t| t.lines().last().map(String::from));
+    let code = output.status.code().unwrap_or(-1);
+    Err(match text {
+        Some(t) => format!("exit {}: {}", code, t),
+        None => format!("exit {}", code),
+    })
+}
+
+// no probes to run, wait for the process to go away
+async fn gone(app: &Path, pid: u32) {
+    while running(app) == Some(pid) {
+        tokio::time::sleep(SCAN).await;
+    }
+}
+
+async fn set_route(routes: &Routes, route: Option<&(String, u16)>, up: bool) {
+    let (domain, port) = match route {
+        Some(r) => r,
+        None => return,
+    };
+    let mut routes = routes.write().await;
+    if up {
+        routes.proxy_routes.insert(domain.clone(), *port);
+    } else if routes.proxy_routes.get(domain) == Some(port) {
+        // unless it was switched to another process already
+        routes.proxy_routes.remove(domain);
+    }
+}
+
+// only while the process we probe is still the app's process
+fn save(app: &Path, pid: u32, health: &HealthState) {
+    crate::supervisor::update(app, |s| {
+        if s.pid == Some(pid) {
+            s.health = Some(health.clone());
+        }
+    });
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail() -> Result<(), String> {
+        Err("connection refused".into())
+    }
+
+    #[test]
+    fn flips_down_after_failure_threshold() {
+        let mut check = Check::new("liveness", Probe::of(ProbeType::Tcp), Some(true));
+        assert_eq!(check.record(&fail()), None);
+        assert_eq!(check.record(&fail()), None);
+        // a pass in between starts the count over
+        assert_eq!(check.record(&Ok(())), None);
+        assert_eq!(check.record(&fail()), None);
+        assert_eq!(check.record(&fail()), None);
+        assert_eq!(check.record(&fail()), Some(false));
+        assert_eq!(check.error.as_deref(), Some("connection refused"));
+        // already down
+        assert_eq!(check.record(&fail()), None);
+        assert_eq!(check.record(&Ok(())), Some(true));
+    }
+
+    #[test]
+    fn flips_up_after_success_threshold() {
+        let probe = Probe {
+            success_threshold: Some(2),
+            ..Probe::of(ProbeType::Tcp)
+        };
+        let mut check = Check::new("readiness", probe, Some(false));
+        assert!(check.starting);
+        assert_eq!(check.record(&Ok(())), None);
+        assert!(check.starting);
+        assert_eq!(check.record(&Ok(())), Some(true));
+        assert!(!check.starting);
+        assert_eq!(check.error, None);
+    }
+
+    #[test]
+    fn first_result_settles_an_unknown_state() {
+        let mut check = Check::new("liveness", Probe::of(ProbeType::Tcp), None);
+        assert_eq!(check.record(&Ok(())), Some(true));
+
+        let probe = Probe {
+            failure_threshold: Some(1),
+            ..Probe::of(ProbeType::Tcp)
+        };
+        let mut check = Check::new("liveness", probe, None);
+        assert_eq!(check.record(&fail()), Some(false));
+    }
+}
@@ -1,28 +1,65 @@
 use common::AppConfig;
-use std::path::PathBuf;
-use std::process::Command;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use tracing::info;
 
-pub fn run_pre(config: &AppConfig, dir: &PathBuf) {
-    if let Some(hooks) = &config.hooks {
-        if let Some(cmd) = &hooks.pre_deploy {
-            info!("Pre-deploy: {}", cmd);
-            let _ = Command::new("sh")
-                .args(["-c", cmd])
-                .current_dir(dir)
-                .status();
-        }
+// hook output goes to the app log of the release (see logs.rs)
+
+pub fn run_pre(config: &AppConfig, dir: &Path, env: &HashMap<String, String>, log: &Path) {
+    if let Some(hooks) = &config.hooks
+        && let Some(cmd) = &hooks.pre_deploy
+    {
+        info!("Pre-deploy: {}", cmd);
+        run(cmd, dir, env, log, "hook:pre_deploy");
+    }
+}
+
+pub fn run_post(config: &AppConfig, dir: &Path, env: &HashMap<String, String>, log: &Path) {
+    if let Some(hooks) = &config.hooks
+        && let Some(cmd) = &hooks.post_deploy
+    {
+        info!("Post-deploy: {}", cmd);
+        run(cmd, dir, env, log, "hook:post_deploy");
     }
 }
 
-pub fn run_post(config: &AppConfig, dir: &PathBuf) {
-    if let Some(hooks) = &config.hooks {
-        if let Some(cmd) = &hooks.post_deploy {
-            info!("Post-deploy: {}", cmd);
-            let _ = Command::new("sh")
-                .args(["-c", cmd])
-                .current_dir(dir)
-                .status();
+pub fn run_rollback(
+    config: &AppConfig,
+    dir: &Path,
+    env: &HashMap<String, String>,
+    log: &Path,
+    reason: &str,
+) {
+    if let Some(hooks) = &config.hooks
+        && let Some(cmd) = &hooks.on_rollback
+    {
+        info!("On-rollback: {}", cmd);
+        let mut env = env.clone();
+        env.insert("FLARE_ROLLBACK_REASON".into(), reason.into());
+        run(cmd, dir, &env, log, "hook:on_rollback");
+    }
+}
+
+fn run(cmd: &str, dir: &Path, env: &HashMap<String, String>, log: &Path, source: &str) {
+    let child = Command::new("sh")
+        .args(["-c", cmd])
+        .current_dir(dir)
+        .envs(env)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+
+    match child {
+        Ok(mut c) => {
+            if let Some(out) = c.stdout.take() {
+                crate::logs::pipe(log, source, out, None);
+            }
+            if let Some(err) = c.stderr.take() {
+                crate::logs::pipe(log, source, err, None);
+            }
+            let _ = c.wait();
         }
+        Err(e) => tracing::warn!("{} failed to start: {}", source, e),
     }
 }
@@ -0,0 +1,405 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, LazyLock, Mutex};
+use tracing::warn;
+
+// Output of everything run for an app, one file per release next to the
+// build step logs: <app>/logs/<release>/app.log. Each line gets a UTC
+// timestamp and its source:
+//   2026-01-02T03:04:05.678Z [app] listening on 3000
+//   2026-01-02T03:04:05.679Z [app:err] ...
+//   ... [build:<step>], [hook:pre_deploy], [flare] (supervisor events)
+// The file is rotated to app.log.1 .. app.log.<FLARE_LOG_KEEP> (default 5)
+// when it grows past FLARE_LOG_MAX_SIZE (default 10M) or on a new day.
+
+pub const FILE: &str = "app.log";
+
+struct Log {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    day: NaiveDate,
+}
+
+static LOGS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<Log>>>>> = LazyLock::new(Default::default);
+
+pub fn path(app: &Path, release: &Path) -> PathBuf {
+    crate::deploy::build::log_dir(app, release).join(FILE)
+}
+
+// copies a child's output into the log line by line (and into `copy` as is);
+// the receiver fires when the stream is closed
+pub fn pipe(
+    path: &Path,
+    source: &str,
+    stream: impl Read + Send + 'static,
+    mut copy: Option<File>,
+) -> Receiver<()> {
+    let (tx, rx) = mpsc::channel();
+    let log = open(path);
+    let source = source.to_string();
+
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if let Some(f) = &mut copy {
+                let _ = f.write_all(&buf);
+            }
+            if let Some(log) = &log {
+                let text = String::from_utf8_lossy(&buf);
+                append(log, &source, text.trim_end_matches(['\n', '\r']));
+            }
+        }
+        let _ = tx.send(());
+    });
+    rx
+}
+
+// one line from the daemon itself
+pub fn line(path: &Path, source: &str, text: &str) {
+    if let Some(log) = open(path) {
+        append(&log, source, text);
+    }
+}
+
+// forgets logs of a removed release
+pub fn close(dir: &Path) {
+    LOGS.lock().unwrap().retain(|p, _| !p.starts_with(dir));
+}
+
+fn open(path: &Path) -> Option<Arc<Mutex<Log>>> {
+    let mut logs = LOGS.lock().unwrap();
+    if let Some(log) = logs.get(path) {
+        return Some(log.clone());
+    }
+
+    let result = (|| -> Result<Log> {
+        std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        // an existing file keeps the day it was last written
+        let day = meta
+            .modified()
+            .map(|t| DateTime::<Utc>::from(t).date_naive())
+            .unwrap_or_else(|_| Utc::now().date_naive());
+        Ok(Log {
+            path: path.to_path_buf(),
+            file,
+            size: meta.len(),
+            day,
+        })
+    })();
+
+    match result {
+        Ok(log) => {
+            let log = Arc::new(Mutex::new(log));
+            logs.insert(path.to_path_buf(), log.clone());
+            Some(log)
+        }
+        Err(e) => {
+            warn!("Can't open log {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn append(log: &Mutex<Log>, source: &str, text: &str) {
+    let mut log = log.lock().unwrap();
+    let now = Utc::now();
+
+    if (log.size >= max_size() || log.day != now.date_naive())
+        && log.size > 0
+        && let Err(e) = rotate(&mut log, keep())
+    {
+        warn!("Can't rotate {:?}: {}", log.path, e);
+    }
+    log.day = now.date_naive();
+
+    let entry = format!("{} [{}] {}\n", timestamp(now), source, text);
+    if log.file.write_all(entry.as_bytes()).is_ok() {
+        log.size += entry.len() as u64;
+    }
+}
+
+fn rotate(log: &mut Log, keep: usize) -> Result<()> {
+    let _ = std::fs::remove_file(rotated(&log.path, keep));
+    for n in (1..keep).rev() {
+        let _ = std::fs::rename(rotated(&log.path, n), rotated(&log.path, n + 1));
+    }
+    std::fs::rename(&log.path, rotated(&log.path, 1))?;
+
+    log.file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log.path)?;
+    log.size = 0;
+    Ok(())
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn max_size() -> u64 {
+    std::env::var("FLARE_LOG_MAX_SIZE")
+        .ok()
+        .and_then(|v| common::parse_size(&v).ok())
+        .unwrap_or(10 << 20)
+}
+
+fn keep() -> usize {
+    std::env::var("FLARE_LOG_KEEP")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5)
+}
+
+pub fn timestamp(t: DateTime<Utc>) -> String {
+    t.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+// --- reading, for `flare logs`
+
+pub struct Filter {
+    since: Option<String>, // timestamps sort as strings
+    grep: Option<String>,
+}
+
+impl Filter {
+    pub fn new(since: Option<&str>, grep: Option<&str>) -> Result<Filter> {
+        let since = match since {
+            Some(s) => Some(match DateTime::parse_from_rfc3339(s) {
+                Ok(t) => timestamp(t.with_timezone(&Utc)),
+                Err(_) => {
+                    let ago = chrono::Duration::from_std(common::parse_duration(s)?)?;
+                    timestamp(Utc::now() - ago)
+                }
+            }),
+            None => None,
+        };
+        Ok(Filter {
+            since,
+            grep: grep.map(String::from),
+        })
+    }
+
+    pub fn matches(&self, line: &str) -> bool {
+        self.since.as_ref().is_none_or(|s| line >= s.as_str())
+            && self.grep.as_ref().is_none_or(|g| line.contains(g.as_str()))
+    }
+}
+
+// rotated files first, oldest to newest, then app.log
+pub fn read_all(path: &Path, filter: &Filter) -> Result<Vec<String>> {
+    let mut files: Vec<PathBuf> = (1..=keep())
+        .rev()
+        .map(|n| rotated(path, n))
+        .filter(|p| p.exists())
+        .collect();
+    files.push(path.to_path_buf());
+
+    let mut lines = Vec::new();
+    for file in files {
+        let data = match std::fs::read(&file) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        lines.extend(
+            String::from_utf8_lossy(&data)
+                .lines()
+                .filter(|l| filter.matches(l))
+                .map(String::from),
+        );
+    }
+    Ok(lines)
+}
+
+pub struct Cursor {
+    inode: u64,
+    pos: u64,
+}
+
+impl Cursor {
+    // where read_all stopped
+    pub fn end(path: &Path) -> Cursor {
+        use std::os::unix::fs::MetadataExt;
+
+        match std::fs::metadata(path) {
+            Ok(m) => Cursor {
+                inode: m.ino(),
+                pos: m.len(),
+            },
+            Err(_) => Cursor { inode: 0, pos: 0 },
+        }
+    }
+}
+
+// complete lines written since the last call; starts over when the file was
+// rotated
+pub fn read_from(path: &Path, cursor: &mut Cursor, filter: &Filter) -> Result<Vec<String>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let meta = file.metadata()?;
+    if meta.ino() != cursor.inode || meta.len() < cursor.pos {
+        cursor.inode = meta.ino();
+        cursor.pos = 0;
+    }
+    file.seek(SeekFrom::Start(cursor.pos))?;
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let end = match data.iter().rposition(|b| *b == b'\n') {
+        Some(i) => i + 1,
+        None => return Ok(Vec::new()),
+    };
+    cursor.pos += end as u64;
+
+    Ok(String::from_utf8_lossy(&data[..end])
+        .lines()
+        .filter(|l| filter.matches(l))
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flare-logs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn rotation_shifts_files_and_keeps_the_newest() {
+        let path = scratch("rotate").join(FILE);
+        let log = open(&path).unwrap();
+        for n in 1..=4 {
+            line(&path, "app", &format!("run {}", n));
+            rotate(&mut log.lock().unwrap(), 2).unwrap();
+        }
+        line(&path, "app", "run 5");
+
+        assert!(read(&path).ends_with("[app] run 5\n"));
+        assert!(read(&rotated(&path, 1)).ends_with("[app] run 4\n"));
+        assert!(read(&rotated(&path, 2)).ends_with("[app] run 3\n"));
+        assert!(!rotated(&path, 3).exists());
+    }
+
+    #[test]
+    fn a_new_day_rotates() {
+        let path = scratch("day").join(FILE);
+        line(&path, "app", "yesterday");
+        let log = open(&path).unwrap();
+        log.lock().unwrap().day = Utc::now().date_naive() - chrono::Days::new(1);
+        line(&path, "app", "today");
+
+        assert!(read(&rotated(&path, 1)).ends_with("[app] yesterday\n"));
+        assert!(read(&path).ends_with("[app] today\n"));
+    }
+
+    #[test]
+    fn filters_by_time_and_text() {
+        let now = Utc::now();
+        let at = |ago: i64, text: &str| {
+            format!(
+                "{} [app] {}",
+                timestamp(now - chrono::Duration::minutes(ago)),
+                text
+            )
+        };
+
+        let recent = Filter::new(Some("10m"), None).unwrap();
+        assert!(!recent.matches(&at(20, "old")));
+        assert!(recent.matches(&at(1, "new")));
+
+        // an offset is compared in UTC
+        let since = Filter::new(Some("2026-01-02T05:00:00+02:00"), None).unwrap();
+        assert!(!since.matches("2026-01-02T02:59:59.999Z [app] before"));
+        assert!(since.matches("2026-01-02T03:00:00.000Z [app] at"));
+
+        let both = Filter::new(Some("10m"), Some("error")).unwrap();
+        assert!(both.matches(&at(1, "an error")));
+        assert!(!both.matches(&at(1, "fine")));
+        assert!(!both.matches(&at(20, "an old error")));
+
+        assert!(Filter::new(Some("yesterday"), None).is_err());
+    }
+
+    #[test]
+    fn read_all_goes_oldest_first() {
+        let path = scratch("all").join(FILE);
+        let log = open(&path).unwrap();
+        line(&path, "app", "first");
+        rotate(&mut log.lock().unwrap(), 5).unwrap();
+        line(&path, "app", "second");
+        line(&path, "app:err", "third");
+
+        let everything = Filter::new(None, None).unwrap();
+        let lines = read_all(&path, &everything).unwrap();
+        let texts: Vec<&str> = lines.iter().map(|l| l.split_once(' ').unwrap().1).collect();
+        assert_eq!(texts, ["[app] first", "[app] second", "[app:err] third"]);
+
+        let errors = Filter::new(None, Some("[app:err]")).unwrap();
+        assert_eq!(read_all(&path, &errors).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn following_starts_over_after_rotation() {
+        let path = scratch("follow").join(FILE);
+        let log = open(&path).unwrap();
+        let everything = Filter::new(None, None).unwrap();
+
+        line(&path, "app", "seen");
+        let mut cursor = Cursor::end(&path);
+        line(&path, "app", "one");
+        let lines = read_from(&path, &mut cursor, &everything).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("one"));
+
+        // a partial line waits for its newline
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"half")
+            .unwrap();
+        assert!(
+            read_from(&path, &mut cursor, &everything)
+                .unwrap()
+                .is_empty()
+        );
+
+        // the new app.log is a different inode, even if it grew past the cursor
+        rotate(&mut log.lock().unwrap(), 5).unwrap();
+        for n in 0..5 {
+            line(&path, "app", &format!("after {}", n));
+        }
+        let lines = read_from(&path, &mut cursor, &everything).unwrap();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with("after 0"));
+    }
+}
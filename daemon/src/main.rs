@@ -1,9 +1,15 @@
+mod bundle;
 mod database;
 mod deploy;
 mod discovery;
+mod env;
 mod gateway;
+mod health;
 mod hooks;
+mod logs;
+mod reconcile;
 mod server;
+mod supervisor;
 mod tls;
 
 #[tokio::main]
@@ -12,6 +18,38 @@ async fn main() {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["import", file] => {
+            match bundle::request_import(std::path::Path::new(file)).await {
+                Ok(release) => println!("Imported {} -> {}", file, release),
+                Err(e) => {
+                    eprintln!("Import failed: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        ["trust", key] => {
+            if let Err(e) = common::bundle::trust_key(key) {
+                eprintln!("Can't trust key: {}", e);
+                std::process::exit(1);
+            }
+            println!("Trusted {}", key);
+            return;
+        }
+        [] => {}
+        _ => {
+            eprintln!("Usage: flared [import <bundle> | trust <public key>]");
+            std::process::exit(2);
+        }
+    }
+
     tracing::info!("Flared starting...");
 
     if let Err(e) = server::run(7530).await {
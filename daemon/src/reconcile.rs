@@ -0,0 +1,170 @@
+use anyhow::Result;
+use common::AppState;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::server::Routes;
+
+// Runs once when flared starts. Gateway routes live in memory and the PIDs
+// in state.toml belong to the previous daemon, so every app is looked at
+// again: [web] sites get their route back, recorded PIDs are checked against
+// /proc, and apps that were running are started again under this daemon
+// unless [run] autostart = false.
+
+pub async fn run(routes: &Routes) {
+    let entries = match std::fs::read_dir(common::apps_dir()) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let app = entry.path();
+        if let Err(e) = app_state(&app, routes).await {
+            warn!("Reconcile {:?}: {}", app, e);
+        }
+    }
+}
+
+async fn app_state(app: &Path, routes: &Routes) -> Result<()> {
+    let mut state = match common::load_state(app)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    let release = match std::fs::read_link(app.join("current")) {
+        Ok(r) => r,
+        Err(_) => return Ok(()),
+    };
+
+    let db = state.db_url.clone().map(|url| crate::database::Connection {
+        port: state.db_port,
+        url,
+    });
+    let config = crate::deploy::vars::load(
+        app,
+        &release,
+        state.profile.as_deref(),
+        &state.tags,
+        db.as_ref(),
+        state.port,
+    )?;
+
+    if let Some(web) = &config.web {
+        let root = release.join(web.root.as_deref().unwrap_or("."));
+        routes
+            .write()
+            .await
+            .static_sites
+            .insert(web.domain.clone(), root.to_string_lossy().into());
+        info!("Static site: {} -> {:?}", web.domain, root);
+        return Ok(());
+    }
+
+    let run = match &config.run {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    let was_running = matches!(state.status.as_str(), "running" | "restarting");
+    let survivor = state.pid.filter(|pid| alive(*pid, app));
+
+    // a survivor isn't our child and its output went to the old daemon, so
+    // it is replaced by a supervised process (supervisor::start stops it)
+    if was_running && run.autostart.unwrap_or(true) {
+        let dir = app.to_path_buf();
+        let pid = tokio::task::spawn_blocking(move || crate::supervisor::start(&dir)).await??;
+        info!("{}: started again as PID {}", state.name, pid);
+        return Ok(());
+    }
+
+    if let Some(pid) = survivor {
+        warn!("{}: PID {} still running unsupervised", state.name, pid);
+        return Ok(());
+    }
+
+    if state.pid.is_some() || was_running {
+        mark_stopped(app, &mut state)?;
+    }
+    Ok(())
+}
+
+// the PID exists and works inside this app's directory, so it isn't a
+// reused PID of something unrelated
+pub fn alive(pid: u32, app: &Path) -> bool {
+    alive_in(Path::new("/proc"), pid, app)
+}
+
+fn alive_in(proc_root: &Path, pid: u32, app: &Path) -> bool {
+    let proc = proc_root.join(pid.to_string());
+    let stat = match std::fs::read_to_string(proc.join("stat")) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    // "<pid> (<comm>) <state> ...", a zombie has exited already
+    if stat
+        .rsplit_once(')')
+        .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z'))
+    {
+        return false;
+    }
+    match std::fs::read_link(proc.join("cwd")) {
+        Ok(cwd) => {
+            cwd.starts_with(app) || std::fs::canonicalize(app).is_ok_and(|a| cwd.starts_with(a))
+        }
+        // not ours to inspect, trust the PID
+        Err(_) => true,
+    }
+}
+
+fn mark_stopped(app: &Path, state: &mut AppState) -> Result<()> {
+    info!("{}: not running", state.name);
+    state.status = "stopped".into();
+    state.pid = None;
+    common::save_state(app, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("flare-reconcile-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // /proc/<pid> with a stat line in `state` and, if given, a cwd link
+    fn process(root: &Path, pid: u32, state: char, cwd: Option<&Path>) {
+        let dir = root.join(pid.to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("stat"),
+            format!("{} (my app) {} 1 {} 0 0", pid, state, pid),
+        )
+        .unwrap();
+        if let Some(cwd) = cwd {
+            std::os::unix::fs::symlink(cwd, dir.join("cwd")).unwrap();
+        }
+    }
+
+    #[test]
+    fn checks_recorded_pids_against_proc() {
+        let root = scratch("proc");
+        let app = scratch("app");
+        let release = app.join("releases/20260102-030405");
+        std::fs::create_dir_all(&release).unwrap();
+
+        process(&root, 100, 'S', Some(&release));
+        process(&root, 101, 'Z', Some(&release));
+        process(&root, 102, 'R', Some(Path::new("/var/lib/other")));
+        process(&root, 103, 'S', None);
+
+        assert!(alive_in(&root, 100, &app), "running in a release");
+        assert!(!alive_in(&root, 101, &app), "zombie");
+        assert!(!alive_in(&root, 102, &app), "reused by another program");
+        assert!(alive_in(&root, 103, &app), "cwd not readable");
+        assert!(!alive_in(&root, 104, &app), "gone");
+    }
+}
@@ -1,7 +1,6 @@
 use anyhow::Result;
-use common::{
-    DeployRequest, ManageRequest, ManageResponse, RegisterTokenRequest, recv_json, send_json,
-};
+use common::{DeployRequest, EnvRequest, EnvResponse, ManageRequest, ManageResponse};
+use common::{LogsChunk, LogsRequest, RegisterTokenRequest};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -17,6 +16,8 @@ pub type Routes = Arc<RwLock<GatewayState>>;
 pub struct GatewayState {
     pub static_sites: HashMap<String, String>,
     pub proxy_routes: HashMap<String, u16>,
+    // domains in a canary deploy, see deploy::canary
+    pub canaries: HashMap<String, Arc<crate::gateway::Split>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -51,6 +52,9 @@ pub async fn run(port: u16) -> Result<()> {
 
     let routes: Routes = Arc::new(RwLock::new(GatewayState::default()));
 
+    // routes and processes from before this daemon started
+    crate::reconcile::run(&routes).await;
+
     // start gateway
     let routes_clone = routes.clone();
     tokio::spawn(async move {
@@ -59,6 +63,15 @@ pub async fn run(port: u16) -> Result<()> {
         }
     });
 
+    // probes, which also keep app routes in the gateway
+    tokio::spawn(crate::health::run(routes.clone()));
+
+    // pick up offline bundles, from `flared import` or the drop dir
+    tokio::spawn(crate::bundle::listen(routes.clone()));
+    if let Ok(dir) = std::env::var("FLARE_DROP_DIR") {
+        tokio::spawn(crate::bundle::watch(dir.into(), routes.clone()));
+    }
+
     // start discovery
     tokio::spawn(async move {
         if let Err(e) = crate::discovery::run(7001).await {
@@ -90,12 +103,10 @@ pub async fn run(port: u16) -> Result<()> {
 async fn handle(mut socket: TlsStream<TcpStream>, routes: Routes) -> Result<()> {
     let msg: serde_json::Value = common::recv_json(&mut socket).await?;
 
-    tracing::info!(
-        "Raw message: {}",
-        serde_json::to_string_pretty(&msg).unwrap()
-    );
-
+    // only the type and app: the rest carries tokens, env values, manifests
     let msg_type = msg.get("msg_type").and_then(|v| v.as_str()).unwrap_or("");
+    let app = msg.get("app").and_then(|v| v.as_str()).unwrap_or("-");
+    tracing::info!("{} request for {}", msg_type, app);
 
     match msg_type {
         "register_token" => {
@@ -110,6 +121,15 @@ async fn handle(mut socket: TlsStream<TcpStream>, routes: Routes) -> Result<()>
             let req: ManageRequest = serde_json::from_value(msg)?;
             handle_manage(socket, req).await
         }
+        "env" => {
+            let req: EnvRequest = serde_json::from_value(msg)?;
+            handle_env(socket, req).await
+        }
+        "logs" => {
+            let req: LogsRequest = serde_json::from_value(msg)?;
+            handle_logs(socket, req).await
+        }
+        "info" => handle_info(socket).await,
         _ => {
             warn!("Unknown message type: {}", msg_type);
             Ok(())
@@ -121,22 +141,43 @@ async fn handle_manage(
     mut socket: tokio_rustls::server::TlsStream<TcpStream>,
     req: ManageRequest,
 ) -> Result<()> {
-    let result = match req.action.as_str() {
-        "start" => start_app(&req.app),
-        "stop" => stop_app(&req.app),
-        "restart" => restart_app(&req.app),
-        "rollback" => rollback_app(&req.app), // добавь
+    if !authorized(req.daemon_token.as_deref()) {
+        warn!("Invalid token");
+        let response = ManageResponse {
+            success: false,
+            message: "Invalid token".into(),
+            state: None,
+        };
+        return common::send_json(&mut socket, &response).await;
+    }
+
+    // stop, restart and rollback wait for the process to exit
+    let (app, action) = (req.app.clone(), req.action.clone());
+    let result = tokio::task::spawn_blocking(move || match action.as_str() {
+        "start" => start_app(&app),
+        "stop" => stop_app(&app),
+        "restart" => restart_app(&app),
+        "rollback" => rollback_app(&app), // добавь
+        "status" => status_app(&app),
         _ => Err(anyhow::anyhow!("Unknown action")),
+    })
+    .await?;
+    // "status" sends state.toml along
+    let state = match (&result, req.action.as_str()) {
+        (Ok(_), "status") => common::load_state(&common::app_dir(&req.app))?,
+        _ => None,
     };
 
     let response = match result {
         Ok(msg) => ManageResponse {
             success: true,
             message: msg,
+            state,
         },
         Err(e) => ManageResponse {
             success: false,
             message: e.to_string(),
+            state: None,
         },
     };
 
@@ -145,28 +186,13 @@ async fn handle_manage(
 
 fn start_app(app: &str) -> Result<String> {
     let dir = common::app_dir(app);
-    let mut state = common::load_state(&dir)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
+    let state = common::load_state(&dir)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
 
-    if state.status == "running" {
+    if state.status == "running" && crate::supervisor::is_supervised(&dir) {
         return Ok("Already running".into());
     }
 
-    let config = common::load_app_config(&dir)?;
-    let run = config
-        .run
-        .ok_or_else(|| anyhow::anyhow!("No [run] section"))?;
-
-    let child = std::process::Command::new("systemd-run")
-        .args(["--user", "--scope", "sh", "-c", &run.command])
-        .current_dir(&dir)
-        .spawn()?;
-
-    let pid = child.id();
-
-    state.status = "running".into();
-    state.pid = Some(pid);
-    common::save_state(&dir, &state)?;
-
+    let pid = crate::supervisor::start(&dir)?;
     Ok(format!("Started with PID {}", pid))
 }
 
@@ -174,58 +200,258 @@ fn stop_app(app: &str) -> Result<String> {
     let dir = common::app_dir(app);
     let mut state = common::load_state(&dir)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
 
-    if let Some(pid) = state.pid {
-        let _ = std::process::Command::new("kill")
-            .arg(pid.to_string())
-            .status();
-    }
+    let result = match crate::supervisor::stop(&dir) {
+        Some(r) => r,
+        // not ours if the daemon was restarted since it started
+        None => match state.pid {
+            Some(pid) => crate::supervisor::terminate(&dir, pid),
+            None => "Not running".into(),
+        },
+    };
 
     state.status = "stopped".into();
     state.pid = None;
     common::save_state(&dir, &state)?;
 
-    Ok("Stopped".into())
+    Ok(result)
 }
 
+// stop waits for the process group to exit, so the port is free again
 fn restart_app(app: &str) -> Result<String> {
-    stop_app(app)?;
-    std::thread::sleep(std::time::Duration::from_millis(500));
-    start_app(app)
+    let stopped = stop_app(app)?;
+    let started = start_app(app)?;
+    Ok(format!("{}. {}", stopped, started))
+}
+
+fn status_app(app: &str) -> Result<String> {
+    let dir = common::app_dir(app);
+    let state = common::load_state(&dir)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
+    Ok(state.status)
 }
 
 fn rollback_app(app: &str) -> Result<String> {
     let dir = common::app_dir(app);
     let versions = dir.join("versions");
 
+    let active = std::fs::read_link(dir.join("current")).ok();
+
     let mut entries: Vec<_> = std::fs::read_dir(&versions)?
         .filter_map(|e| e.ok())
+        .map(|e| e.path())
         .collect();
 
-    entries.sort_by_key(|e| e.path());
-
-    let latest = entries
-        .last()
-        .ok_or_else(|| anyhow::anyhow!("No backups found"))?;
+    entries.sort();
 
-    let current = dir.join("current");
+    // monorepo apps point `current` at a subdirectory of the release
+    let (active, sub) = match &active {
+        Some(a) => match entries.iter().find(|p| a.starts_with(p)) {
+            Some(r) => (Some(r.clone()), a.strip_prefix(r)?.to_path_buf()),
+            None => (Some(a.clone()), PathBuf::new()),
+        },
+        None => (None, PathBuf::new()),
+    };
 
-    if current.exists() {
-        std::fs::remove_file(&current)?;
+    // newest release older than the active one
+    let previous = match &active {
+        Some(a) => entries.iter().rev().find(|p| *p < a),
+        None => entries.last(),
     }
+    .ok_or_else(|| anyhow::anyhow!("No backups found"))?;
 
-    std::os::unix::fs::symlink(latest.path(), &current)?;
+    if sub.as_os_str().is_empty() {
+        crate::deploy::activate(&dir, previous)?;
+    } else {
+        crate::deploy::activate(&dir, &previous.join(sub))?;
+    }
 
-    // restart if running
+    // restart if running, a crash loop would keep restarting the old release
     let state = common::load_state(&dir)?;
-    if let Some(s) = state {
-        if s.status == "running" {
-            restart_app(app)?;
-        }
+    if let Some(s) = state
+        && matches!(s.status.as_str(), "running" | "restarting")
+    {
+        restart_app(app)?;
     }
 
     Ok("Rolled back".into())
 }
 
+async fn handle_env(mut socket: TlsStream<TcpStream>, req: EnvRequest) -> Result<()> {
+    let response = if !authorized(req.daemon_token.as_deref()) {
+        warn!("Invalid token");
+        EnvResponse {
+            success: false,
+            message: "Invalid token".into(),
+            vars: HashMap::new(),
+        }
+    } else {
+        // restarts a running app, which waits for the old process to exit
+        match tokio::task::spawn_blocking(move || update_env(&req)).await? {
+            Ok((message, vars)) => EnvResponse {
+                success: true,
+                message,
+                vars,
+            },
+            Err(e) => EnvResponse {
+                success: false,
+                message: e.to_string(),
+                vars: HashMap::new(),
+            },
+        }
+    };
+
+    common::send_json(&mut socket, &response).await
+}
+
+fn update_env(req: &EnvRequest) -> Result<(String, HashMap<String, String>)> {
+    let dir = common::app_dir(&req.app);
+    let state = common::load_state(&dir)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
+    let mut vars = crate::env::load_overrides(&dir)?;
+
+    let message = match req.action.as_str() {
+        "list" => {
+            return Ok((
+                format!("{} variable(s)", vars.len()),
+                vars.into_iter().collect(),
+            ));
+        }
+        "set" => {
+            if let Some(key) = req.vars.keys().find(|k| !crate::env::valid_key(k)) {
+                anyhow::bail!("Invalid variable name: {:?}", key);
+            }
+            vars.extend(req.vars.clone());
+            format!("Set {}", sorted(req.vars.keys()))
+        }
+        "unset" => {
+            let removed: Vec<&String> = req
+                .keys
+                .iter()
+                .filter(|k| vars.remove(*k).is_some())
+                .collect();
+            if removed.is_empty() {
+                return Ok(("Nothing to unset".into(), HashMap::new()));
+            }
+            format!("Unset {}", sorted(removed.into_iter()))
+        }
+        _ => anyhow::bail!("Unknown action"),
+    };
+    crate::env::save_overrides(&dir, &vars)?;
+    info!("{}: {}", req.app, message);
+
+    // the new environment only reaches the app through a restart
+    if state.status == "running" {
+        restart_app(&req.app)?;
+        return Ok((format!("{}, restarted", message), HashMap::new()));
+    }
+    Ok((message, HashMap::new()))
+}
+
+fn sorted<'a>(keys: impl Iterator<Item = &'a String>) -> String {
+    let mut keys: Vec<&str> = keys.map(|k| k.as_str()).collect();
+    keys.sort();
+    keys.join(", ")
+}
+
+async fn handle_logs(mut socket: TlsStream<TcpStream>, req: LogsRequest) -> Result<()> {
+    let fail = |message: String| LogsChunk {
+        lines: Vec::new(),
+        done: true,
+        error: Some(message),
+    };
+
+    if !authorized(req.daemon_token.as_deref()) {
+        warn!("Invalid token");
+        return common::send_json(&mut socket, &fail("Invalid token".into())).await;
+    }
+
+    let (path, filter) = match log_target(&req) {
+        Ok(t) => t,
+        Err(e) => return common::send_json(&mut socket, &fail(e.to_string())).await,
+    };
+
+    let mut lines = crate::logs::read_all(&path, &filter)?;
+    let mut cursor = crate::logs::Cursor::end(&path);
+    if let Some(n) = req.lines {
+        lines.drain(..lines.len().saturating_sub(n));
+    }
+    for chunk in lines.chunks(500) {
+        let msg = LogsChunk {
+            lines: chunk.to_vec(),
+            done: false,
+            error: None,
+        };
+        common::send_json(&mut socket, &msg).await?;
+    }
+
+    if !req.follow {
+        let done = LogsChunk {
+            lines: Vec::new(),
+            done: true,
+            error: None,
+        };
+        return common::send_json(&mut socket, &done).await;
+    }
+
+    // until the client goes away; an empty chunk now and then notices that
+    let mut idle = 0;
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        let lines = crate::logs::read_from(&path, &mut cursor, &filter)?;
+        idle = if lines.is_empty() { idle + 1 } else { 0 };
+        if !lines.is_empty() || idle % 10 == 0 {
+            let msg = LogsChunk {
+                lines,
+                done: false,
+                error: None,
+            };
+            if common::send_json(&mut socket, &msg).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn log_target(req: &LogsRequest) -> Result<(PathBuf, crate::logs::Filter)> {
+    let dir = common::app_dir(&req.app);
+    if !dir.exists() {
+        anyhow::bail!("App not found");
+    }
+
+    let release = match &req.release {
+        // release ids are timestamps, nothing else reaches the file system
+        Some(id) if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) => id.clone(),
+        Some(id) => anyhow::bail!("Invalid release id {:?}", id),
+        None => {
+            let current = std::fs::read_link(dir.join("current"))
+                .map_err(|_| anyhow::anyhow!("{} has no active release", req.app))?;
+            crate::deploy::release_id(&dir, &current)
+        }
+    };
+    let path = dir.join("logs").join(&release).join(crate::logs::FILE);
+    if !path.exists() {
+        anyhow::bail!("No logs for release {}", release);
+    }
+
+    let filter = crate::logs::Filter::new(req.since.as_deref(), req.grep.as_deref())?;
+    Ok((path, filter))
+}
+
+fn authorized(token: Option<&str>) -> bool {
+    let token = token.unwrap_or("");
+    load_tokens()
+        .tokens
+        .iter()
+        .any(|hash| common::verify_token(token, hash))
+}
+
+async fn handle_info(mut socket: tokio_rustls::server::TlsStream<TcpStream>) -> Result<()> {
+    let info = common::DeviceInfo {
+        version: env!("CARGO_PKG_VERSION").into(),
+        arch: crate::deploy::artifact::arch().into(),
+        os: std::env::consts::OS.into(),
+    };
+    common::send_json(&mut socket, &info).await
+}
+
 async fn handle_register_token(
     mut socket: tokio_rustls::server::TlsStream<TcpStream>,
     req: common::RegisterTokenRequest,
@@ -248,40 +474,111 @@ async fn handle_deploy(
     routes: Routes,
     req: common::DeployRequest,
 ) -> Result<()> {
-    // verify token
-    let store = load_tokens();
-
-    let token = req.daemon_token.as_deref().unwrap_or("");
-    let valid = store
-        .tokens
-        .iter()
-        .any(|hash| common::verify_token(token, hash));
-
-    if !valid {
+    if !authorized(req.daemon_token.as_deref()) {
         warn!("Invalid token");
-        let response = common::DeployResponse {
-            success: false,
-            message: "Invalid token".into(),
-            app_dir: None,
-        };
-        common::send_json(&mut socket, &response).await?;
-        return Ok(());
+        return common::send_json(&mut socket, &failed_deploy("Invalid token".into())).await;
     }
 
     info!("Deploy: {}", req.repo);
 
-    let response = match crate::deploy::run(&req, routes).await {
-        Ok(dir) => common::DeployResponse {
+    let mut wanted: Vec<&common::FileEntry> = req.manifest.iter().flat_map(|m| &m.files).collect();
+    wanted.extend(&req.artifact);
+    // until the release has its copies, the blobs must survive other deploys' gc
+    let _pin = crate::deploy::store::pin(wanted.iter().map(|f| f.blob_key()));
+
+    if !wanted.is_empty()
+        && let Err(e) = receive(&mut socket, &req, &wanted).await
+    {
+        warn!("Upload failed: {}", e);
+        return common::send_json(&mut socket, &failed_deploy(e.to_string())).await;
+    }
+
+    let mut report = crate::deploy::Report::default();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    if req.progress {
+        report.progress = Some(tx);
+    }
+
+    // progress lines go out while the deploy runs; a client that went away
+    // doesn't stop it half way, it just isn't told anymore
+    let mut listening = true;
+    let result = {
+        let deploy = crate::deploy::run(&req, routes, &mut report);
+        tokio::pin!(deploy);
+        loop {
+            tokio::select! {
+                result = &mut deploy => break result,
+                Some(line) = rx.recv() => progress(&mut socket, &mut listening, line).await,
+            }
+        }
+    };
+    while let Ok(line) = rx.try_recv() {
+        progress(&mut socket, &mut listening, line).await;
+    }
+    if !listening {
+        info!("Deploy of {} finished, ok: {}", req.repo, result.is_ok());
+        return Ok(());
+    }
+
+    let response = match result {
+        Ok(dirs) => common::DeployResponse {
             success: true,
-            message: format!("Deployed to {}", dir.display()),
-            app_dir: Some(dir.to_string_lossy().into()),
+            message: format!(
+                "Deployed to {}",
+                dirs.iter()
+                    .map(|d| d.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            app_dir: dirs.first().map(|d| d.to_string_lossy().into()),
+            steps: report.steps,
+            // apps/<name>/versions/<id>
+            apps: dirs
+                .iter()
+                .filter_map(|d| d.strip_prefix(common::apps_dir()).ok()?.iter().next())
+                .map(|n| n.to_string_lossy().into())
+                .collect(),
         },
         Err(e) => common::DeployResponse {
-            success: false,
-            message: e.to_string(),
-            app_dir: None,
+            steps: report.steps,
+            ..failed_deploy(e.to_string())
         },
     };
 
     common::send_json(&mut socket, &response).await
 }
+
+async fn progress(
+    socket: &mut tokio_rustls::server::TlsStream<TcpStream>,
+    listening: &mut bool,
+    line: String,
+) {
+    if *listening
+        && let Err(e) = common::send_json(socket, &common::DeployProgress { progress: line }).await
+    {
+        warn!("Client gone, deploy goes on: {}", e);
+        *listening = false;
+    }
+}
+
+async fn receive(
+    socket: &mut tokio_rustls::server::TlsStream<TcpStream>,
+    req: &common::DeployRequest,
+    wanted: &[&common::FileEntry],
+) -> Result<()> {
+    if let Some(manifest) = &req.manifest {
+        let limits = crate::deploy::extract::Limits::from_env()?;
+        crate::deploy::extract::check_manifest(manifest, &limits)?;
+    }
+    crate::deploy::store::receive(socket, wanted).await
+}
+
+fn failed_deploy(message: String) -> common::DeployResponse {
+    common::DeployResponse {
+        success: false,
+        message,
+        app_dir: None,
+        steps: Vec::new(),
+        apps: Vec::new(),
+    }
+}
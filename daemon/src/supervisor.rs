@@ -0,0 +1,461 @@
+use anyhow::Result;
+use common::{AppConfig, AppState, RestartPolicy, StopSignal};
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+// Owns the process of every running app. Each one gets a thread that waits
+// on it, so exits are reaped and recorded in state.toml, and starts it again
+// according to [run] restart:
+//   always       after any exit
+//   on-failure   after a non-zero exit or a signal (default)
+//   never
+// Restarts back off 1s, 2s, 4s ... up to a minute. More than max_restarts
+// in a row, each running less than STABLE, marks the app "crashed".
+//
+// A failed liveness probe (health.rs) stops the process and counts as a
+// failure, whatever its exit code.
+//
+// Apps run in their own process group. Stopping sends [run] stop_signal to
+// the group, waits up to stop_timeout for it to go away, then SIGKILLs it.
+//
+// A blue-green deploy runs the new release as a standby next to the active
+// process, on its own port, until promote() makes it the active one. Only
+// the active process is recorded in state.toml.
+
+const STABLE: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+struct Slot {
+    pid: AtomicU32, // 0 while waiting to restart
+    stopping: AtomicBool,
+    unhealthy: AtomicBool,
+    active: AtomicBool, // false for a standby
+    release: PathBuf,
+    port: Option<u16>,
+}
+
+impl Slot {
+    fn new(pid: u32, release: PathBuf, port: Option<u16>, active: bool) -> Arc<Slot> {
+        Arc::new(Slot {
+            pid: AtomicU32::new(pid),
+            stopping: AtomicBool::new(false),
+            unhealthy: AtomicBool::new(false),
+            active: AtomicBool::new(active),
+            release,
+            port,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Policy {
+    restart: RestartPolicy,
+    max_restarts: u32,
+}
+
+static APPS: LazyLock<Mutex<HashMap<PathBuf, Arc<Slot>>>> = LazyLock::new(Default::default);
+static STANDBY: LazyLock<Mutex<HashMap<PathBuf, Arc<Slot>>>> = LazyLock::new(Default::default);
+static STATE: Mutex<()> = Mutex::new(());
+
+// starts the active release of an app, replacing a process we already run
+pub fn start(app: &Path) -> Result<u32> {
+    let previous = stop(app).or_else(|| {
+        // left behind by an earlier daemon
+        let pid = common::load_state(app).ok()??.pid?;
+        crate::reconcile::alive(pid, app).then(|| terminate(app, pid))
+    });
+    if let Some(result) = previous {
+        info!("Previous process: {}", result);
+    }
+
+    let release = std::fs::read_link(app.join("current"))?;
+    let port = common::load_state(app)?.and_then(|s| s.port);
+    let (child, policy) = spawn(app, &release, port)?;
+    let pid = child.id();
+
+    let slot = Slot::new(pid, release, port, true);
+    APPS.lock().unwrap().insert(app.to_path_buf(), slot.clone());
+
+    update(app, |s| {
+        s.status = "running".into();
+        s.pid = Some(pid);
+        s.exit_code = None;
+        s.restarts = 0;
+    });
+
+    let app = app.to_path_buf();
+    std::thread::spawn(move || watch(&app, &slot, child, policy));
+    Ok(pid)
+}
+
+// takes the app away from the supervisor and stops it, returning what
+// happened; None if it wasn't ours (e.g. started by an earlier daemon)
+pub fn stop(app: &Path) -> Option<String> {
+    let slot = APPS.lock().unwrap().remove(app)?;
+    slot.stopping.store(true, Ordering::SeqCst);
+
+    let pid = slot.pid.load(Ordering::SeqCst);
+    if pid == 0 {
+        // between restarts, nothing to signal
+        return Some("Stopped".into());
+    }
+    Some(terminate(app, pid))
+}
+
+// runs `release` on `port` next to the active process, supervised but not
+// routed or recorded until promote()
+pub fn start_standby(app: &Path, release: &Path, port: u16) -> Result<u32> {
+    stop_standby(app);
+
+    let (child, policy) = spawn(app, release, Some(port))?;
+    let pid = child.id();
+
+    let slot = Slot::new(pid, release.to_path_buf(), Some(port), false);
+    STANDBY
+        .lock()
+        .unwrap()
+        .insert(app.to_path_buf(), slot.clone());
+
+    let app = app.to_path_buf();
+    std::thread::spawn(move || watch(&app, &slot, child, policy));
+    Ok(pid)
+}
+
+// false once the standby has crashed or was stopped
+pub fn standby_running(app: &Path) -> bool {
+    STANDBY.lock().unwrap().contains_key(app)
+}
+
+pub fn stop_standby(app: &Path) -> Option<String> {
+    let slot = STANDBY.lock().unwrap().remove(app)?;
+    slot.stopping.store(true, Ordering::SeqCst);
+    let pid = slot.pid.load(Ordering::SeqCst);
+    (pid != 0).then(|| terminate(app, pid))
+}
+
+// the standby becomes the active process; the one it replaces is left alone
+// for `drain` to finish its requests, then stopped
+pub fn promote(app: &Path, drain: Duration) -> Result<u32> {
+    let slot = STANDBY
+        .lock()
+        .unwrap()
+        .remove(app)
+        .ok_or_else(|| anyhow::anyhow!("No standby process to switch to"))?;
+    let previous = APPS.lock().unwrap().insert(app.to_path_buf(), slot.clone());
+    slot.active.store(true, Ordering::SeqCst);
+
+    let pid = slot.pid.load(Ordering::SeqCst);
+    update(app, |s| {
+        s.status = "running".into();
+        s.pid = (pid != 0).then_some(pid);
+        s.port = slot.port;
+        s.exit_code = None;
+        s.restarts = 0;
+        s.health = None;
+    });
+
+    if let Some(old) = previous {
+        // not restarted or recorded from here on
+        old.active.store(false, Ordering::SeqCst);
+        old.stopping.store(true, Ordering::SeqCst);
+        let app = app.to_path_buf();
+        std::thread::spawn(move || {
+            std::thread::sleep(drain);
+            let pid = old.pid.load(Ordering::SeqCst);
+            if pid != 0 {
+                info!("{:?}: draining PID {}: {}", app, pid, terminate(&app, pid));
+            }
+        });
+    }
+    Ok(pid)
+}
+
+// stops a process that failed its liveness probe and lets the restart
+// policy take over; false if `pid` is no longer the one we run
+pub fn kill_unhealthy(app: &Path, pid: u32) -> bool {
+    let slot = match APPS.lock().unwrap().get(app) {
+        Some(s) => s.clone(),
+        None => return false,
+    };
+    if slot.pid.load(Ordering::SeqCst) != pid {
+        return false;
+    }
+    slot.unhealthy.store(true, Ordering::SeqCst);
+    terminate(app, pid);
+    true
+}
+
+// stop_signal to the group, then SIGKILL once stop_timeout has passed
+pub fn terminate(app: &Path, pid: u32) -> String {
+    let (signal, timeout) = match load(app) {
+        Ok((config, _)) => {
+            let run = config.run.as_ref();
+            (
+                run.and_then(|r| r.stop_signal).unwrap_or(StopSignal::Term),
+                run.and_then(|r| r.stop_timeout)
+                    .unwrap_or(DEFAULT_STOP_TIMEOUT),
+            )
+        }
+        Err(_) => (StopSignal::Term, DEFAULT_STOP_TIMEOUT),
+    };
+    let (number, name) = signal_number(signal);
+
+    // processes from before process groups only have their own PID
+    let group = -(pid as i32);
+    let target = if send(group, 0) { group } else { pid as i32 };
+    if !send(target, number) {
+        return "Not running".into();
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(timeout) {
+        if !send(target, 0) {
+            return format!("Stopped with {}", name);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    send(target, libc::SIGKILL);
+    warn!(
+        "{:?}: still running {}s after {}, killed",
+        app, timeout, name
+    );
+    format!(
+        "Killed with SIGKILL after {} was ignored for {}s",
+        name, timeout
+    )
+}
+
+fn send(target: i32, signal: i32) -> bool {
+    unsafe { libc::kill(target, signal) == 0 }
+}
+
+fn signal_number(signal: StopSignal) -> (i32, &'static str) {
+    match signal {
+        StopSignal::Term => (libc::SIGTERM, "SIGTERM"),
+        StopSignal::Int => (libc::SIGINT, "SIGINT"),
+        StopSignal::Quit => (libc::SIGQUIT, "SIGQUIT"),
+        StopSignal::Hup => (libc::SIGHUP, "SIGHUP"),
+        StopSignal::Usr1 => (libc::SIGUSR1, "SIGUSR1"),
+        StopSignal::Usr2 => (libc::SIGUSR2, "SIGUSR2"),
+    }
+}
+
+pub fn is_supervised(app: &Path) -> bool {
+    APPS.lock().unwrap().contains_key(app)
+}
+
+// command for a release, with its config and environment as they are now,
+// so restarts pick up `flare env` changes
+fn spawn(app: &Path, release: &Path, port: Option<u16>) -> Result<(Child, Policy)> {
+    let config = load_release(app, release, port)?;
+    let env = crate::env::resolve(app, release, &config)?;
+    let run = config
+        .run
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No [run] section"))?;
+
+    let policy = Policy {
+        restart: run.restart.unwrap_or(RestartPolicy::OnFailure),
+        max_restarts: run.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS),
+    };
+    let mut child = crate::deploy::build_run_command(run, &config, release)
+        .envs(&env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let log = crate::logs::path(app, release);
+    if let Some(out) = child.stdout.take() {
+        crate::logs::pipe(&log, "app", out, None);
+    }
+    if let Some(err) = child.stderr.take() {
+        crate::logs::pipe(&log, "app:err", err, None);
+    }
+    Ok((child, policy))
+}
+
+// config of the active release, resolved the way it was deployed
+pub fn load(app: &Path) -> Result<(AppConfig, PathBuf)> {
+    let release = std::fs::read_link(app.join("current"))?;
+    let port = common::load_state(app)?.and_then(|s| s.port);
+    Ok((load_release(app, &release, port)?, release))
+}
+
+// `port` replaces [run] port
+pub fn load_release(app: &Path, release: &Path, port: Option<u16>) -> Result<AppConfig> {
+    let state = common::load_state(app)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
+    let db = state.db_url.clone().map(|url| crate::database::Connection {
+        port: state.db_port,
+        url,
+    });
+    let profile = state.profile.as_deref();
+    crate::deploy::vars::load(app, release, profile, &state.tags, db.as_ref(), port)
+}
+
+fn watch(app: &Path, slot: &Arc<Slot>, mut child: Child, mut policy: Policy) {
+    let name = app
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let mut started = Instant::now();
+    let mut failures = 0;
+
+    loop {
+        let status = match child.wait() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("{}: can't wait for process: {}", name, e);
+                return release(app, slot);
+            }
+        };
+        slot.pid.store(0, Ordering::SeqCst);
+        if slot.stopping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let code = exit_code(status);
+        let failed = !status.success() || slot.unhealthy.swap(false, Ordering::SeqCst);
+        let again = match policy.restart {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Never => false,
+        };
+
+        if !again {
+            info!("{}: exited with {}", name, code);
+            note(app, slot, &format!("exited with {}", code));
+            record(app, slot, |s| {
+                s.status = if failed { "failed" } else { "exited" }.into();
+                s.pid = None;
+                s.exit_code = Some(code);
+            });
+            return release(app, slot);
+        }
+
+        if started.elapsed() >= STABLE {
+            failures = 0;
+        }
+        failures += 1;
+        if failures > policy.max_restarts {
+            error!(
+                "{}: exited with {}, {} restarts in a row, giving up",
+                name, code, policy.max_restarts
+            );
+            event(
+                app,
+                &format!(
+                    "exited with {}, crashed after {} restarts",
+                    code, policy.max_restarts
+                ),
+            );
+            record(app, slot, |s| {
+                s.status = "crashed".into();
+                s.pid = None;
+                s.exit_code = Some(code);
+            });
+            return release(app, slot);
+        }
+
+        let delay = Duration::from_secs(1 << (failures - 1).min(6)).min(MAX_BACKOFF);
+        warn!("{}: exited with {}, restarting in {:?}", name, code, delay);
+        note(
+            app,
+            slot,
+            &format!("exited with {}, restarting in {:?}", code, delay),
+        );
+        record(app, slot, |s| {
+            s.status = "restarting".into();
+            s.pid = None;
+            s.exit_code = Some(code);
+            s.restarts += 1;
+        });
+
+        std::thread::sleep(delay);
+        if slot.stopping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        match spawn(app, &slot.release, slot.port) {
+            Ok((c, p)) => {
+                child = c;
+                policy = p;
+                started = Instant::now();
+                let pid = child.id();
+                slot.pid.store(pid, Ordering::SeqCst);
+                info!("{}: restarted as PID {}", name, pid);
+                note(app, slot, &format!("restarted as PID {}", pid));
+                record(app, slot, |s| {
+                    s.status = "running".into();
+                    s.pid = Some(pid);
+                });
+            }
+            Err(e) => {
+                error!("{}: restart failed: {}", name, e);
+                note(app, slot, &format!("restart failed: {}", e));
+                record(app, slot, |s| s.status = "failed".into());
+                return release(app, slot);
+            }
+        }
+    }
+}
+
+// supervisor events in the app log, between the app's own lines
+pub fn event(app: &Path, text: &str) {
+    if let Ok(release) = std::fs::read_link(app.join("current")) {
+        crate::logs::line(&crate::logs::path(app, &release), "flare", text);
+    }
+}
+
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or(status.signal().map(|s| 128 + s))
+        .unwrap_or(-1)
+}
+
+// in the log of the release the process runs
+fn note(app: &Path, slot: &Slot, text: &str) {
+    crate::logs::line(&crate::logs::path(app, &slot.release), "flare", text);
+}
+
+// state.toml follows the active process only
+fn record(app: &Path, slot: &Slot, f: impl FnOnce(&mut AppState)) {
+    if slot.active.load(Ordering::SeqCst) {
+        update(app, f);
+    }
+}
+
+// drops the slot unless a newer start already replaced it
+fn release(app: &Path, slot: &Arc<Slot>) {
+    for slots in [&APPS, &STANDBY] {
+        let mut slots = slots.lock().unwrap();
+        if slots.get(app).is_some_and(|s| Arc::ptr_eq(s, slot)) {
+            slots.remove(app);
+        }
+    }
+}
+
+pub fn update(app: &Path, f: impl FnOnce(&mut AppState)) {
+    let _lock = STATE.lock().unwrap();
+    let result = common::load_state(app).and_then(|state| match state {
+        Some(mut s) => {
+            f(&mut s);
+            common::save_state(app, &s)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        warn!("Can't update state of {:?}: {}", app, e);
+    }
+}
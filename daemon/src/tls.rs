@@ -20,10 +20,9 @@ fn load_or_generate() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'st
     if let (Ok(cert), Ok(key)) = (
         std::env::var("FLARE_TLS_CERT"),
         std::env::var("FLARE_TLS_KEY"),
-    ) {
-        if let Ok(result) = load_files(&cert, &key) {
-            return Ok(result);
-        }
+    ) && let Ok(result) = load_files(&cert, &key)
+    {
+        return Ok(result);
     }
 
     generate_cert()